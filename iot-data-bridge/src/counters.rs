@@ -0,0 +1,121 @@
+//! Counters of the connections, datagrams and readings per protocol, logged periodically to see
+//! e.g. which sensors still have to be flashed with a newer protocol.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::framing::Protocol;
+
+#[derive(Debug, Default)]
+struct Counts {
+    connections: AtomicU64,
+    datagrams: AtomicU64,
+    readings: AtomicU64,
+    invalid: AtomicU64,
+}
+
+/// Totals since the bridge started, shared by all connections
+#[derive(Debug, Default)]
+pub struct ProtocolCounters {
+    // in the order of `Protocol::ALL`
+    counts: [Counts; Protocol::ALL.len()],
+}
+
+impl ProtocolCounters {
+    pub fn new() -> Self {
+        ProtocolCounters::default()
+    }
+
+    fn counts(&self, protocol: Protocol) -> &Counts {
+        let index = Protocol::ALL
+            .iter()
+            .position(|candidate| *candidate == protocol)
+            .expect("all protocols are counted");
+        &self.counts[index]
+    }
+
+    /// A connection was detected to speak the protocol
+    pub fn connection(&self, protocol: Protocol) {
+        self.counts(protocol)
+            .connections
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A datagram was detected to speak the protocol
+    pub fn datagram(&self, protocol: Protocol) {
+        self.counts(protocol)
+            .datagrams
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reading(&self, protocol: Protocol) {
+        self.counts(protocol)
+            .readings
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn invalid(&self, protocol: Protocol) {
+        self.counts(protocol)
+            .invalid
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// e.g. `legacy: 2 connections, 0 datagrams, 10 readings, 0 invalid; v2: ...`
+    pub fn summary(&self) -> String {
+        Protocol::ALL
+            .iter()
+            .map(|protocol| {
+                let counts = self.counts(*protocol);
+                format!(
+                    "{}: {} connections, {} datagrams, {} readings, {} invalid",
+                    protocol,
+                    counts.connections.load(Ordering::Relaxed),
+                    counts.datagrams.load(Ordering::Relaxed),
+                    counts.readings.load(Ordering::Relaxed),
+                    counts.invalid.load(Ordering::Relaxed)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// Logs the counters every `interval` if they changed
+pub async fn log_counters(counters: Arc<ProtocolCounters>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    let mut logged = String::new();
+    loop {
+        ticks.tick().await;
+        let summary = counters.summary();
+        if summary != logged {
+            info!("Protocols since start: {}", summary);
+            logged = summary;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProtocolCounters;
+    use crate::framing::Protocol;
+
+    #[test]
+    fn test_summary() {
+        let counters = ProtocolCounters::new();
+        counters.connection(Protocol::V2);
+        counters.reading(Protocol::V2);
+        counters.reading(Protocol::V2);
+        counters.datagram(Protocol::Text);
+        counters.invalid(Protocol::Text);
+        assert_eq!(
+            counters.summary(),
+            "legacy: 0 connections, 0 datagrams, 0 readings, 0 invalid; \
+             v2: 1 connections, 0 datagrams, 2 readings, 0 invalid; \
+             text: 0 connections, 1 datagrams, 0 readings, 1 invalid; \
+             mqtt: 0 connections, 0 datagrams, 0 readings, 0 invalid"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Devices speaking the v2 protocol identify themselves, so their values belong to the same sensor
+//! whatever address they connect from.
+//!
+//! The sequence numbers of a device are tracked across its connections: a repeated or older frame
+//! is dropped and missing numbers are logged as lost frames. A device counts from 0 again after a
+//! restart, so a retransmission of one of its first frames cannot be told apart from a restart.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use iot_db_accessor::{MeasurementKind, NewSensorData, SensorStore};
+use iot_protocol::{Frame, Kind};
+use tracing::{info, warn};
+
+/// frames behind the previous one with a sequence number below this start a new sequence
+const RESTART_SEQUENCES: u32 = 16;
+
+#[derive(Debug, Clone, Copy)]
+struct Device {
+    sensor_id: i64,
+    last_sequence: u32,
+}
+
+/// The v2 devices seen since the bridge started, shared by all connections
+#[derive(Debug, Clone)]
+pub struct Devices<S> {
+    store: S,
+    known: Arc<Mutex<HashMap<u64, Device>>>,
+}
+
+impl<S: SensorStore> Devices<S> {
+    pub fn new(store: S) -> Self {
+        Devices {
+            store,
+            known: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The value of the frame, `None` if the frame repeats or precedes the previous frame of its
+    /// device
+    pub async fn accept(&self, frame: &Frame) -> iot_db_accessor::Result<Option<NewSensorData>> {
+        let known = self.lock().get(&frame.device_id).copied();
+        let sensor_id = match known {
+            Some(device) => {
+                if !check_sequence(frame, device.last_sequence) {
+                    return Ok(None);
+                }
+                device.sensor_id
+            }
+            None => {
+                self.store
+                    .register_sensor(&sensor_name(frame.device_id))
+                    .await?
+            }
+        };
+        self.lock().insert(
+            frame.device_id,
+            Device {
+                sensor_id,
+                last_sequence: frame.sequence,
+            },
+        );
+        Ok(Some(sensor_data(sensor_id, frame)))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Device>> {
+        self.known.lock().expect("device lock is poisoned")
+    }
+}
+
+/// Name of the sensor of a device, e.g. `device-e6614103e75f2a2b`
+pub fn sensor_name(device_id: u64) -> String {
+    format!("device-{:016x}", device_id)
+}
+
+// whether the frame is new, the sequence numbers wrap around
+fn check_sequence(frame: &Frame, last_sequence: u32) -> bool {
+    match frame.sequence.wrapping_sub(last_sequence) {
+        0 => {
+            info!(
+                "Dropped repeated frame {} of device {:016x}",
+                frame.sequence, frame.device_id
+            );
+            false
+        }
+        1 => true,
+        difference if difference > u32::MAX / 2 && frame.sequence < RESTART_SEQUENCES => {
+            info!(
+                "Sequence of device {:016x} restarted at {}",
+                frame.device_id, frame.sequence
+            );
+            true
+        }
+        // retransmitted or reordered
+        difference if difference > u32::MAX / 2 => {
+            info!(
+                "Dropped frame {} of device {:016x}, it is older than frame {}",
+                frame.sequence, frame.device_id, last_sequence
+            );
+            false
+        }
+        difference => {
+            warn!(
+                "Lost {} frames of device {:016x} before frame {}",
+                difference - 1,
+                frame.device_id,
+                frame.sequence
+            );
+            true
+        }
+    }
+}
+
+pub fn measurement_kind(kind: Kind) -> MeasurementKind {
+    match kind {
+        Kind::Temperature => MeasurementKind::Temperature,
+        Kind::Humidity => MeasurementKind::Humidity,
+        Kind::Pressure => MeasurementKind::Pressure,
+        Kind::Voltage => MeasurementKind::Voltage,
+    }
+}
+
+fn sensor_data(sensor_id: i64, frame: &Frame) -> NewSensorData {
+    let kind = measurement_kind(frame.kind);
+    let device_timestamp = frame.device_timestamp.and_then(|millis| {
+        let timestamp = DateTime::<Utc>::from_timestamp_millis(millis);
+        if timestamp.is_none() {
+            warn!(
+                "Invalid timestamp {} of device {:016x}",
+                millis, frame.device_id
+            );
+        }
+        timestamp
+    });
+    NewSensorData {
+        sensor_id,
+        timestamp: Utc::now(),
+        kind,
+        // values of the v2 protocol are in the default unit of their kind
+        unit: kind.default_unit().to_string(),
+        value: frame.value.into(),
+        // the sequence restarts with the device, only the device timestamp identifies a value
+        message_id: None,
+        device_timestamp,
+    }
+}
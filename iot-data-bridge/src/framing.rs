@@ -0,0 +1,469 @@
+//! Framing of the readings received over TCP and UDP.
+//!
+//! TCP is a byte stream, one `read` may return part of a reading or several readings at once,
+//! depending on how the segments arrived. The decoder buffers the received bytes and yields every
+//! complete reading, the rest stays buffered until the next read. A datagram is decoded on its own
+//! and holds complete readings, the last line of text may omit its line break.
+//!
+//! A connection or datagram speaks one protocol, detected from its first byte:
+//! - the magic `A5` starts v2 frames, after an invalid frame the decoder skips to the next magic,
+//!   so one corrupted frame does not lose the following ones
+//! - a digit, sign, point, `{` or whitespace starts newline delimited text, each line is a number
+//!   (a temperature in °C) or a JSON object like `{"value": 45, "kind": "humidity"}`
+//! - anything else is a legacy reading, a bare `f32`. As text, the first byte of a plausible
+//!   temperature would be `@`, `A`, `B` or above `0xC0`, never one of the bytes starting text.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use iot_db_accessor::{MeasurementKind, NewSensorData};
+use iot_protocol::{decode_legacy, starts_frame, DecodeError, Frame, LEGACY_SIZE, MAGIC};
+use serde::{Deserialize, Deserializer};
+
+/// Longest accepted line of the text protocol, longer lines are discarded
+pub const MAX_LINE_LENGTH: usize = 1024;
+
+/// The protocol spoken on a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Legacy,
+    V2,
+    Text,
+    /// messages of an MQTT broker, never detected on a connection of a sensor
+    Mqtt,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 4] = [
+        Protocol::Legacy,
+        Protocol::V2,
+        Protocol::Text,
+        Protocol::Mqtt,
+    ];
+
+    /// Detects the protocol from the first byte of a connection
+    pub fn detect(first: u8) -> Protocol {
+        if starts_frame(&[first]) {
+            Protocol::V2
+        } else if first.is_ascii_digit() || b"+-.{ \t\r\n".contains(&first) {
+            Protocol::Text
+        } else {
+            Protocol::Legacy
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Legacy => "legacy",
+            Protocol::V2 => "v2",
+            Protocol::Text => "text",
+            Protocol::Mqtt => "mqtt",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A decoded reading of one of the protocols
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reading {
+    /// a temperature in °C of the sensor at the address of the connection
+    Legacy(f32),
+    V2(Frame),
+    Text(TextReading),
+}
+
+/// A line of the text protocol
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TextReading {
+    pub value: f64,
+    #[serde(default = "default_kind")]
+    pub kind: MeasurementKind,
+    /// defaults to the default unit of the kind
+    pub unit: Option<String>,
+    /// name of the sensor, defaults to the address of the connection
+    pub sensor: Option<String>,
+    /// a string or a number identifying the value, a value sent again is stored once
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub message_id: Option<String>,
+    /// when the device measured the value, e.g. `2024-01-01T09:00:00Z`
+    pub device_timestamp: Option<DateTime<Utc>>,
+}
+
+fn default_kind() -> MeasurementKind {
+    MeasurementKind::Temperature
+}
+
+fn string_or_number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    match Option::<serde_json::Value>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(serde_json::Value::String(id)) => Ok(Some(id)),
+        Some(serde_json::Value::Number(id)) => Ok(Some(id.to_string())),
+        Some(_) => Err(serde::de::Error::custom(
+            "message_id must be a string or a number",
+        )),
+    }
+}
+
+impl TextReading {
+    /// Parses a line without its line break
+    pub fn parse(line: &str) -> Result<TextReading, String> {
+        let line = line.trim();
+        if line.starts_with('{') {
+            return serde_json::from_str(line).map_err(|e| e.to_string());
+        }
+        let value = line
+            .parse()
+            .map_err(|_| format!("'{}' is neither a number nor JSON", line))?;
+        Ok(TextReading {
+            value,
+            kind: default_kind(),
+            unit: None,
+            sensor: None,
+            message_id: None,
+            device_timestamp: None,
+        })
+    }
+
+    pub fn into_sensor_data(self, sensor_id: i64) -> NewSensorData {
+        NewSensorData {
+            sensor_id,
+            timestamp: Utc::now(),
+            kind: self.kind,
+            unit: self
+                .unit
+                .unwrap_or_else(|| self.kind.default_unit().to_string()),
+            value: self.value,
+            message_id: self.message_id,
+            device_timestamp: self.device_timestamp,
+        }
+    }
+}
+
+/// Why received bytes are no reading
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvalidReading {
+    Frame(DecodeError),
+    Line(String),
+    /// bytes left when the input ended
+    Incomplete(usize),
+}
+
+impl fmt::Display for InvalidReading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidReading::Frame(error) => write!(f, "invalid frame: {}", error),
+            InvalidReading::Line(error) => write!(f, "invalid line: {}", error),
+            InvalidReading::Incomplete(len) => write!(f, "incomplete reading of {} bytes", len),
+        }
+    }
+}
+
+/// Splits the received bytes into readings, regardless of the segment boundaries
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    // start of the first reading that was not yet decoded
+    position: usize,
+    // unknown until the first byte is received
+    protocol: Option<Protocol>,
+    // the rest of a too long line is discarded up to its line break
+    discard_line: bool,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder::default()
+    }
+
+    /// The protocol of the connection, known after the first byte was decoded
+    pub fn protocol(&self) -> Option<Protocol> {
+        self.protocol
+    }
+
+    /// Appends the bytes of one read
+    pub fn extend(&mut self, bytes: &[u8]) {
+        // decoded readings are dropped before the buffer grows
+        if self.position > 0 {
+            self.buffer.drain(..self.position);
+            self.position = 0;
+        }
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next complete reading, `None` until its remaining bytes are received. An invalid
+    /// reading is returned as error, decoding continues after it.
+    pub fn next_reading(&mut self) -> Option<Result<Reading, InvalidReading>> {
+        let protocol = match self.protocol {
+            Some(protocol) => protocol,
+            None => *self
+                .protocol
+                .insert(Protocol::detect(*self.buffer.get(self.position)?)),
+        };
+        match protocol {
+            Protocol::Legacy => self.next_legacy(),
+            Protocol::V2 => self.next_frame(),
+            Protocol::Text => self.next_line(),
+            Protocol::Mqtt => unreachable!("MQTT is never detected"),
+        }
+    }
+
+    /// Bytes of an incomplete reading, lost if the connection is closed now
+    pub fn pending(&self) -> usize {
+        self.buffer.len() - self.position
+    }
+
+    /// The rest of the input after the last complete reading, once no more bytes follow: a last
+    /// line of text without line break is a reading, any other bytes an incomplete reading
+    pub fn finish(&mut self) -> Option<Result<Reading, InvalidReading>> {
+        if self.pending() == 0 {
+            return None;
+        }
+        if self.protocol == Some(Protocol::Text) {
+            self.buffer.push(b'\n');
+            return self.next_line();
+        }
+        let pending = self.pending();
+        self.position = self.buffer.len();
+        Some(Err(InvalidReading::Incomplete(pending)))
+    }
+
+    fn next_legacy(&mut self) -> Option<Result<Reading, InvalidReading>> {
+        let value = decode_legacy(&self.buffer[self.position..])?;
+        self.position += LEGACY_SIZE;
+        Some(Ok(Reading::Legacy(value)))
+    }
+
+    fn next_frame(&mut self) -> Option<Result<Reading, InvalidReading>> {
+        match Frame::decode(&self.buffer[self.position..]) {
+            Ok((frame, len)) => {
+                self.position += len;
+                Some(Ok(Reading::V2(frame)))
+            }
+            Err(DecodeError::Incomplete) => None,
+            Err(error) => {
+                self.resync();
+                Some(Err(InvalidReading::Frame(error)))
+            }
+        }
+    }
+
+    fn next_line(&mut self) -> Option<Result<Reading, InvalidReading>> {
+        loop {
+            let bytes = &self.buffer[self.position..];
+            let Some(len) = bytes.iter().position(|byte| *byte == b'\n') else {
+                if bytes.len() <= MAX_LINE_LENGTH {
+                    return None;
+                }
+                self.position = self.buffer.len();
+                return match std::mem::replace(&mut self.discard_line, true) {
+                    true => None,
+                    false => Some(Err(InvalidReading::Line(format!(
+                        "longer than {} bytes",
+                        MAX_LINE_LENGTH
+                    )))),
+                };
+            };
+            self.position += len + 1;
+            if std::mem::take(&mut self.discard_line) {
+                continue;
+            }
+            let line = match std::str::from_utf8(&bytes[..len]) {
+                Ok(line) => line,
+                Err(e) => return Some(Err(InvalidReading::Line(e.to_string()))),
+            };
+            // empty lines are skipped, e.g. of clients sending \r\n\r\n
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(
+                TextReading::parse(line)
+                    .map(Reading::Text)
+                    .map_err(InvalidReading::Line),
+            );
+        }
+    }
+
+    // skips the first byte of the invalid frame and everything up to the next magic
+    fn resync(&mut self) {
+        let skipped = self.buffer[self.position + 1..]
+            .iter()
+            .position(|byte| *byte == MAGIC[0])
+            .map_or(self.pending(), |offset| offset + 1);
+        self.position += skipped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iot_db_accessor::MeasurementKind;
+    use iot_protocol::{DecodeError, Frame, Kind};
+
+    use super::{FrameDecoder, InvalidReading, Protocol, Reading, TextReading, MAX_LINE_LENGTH};
+
+    fn bytes(values: &[f32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    fn readings(decoder: &mut FrameDecoder) -> Vec<Result<Reading, InvalidReading>> {
+        std::iter::from_fn(|| decoder.next_reading()).collect()
+    }
+
+    fn legacy(values: &[f32]) -> Vec<Result<Reading, InvalidReading>> {
+        values
+            .iter()
+            .map(|value| Ok(Reading::Legacy(*value)))
+            .collect()
+    }
+
+    fn frame(sequence: u32) -> Frame {
+        Frame {
+            device_id: 7,
+            sequence,
+            kind: Kind::Temperature,
+            device_timestamp: None,
+            value: 21.5,
+        }
+    }
+
+    fn text(value: f64) -> Result<Reading, InvalidReading> {
+        Ok(Reading::Text(
+            TextReading::parse(&value.to_string()).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn test_detect_protocol() {
+        for value in [-40f32, -0.5, 0., 0.001, 1., 21.5, 100.] {
+            let first = value.to_be_bytes()[0];
+            assert_eq!(Protocol::detect(first), Protocol::Legacy, "{}", value);
+        }
+        assert_eq!(
+            Protocol::detect(frame(1).encode().as_bytes()[0]),
+            Protocol::V2
+        );
+        for line in ["21.5\n", "-3\n", "{\"value\": 1}\n"] {
+            assert_eq!(Protocol::detect(line.as_bytes()[0]), Protocol::Text);
+        }
+    }
+
+    #[test]
+    fn test_coalesced_readings() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes(&[21.5, 22., 22.5]));
+        assert_eq!(readings(&mut decoder), legacy(&[21.5, 22., 22.5]));
+        assert_eq!(decoder.pending(), 0);
+        assert_eq!(decoder.protocol(), Some(Protocol::Legacy));
+    }
+
+    #[test]
+    fn test_split_readings() {
+        let mut decoder = FrameDecoder::new();
+        let data = bytes(&[21.5, 22.]);
+        // one byte per read
+        let mut decoded = Vec::new();
+        for byte in &data {
+            decoder.extend(std::slice::from_ref(byte));
+            decoded.extend(readings(&mut decoder));
+        }
+        assert_eq!(decoded, legacy(&[21.5, 22.]));
+
+        // a read ending within the second reading
+        decoder.extend(&data[..6]);
+        assert_eq!(readings(&mut decoder), legacy(&[21.5]));
+        assert_eq!(decoder.pending(), 2);
+        decoder.extend(&data[6..]);
+        assert_eq!(readings(&mut decoder), legacy(&[22.]));
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    fn test_v2_frames() {
+        let mut data = Vec::new();
+        for sequence in 1..=3 {
+            data.extend_from_slice(frame(sequence).encode().as_bytes());
+        }
+        // split within the second frame, the rest coalesced
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&data[..30]);
+        assert_eq!(readings(&mut decoder), [Ok(Reading::V2(frame(1)))]);
+        decoder.extend(&data[30..]);
+        assert_eq!(
+            readings(&mut decoder),
+            [Ok(Reading::V2(frame(2))), Ok(Reading::V2(frame(3)))]
+        );
+        assert_eq!(decoder.pending(), 0);
+        assert_eq!(decoder.protocol(), Some(Protocol::V2));
+
+        // the frame after a corrupted one is decoded
+        let mut decoder = FrameDecoder::new();
+        let mut corrupted = data.clone();
+        corrupted[10] ^= 0xFF;
+        decoder.extend(&corrupted);
+        assert_eq!(
+            readings(&mut decoder),
+            [
+                Err(InvalidReading::Frame(DecodeError::ChecksumMismatch)),
+                Ok(Reading::V2(frame(2))),
+                Ok(Reading::V2(frame(3)))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_lines() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(b"21.5\r\n\r\n22");
+        assert_eq!(readings(&mut decoder), [text(21.5)]);
+        assert_eq!(decoder.pending(), 2);
+        decoder.extend(b".5\n{\"value\": 45, \"kind\": \"humidity\", \"sensor\": \"attic\",");
+        assert_eq!(readings(&mut decoder), [text(22.5)]);
+        decoder.extend(b" \"message_id\": 17}\nwarm\n");
+        let decoded = readings(&mut decoder);
+        assert_eq!(decoded.len(), 2);
+        let Ok(Reading::Text(reading)) = &decoded[0] else {
+            panic!("{:?}", decoded[0]);
+        };
+        assert_eq!(reading.kind, MeasurementKind::Humidity);
+        assert_eq!(reading.sensor.as_deref(), Some("attic"));
+        assert_eq!(reading.message_id.as_deref(), Some("17"));
+        let data = reading.clone().into_sensor_data(3);
+        assert_eq!(data.unit, "%");
+        assert!(matches!(decoded[1], Err(InvalidReading::Line(_))));
+        assert_eq!(decoder.protocol(), Some(Protocol::Text));
+
+        // a too long line is reported once and skipped up to its line break
+        decoder.extend(&[b'1'; MAX_LINE_LENGTH + 1]);
+        assert!(matches!(
+            readings(&mut decoder)[..],
+            [Err(InvalidReading::Line(_))]
+        ));
+        decoder.extend(&[b'1'; MAX_LINE_LENGTH + 1]);
+        decoder.extend(b"1\n23\n");
+        assert_eq!(readings(&mut decoder), [text(23.)]);
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(b"21.5\n22");
+        assert_eq!(readings(&mut decoder), [text(21.5)]);
+        assert_eq!(decoder.finish(), Some(text(22.)));
+        assert_eq!(decoder.finish(), None);
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes(&[21.5, 22.])[..6]);
+        assert_eq!(readings(&mut decoder), legacy(&[21.5]));
+        assert_eq!(decoder.finish(), Some(Err(InvalidReading::Incomplete(2))));
+        assert_eq!(decoder.pending(), 0);
+    }
+}
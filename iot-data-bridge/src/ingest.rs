@@ -0,0 +1,144 @@
+//! Turns decoded readings into sensor values and queues them for the batch writer.
+//!
+//! Readings without identity, the legacy ones and text lines without a sensor name, belong to the
+//! sensor named by the address of the sender. Values that can't be stored, e.g. with a unit not
+//! matching their kind, are dropped here, so they don't fail the batch they would be written in.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use iot_db_accessor::{validate_sensor_value, MeasurementKind, NewSensorData, SensorStore};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::counters::ProtocolCounters;
+use crate::devices::Devices;
+use crate::framing::{InvalidReading, Protocol, Reading};
+
+/// Shared by all connections
+#[derive(Debug, Clone)]
+pub struct Ingest<S> {
+    store: S,
+    devices: Devices<S>,
+    counters: Arc<ProtocolCounters>,
+    sender: mpsc::Sender<NewSensorData>,
+    // sensor ids by name
+    sensors: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl<S: SensorStore + Clone> Ingest<S> {
+    pub fn new(
+        store: S,
+        counters: Arc<ProtocolCounters>,
+        sender: mpsc::Sender<NewSensorData>,
+    ) -> Self {
+        Ingest {
+            devices: Devices::new(store.clone()),
+            store,
+            counters,
+            sender,
+            sensors: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn counters(&self) -> &ProtocolCounters {
+        &self.counters
+    }
+
+    /// Queues the value of the reading received from `addr`, an error means that the sensor of
+    /// the reading could not be registered
+    pub async fn ingest(
+        &self,
+        reading: Result<Reading, InvalidReading>,
+        protocol: Protocol,
+        addr: SocketAddr,
+    ) -> iot_db_accessor::Result<()> {
+        let sensor_data = match reading {
+            Ok(Reading::Legacy(temp)) => {
+                // the raw sensor protocol carries no identity, so sensors are told apart by address
+                let sensor_id = self.sensor_id(&addr.ip().to_string()).await?;
+                legacy_sensor_data(sensor_id, temp)
+            }
+            Ok(Reading::V2(frame)) => match self.devices.accept(&frame).await? {
+                Some(sensor_data) => sensor_data,
+                // a repeated frame
+                None => return Ok(()),
+            },
+            Ok(Reading::Text(reading)) => {
+                let name = match &reading.sensor {
+                    Some(name) => name.clone(),
+                    None => addr.ip().to_string(),
+                };
+                let sensor_id = self.sensor_id(&name).await?;
+                reading.into_sensor_data(sensor_id)
+            }
+            Err(e) => {
+                self.counters.invalid(protocol);
+                warn!("Invalid sensor data from {}: {}", addr, e);
+                return Ok(());
+            }
+        };
+        self.accept(sensor_data, protocol, addr).await;
+        Ok(())
+    }
+
+    /// Queues the value unless it can't be stored, `source` names the sender in the log
+    pub async fn accept(
+        &self,
+        sensor_data: NewSensorData,
+        protocol: Protocol,
+        source: impl fmt::Display,
+    ) {
+        if let Err(e) =
+            validate_sensor_value(sensor_data.kind, &sensor_data.unit, sensor_data.value)
+        {
+            self.counters.invalid(protocol);
+            warn!("Invalid sensor data from {}: {}", source, e);
+            return;
+        }
+        self.counters.reading(protocol);
+        self.queue(sensor_data).await;
+    }
+
+    /// The id of the sensor named `name`, registered when it is first seen
+    pub async fn sensor_id(&self, name: &str) -> iot_db_accessor::Result<i64> {
+        if let Some(sensor_id) = self.lock().get(name) {
+            return Ok(*sensor_id);
+        }
+        let sensor_id = self.store.register_sensor(name).await?;
+        self.lock().insert(name.to_string(), sensor_id);
+        Ok(sensor_id)
+    }
+
+    async fn queue(&self, sensor_data: NewSensorData) {
+        info!(
+            "received {} value from sensor {}: {}",
+            sensor_data.kind, sensor_data.sensor_id, sensor_data.value
+        );
+        let sensor_id = sensor_data.sensor_id;
+        if self.sender.send(sensor_data).await.is_err() {
+            warn!("Failed to queue value of sensor {}", sensor_id);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, i64>> {
+        self.sensors.lock().expect("sensor lock is poisoned")
+    }
+}
+
+fn legacy_sensor_data(sensor_id: i64, temp: f32) -> NewSensorData {
+    // the raw sensor protocol only transmits the temperature in degrees Celsius
+    let kind = MeasurementKind::Temperature;
+    NewSensorData {
+        sensor_id,
+        timestamp: chrono::Utc::now(),
+        kind,
+        unit: kind.default_unit().to_string(),
+        // PicoW AnalogDigitalConverter only supports f32, but the backend supports f64
+        value: temp.into(),
+        message_id: None,
+        device_timestamp: None,
+    }
+}
@@ -1,69 +1,213 @@
 #![warn(rust_2018_idioms)]
 
+mod counters;
+mod devices;
+mod framing;
+mod ingest;
+mod mqtt;
+
 use dotenvy::dotenv;
 use tokio::io::AsyncReadExt;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time::{timeout_at, Instant};
 
-use tracing::{info, warn};
+use tracing::{debug, warn};
 
 use std::env;
+use std::net::SocketAddr;
+use std::process::ExitCode;
 use std::sync::Arc;
+use std::time::Duration;
+
+use iot_db_accessor::{
+    spawn_retention_job, DatabaseConfig, NewSensorData, RetentionPolicy, SensorStore,
+};
 
-use iot_db_accessor::add_sensor_data;
-use sqlx::SqlitePool;
+use crate::counters::{log_counters, ProtocolCounters};
+use crate::framing::{FrameDecoder, Protocol};
+use crate::ingest::Ingest;
+use crate::mqtt::MqttConfig;
 
 const BUFFER_SIZE: usize = 1024;
-const SENSOR_DATA_SIZE: usize = 4;
+/// largest payload of a UDP datagram
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+/// values are written to the database when the batch is full ...
+const BATCH_SIZE: usize = 100;
+/// ... or the first value of the batch waited this long
+const BATCH_DELAY: Duration = Duration::from_millis(500);
+/// a batch failing because the database is busy is written up to this often ...
+const BATCH_ATTEMPTS: u32 = 3;
+/// ... waiting this long before the first retry, doubled for each further retry
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+/// how often the counters of the protocols are logged, if they changed
+const COUNTERS_INTERVAL: Duration = Duration::from_secs(60);
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
     dotenv().ok();
     tracing_init();
 
-    let pool = SqlitePool::connect(&env::var("DATABASE_URL")?).await?;
-    let pool = Arc::new(pool);
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {:#}", error);
+            match error.downcast_ref::<iot_db_accessor::Error>() {
+                Some(error) => ExitCode::from(error.exit_code()),
+                None => ExitCode::FAILURE,
+            }
+        }
+    }
+}
 
-    // if no host is configured, wildcard address is used
-    let serverurl = &env::var("IOT_DATA_BRIDGE_URL")?;
-    let serverurl = if serverurl.starts_with(':') {
-        format!("0.0.0.0{}", serverurl)
-    } else {
-        serverurl.to_string()
-    };
+async fn run() -> anyhow::Result<()> {
+    let pool = DatabaseConfig::from_env()?.connect_and_migrate().await?;
+    // old values are rolled up and removed in the background
+    spawn_retention_job(pool.clone(), RetentionPolicy::from_env()?);
 
+    let serverurl = server_address(&env::var("IOT_DATA_BRIDGE_URL")?);
     let listener = TcpListener::bind(&serverurl).await?;
     println!("IoT Data Bridge is listening on: {}", serverurl);
 
+    let ingest = spawn_ingest(pool);
+    // UDP is optional, sensors firing and forgetting their readings don't hold a connection
+    if let Ok(udpurl) = env::var("IOT_DATA_BRIDGE_UDP_URL") {
+        let udpurl = server_address(&udpurl);
+        let socket = UdpSocket::bind(&udpurl).await?;
+        println!("IoT Data Bridge is receiving datagrams on: {}", udpurl);
+        tokio::spawn(receive_datagrams(socket, ingest.clone()));
+    }
+    // values published by other sensors to an MQTT broker
+    if let Some(config) = MqttConfig::from_env()? {
+        println!(
+            "IoT Data Bridge subscribes to MQTT broker: {}",
+            config.broker()
+        );
+        tokio::spawn(mqtt::subscribe(config, ingest.clone()));
+    }
+    serve(listener, ingest).await
+}
+
+// if no host is configured, wildcard address is used
+fn server_address(url: &str) -> String {
+    if url.starts_with(':') {
+        format!("0.0.0.0{}", url)
+    } else {
+        url.to_string()
+    }
+}
+
+/// Starts writing the ingested values to `store` and logging the protocol counters
+fn spawn_ingest<S>(store: S) -> Ingest<S>
+where
+    S: SensorStore + Clone + 'static,
+{
+    let (sender, receiver) = mpsc::channel(4 * BATCH_SIZE);
+    tokio::spawn(write_batches(store.clone(), receiver));
+    let counters = Arc::new(ProtocolCounters::new());
+    tokio::spawn(log_counters(counters.clone(), COUNTERS_INTERVAL));
+    Ingest::new(store, counters, sender)
+}
+
+/// Accepts sensor connections and ingests the received values
+async fn serve<S>(listener: TcpListener, ingest: Ingest<S>) -> anyhow::Result<()>
+where
+    S: SensorStore + Clone + 'static,
+{
     loop {
-        let pool = Arc::clone(&pool);
         // Asynchronously wait for an inbound socket.
-        let (mut socket, _) = listener.accept().await?;
+        let (socket, addr) = listener.accept().await?;
+        tokio::spawn(handle_connection(socket, addr, ingest.clone()));
+    }
+}
 
-        tokio::spawn(async move {
-            let mut buf = vec![0; BUFFER_SIZE];
+/// Decodes the readings of one connection until the sensor disconnects, the protocol is detected
+/// from the first received byte
+async fn handle_connection<S: SensorStore + Clone>(
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    ingest: Ingest<S>,
+) {
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut decoder = FrameDecoder::new();
+    // the protocol is known with the first byte, the first reading may need several reads
+    let mut counted = false;
 
-            loop {
-                let n = socket.read(&mut buf).await;
-
-                match n {
-                    Ok(SENSOR_DATA_SIZE) => {
-                        // Sensorvalue contains 4 byte
-                        process_sensor_data(&pool, &buf).await;
-                    }
-                    Ok(0) => return, // Client disconnected
-                    Err(_) => {
-                        warn!("Failed to read data from socket");
-                    }
-                    Ok(n) => {
-                        let response = String::from_utf8_lossy(&buf[..n]);
-                        warn!(
-                            "Invalid sensor data: could not process {} (len: {})",
-                            response, n
-                        );
-                    }
-                };
+    loop {
+        let n = match socket.read(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("Failed to read data from socket of {}: {}", addr, e);
+                return;
             }
-        });
+        };
+        // Client disconnected
+        if n == 0 {
+            // e.g. the last line of text without line break
+            if let Some(reading) = decoder.finish() {
+                let protocol = decoder.protocol().expect("detected with the first byte");
+                count_connection(&ingest, addr, protocol, &mut counted);
+                if let Err(e) = ingest.ingest(reading, protocol, addr).await {
+                    warn!("Failed to register sensor of {}: {:?}", addr, e);
+                }
+            }
+            return;
+        }
+        // a read may end within a reading or contain several readings
+        decoder.extend(&buf[..n]);
+        while let Some(reading) = decoder.next_reading() {
+            let protocol = decoder.protocol().expect("detected with the first reading");
+            count_connection(&ingest, addr, protocol, &mut counted);
+            if let Err(e) = ingest.ingest(reading, protocol, addr).await {
+                warn!("Failed to register sensor of {}: {:?}", addr, e);
+                return;
+            }
+        }
+    }
+}
+
+// counts the connection with its first reading
+fn count_connection<S: SensorStore + Clone>(
+    ingest: &Ingest<S>,
+    addr: SocketAddr,
+    protocol: Protocol,
+    counted: &mut bool,
+) {
+    if !std::mem::replace(counted, true) {
+        debug!("{} speaks the {} protocol", addr, protocol);
+        ingest.counters().connection(protocol);
+    }
+}
+
+/// Ingests the readings of each received datagram, which holds one or more complete readings.
+/// Lost datagrams are not noticed, except as lost frames of v2 devices.
+async fn receive_datagrams<S: SensorStore + Clone>(socket: UdpSocket, ingest: Ingest<S>) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Failed to receive datagram: {}", e);
+                continue;
+            }
+        };
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&buf[..n]);
+        let mut readings: Vec<_> = std::iter::from_fn(|| decoder.next_reading()).collect();
+        readings.extend(decoder.finish());
+        let Some(protocol) = decoder.protocol() else {
+            debug!("Empty datagram from {}", addr);
+            continue;
+        };
+        debug!("{} sent a {} datagram of {} bytes", addr, protocol, n);
+        ingest.counters().datagram(protocol);
+        for reading in readings {
+            if let Err(e) = ingest.ingest(reading, protocol, addr).await {
+                warn!("Failed to register sensor of {}: {:?}", addr, e);
+                break;
+            }
+        }
     }
 }
 
@@ -77,13 +221,360 @@ fn tracing_init() {
         .init();
 }
 
-async fn process_sensor_data(pool: &SqlitePool, data: &[u8]) {
-    let temp = f32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-    // PicoW AnalogDigitalConverter only supports f32, but the backend supports f64
-    let value = temp.into();
-    info!("received value from sensor: {}", value);
-    let result = add_sensor_data(pool, chrono::Utc::now().naive_utc(), value).await;
-    if let Err(e) = result {
-        warn!("An error occurred: {:?}", e);
+/// Collects the received values and writes them in batches bounded by `BATCH_SIZE` and `BATCH_DELAY`
+async fn write_batches<S: SensorStore>(store: S, mut receiver: mpsc::Receiver<NewSensorData>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    // wait for the first value of the next batch
+    while let Some(sensor_data) = receiver.recv().await {
+        batch.push(sensor_data);
+        let deadline = Instant::now() + BATCH_DELAY;
+        while batch.len() < BATCH_SIZE {
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(sensor_data)) => batch.push(sensor_data),
+                // all senders are gone or the deadline is reached
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        store_batch(&store, &batch).await;
+        batch.clear();
+    }
+}
+
+/// Writes the batch in one transaction, if that fails for another reason than a busy database
+/// the values are written one by one, so one invalid value doesn't lose the others
+async fn store_batch<S: SensorStore>(store: &S, batch: &[NewSensorData]) {
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..=BATCH_ATTEMPTS {
+        match store.add_sensor_data_batch(batch).await {
+            Ok(_) => return,
+            Err(iot_db_accessor::Error::Busy(e)) if attempt < BATCH_ATTEMPTS => {
+                warn!(
+                    "Database is busy, retrying {} values in {:?}: {}",
+                    batch.len(),
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to store {} values at once, storing them one by one: {:?}",
+                    batch.len(),
+                    e
+                );
+                break;
+            }
+        }
+    }
+    for sensor_data in batch {
+        if let Err(e) = store.add_sensor_data(sensor_data).await {
+            warn!(
+                "Failed to store value of sensor {}: {:?}",
+                sensor_data.sensor_id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use iot_db_accessor::{
+        MeasurementKind, MemoryStore, NewSensorData, QualityFilter, SensorData, SensorStore,
+    };
+    use iot_protocol::{Frame, Kind};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+    use tokio::sync::mpsc;
+
+    use super::{receive_datagrams, serve, spawn_ingest, write_batches, BATCH_DELAY};
+    use crate::devices::sensor_name;
+    use crate::ingest::Ingest;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Serves connections on a free port of the loopback interface, storing into a new store
+    async fn serve_tcp() -> (MemoryStore, Ingest<MemoryStore>, SocketAddr) {
+        let store = MemoryStore::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ingest = spawn_ingest(store.clone());
+        tokio::spawn(serve(listener, ingest.clone()));
+        (store, ingest, addr)
+    }
+
+    /// The stored values once there are at least `count`, the values arrive in batches
+    pub(crate) async fn wait_for_values(store: &MemoryStore, count: usize) -> Vec<SensorData> {
+        tokio::time::timeout(TIMEOUT, async {
+            loop {
+                let values = store
+                    .list_sensordata(&[], QualityFilter::All)
+                    .await
+                    .unwrap();
+                if values.len() >= count {
+                    return values;
+                }
+                tokio::time::sleep(BATCH_DELAY / 10).await;
+            }
+        })
+        .await
+        .expect("sent values were not stored")
+    }
+
+    #[tokio::test]
+    async fn test_store_received_value() {
+        let (store, _, addr) = serve_tcp().await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&21.5f32.to_be_bytes()).await.unwrap();
+
+        let values = wait_for_values(&store, 1).await;
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].value, 21.5);
+        assert_eq!(values[0].kind, MeasurementKind::Temperature);
+        let sensors = store.list_sensors().await.unwrap();
+        let sensor = sensors
+            .iter()
+            .find(|sensor| sensor.id == values[0].sensor_id);
+        assert_eq!(sensor.unwrap().name, "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_store_coalesced_and_split_values() {
+        let (store, _, addr) = serve_tcp().await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        let data: Vec<u8> = [20f32, 21., 22.]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        // two readings at once and the third split in two writes
+        stream.write_all(&data[..10]).await.unwrap();
+        stream.flush().await.unwrap();
+        tokio::time::sleep(BATCH_DELAY / 5).await;
+        stream.write_all(&data[10..]).await.unwrap();
+
+        let values: Vec<_> = wait_for_values(&store, 3)
+            .await
+            .iter()
+            .map(|value| value.value)
+            .collect();
+        assert_eq!(values, [20., 21., 22.]);
+    }
+
+    #[tokio::test]
+    async fn test_store_v2_frames() {
+        let (store, _, addr) = serve_tcp().await;
+
+        let frame = |sequence, kind, value| Frame {
+            device_id: 0xE661_4103_E75F_2A2B,
+            sequence,
+            kind,
+            device_timestamp: Some(1_704_099_600_000 + i64::from(sequence)),
+            value,
+        };
+        let mut data = Vec::new();
+        for frame in [
+            frame(100, Kind::Temperature, 21.5),
+            frame(101, Kind::Humidity, 45.),
+            // a retransmission is dropped
+            frame(101, Kind::Humidity, 45.),
+        ] {
+            data.extend_from_slice(frame.encode().as_bytes());
+        }
+        // a corrupted frame is skipped
+        let mut corrupted = frame(102, Kind::Temperature, 22.)
+            .encode()
+            .as_bytes()
+            .to_vec();
+        corrupted[20] ^= 0x01;
+        data.extend_from_slice(&corrupted);
+        for frame in [
+            frame(103, Kind::Temperature, 22.5),
+            // an older frame is dropped as well, a restarted device counts from 0
+            frame(100, Kind::Temperature, 21.5),
+            frame(0, Kind::Temperature, 23.),
+        ] {
+            data.extend_from_slice(frame.encode().as_bytes());
+        }
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+
+        let values = wait_for_values(&store, 4).await;
+        let kinds: Vec<_> = values
+            .iter()
+            .map(|value| (value.kind, value.value))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (MeasurementKind::Temperature, 21.5),
+                (MeasurementKind::Humidity, 45.),
+                (MeasurementKind::Temperature, 22.5),
+                (MeasurementKind::Temperature, 23.)
+            ]
+        );
+        // the device is the sensor, not the address
+        let sensors = store.list_sensors().await.unwrap();
+        let sensor = sensors
+            .iter()
+            .find(|sensor| sensor.id == values[0].sensor_id);
+        assert_eq!(sensor.unwrap().name, sensor_name(0xE661_4103_E75F_2A2B));
+        assert!(sensors.iter().all(|sensor| sensor.name != "127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_store_text_lines() {
+        let (store, _, addr) = serve_tcp().await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"21.5\n\
+                  {\"value\": 45, \"kind\": \"humidity\", \"sensor\": \"cellar\"}\r\n\
+                  not a number\n\
+                  {\"value\": 1013.2, \"kind\": \"pressure\", \"unit\": \"%\"}\n\
+                  22\n",
+            )
+            .await
+            .unwrap();
+
+        let values = wait_for_values(&store, 3).await;
+        let kinds: Vec<_> = values
+            .iter()
+            .map(|value| (value.kind, value.value))
+            .collect();
+        // the invalid line and the value with a wrong unit are dropped
+        assert_eq!(
+            kinds,
+            [
+                (MeasurementKind::Temperature, 21.5),
+                (MeasurementKind::Humidity, 45.),
+                (MeasurementKind::Temperature, 22.)
+            ]
+        );
+        let sensors = store.list_sensors().await.unwrap();
+        let name = |sensor_id| {
+            sensors
+                .iter()
+                .find(|sensor| sensor.id == sensor_id)
+                .map(|sensor| sensor.name.as_str())
+        };
+        assert_eq!(name(values[0].sensor_id), Some("127.0.0.1"));
+        assert_eq!(name(values[1].sensor_id), Some("cellar"));
+    }
+
+    #[tokio::test]
+    async fn test_store_datagrams() {
+        let store = MemoryStore::new();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(receive_datagrams(socket, spawn_ingest(store.clone())));
+
+        let frame = Frame {
+            device_id: 5,
+            sequence: 1,
+            kind: Kind::Voltage,
+            device_timestamp: None,
+            value: 3.5,
+        };
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let legacy: Vec<u8> = [20f32, 21.]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        // several readings per datagram, the last line needs no line break
+        for datagram in [
+            &legacy[..],
+            frame.encode().as_bytes(),
+            b"22\n{\"value\": 45, \"kind\": \"humidity\"}",
+        ] {
+            sender.send_to(datagram, addr).await.unwrap();
+            tokio::time::sleep(BATCH_DELAY / 5).await;
+        }
+
+        let values = wait_for_values(&store, 5).await;
+        let kinds: Vec<_> = values
+            .iter()
+            .map(|value| (value.kind, value.value))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (MeasurementKind::Temperature, 20.),
+                (MeasurementKind::Temperature, 21.),
+                (MeasurementKind::Voltage, 3.5),
+                (MeasurementKind::Temperature, 22.),
+                (MeasurementKind::Humidity, 45.)
+            ]
+        );
+        // readings without identity belong to the address of the sender
+        let sensors = store.list_sensors().await.unwrap();
+        let name = |sensor_id| {
+            sensors
+                .iter()
+                .find(|sensor| sensor.id == sensor_id)
+                .map(|sensor| sensor.name.clone())
+        };
+        assert_eq!(name(values[0].sensor_id).as_deref(), Some("127.0.0.1"));
+        assert_eq!(name(values[2].sensor_id), Some(sensor_name(5)));
+        assert_eq!(values[3].sensor_id, values[0].sensor_id);
+    }
+
+    #[tokio::test]
+    async fn test_count_connection_with_split_first_frame() {
+        let (store, ingest, addr) = serve_tcp().await;
+
+        let frame = Frame {
+            device_id: 9,
+            sequence: 1,
+            kind: Kind::Temperature,
+            device_timestamp: None,
+            value: 21.5,
+        };
+        let encoded = frame.encode();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        // the protocol is detected with the first write, the reading completed by the second one
+        stream.write_all(&encoded.as_bytes()[..10]).await.unwrap();
+        stream.flush().await.unwrap();
+        tokio::time::sleep(BATCH_DELAY / 5).await;
+        stream.write_all(&encoded.as_bytes()[10..]).await.unwrap();
+
+        assert_eq!(wait_for_values(&store, 1).await.len(), 1);
+        assert!(ingest
+            .counters()
+            .summary()
+            .contains("v2: 1 connections, 0 datagrams, 1 readings"));
+    }
+
+    #[tokio::test]
+    async fn test_store_valid_values_of_failed_batch() {
+        let store = MemoryStore::new();
+        let sensor_id = store.register_sensor("picow-1").await.unwrap();
+        let value =
+            |sensor_id, value| NewSensorData::temperature(sensor_id, chrono::Utc::now(), value);
+        let (sender, receiver) = mpsc::channel(10);
+        // the unknown sensor fails the batch
+        for sensor_data in [value(sensor_id, 20.), value(42, 21.), value(sensor_id, 22.)] {
+            sender.send(sensor_data).await.unwrap();
+        }
+        drop(sender);
+        write_batches(store.clone(), receiver).await;
+
+        let values: Vec<_> = store
+            .list_sensordata(&[], QualityFilter::All)
+            .await
+            .unwrap()
+            .iter()
+            .map(|value| value.value)
+            .collect();
+        assert_eq!(values, [20., 22.]);
     }
 }
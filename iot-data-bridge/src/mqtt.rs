@@ -0,0 +1,571 @@
+//! Ingestion of the values other sensors, e.g. ESP32s or Tasmota plugs, publish to an MQTT broker.
+//!
+//! Each topic mapping subscribes to a topic filter and tells how a message becomes a value: its
+//! kind, the sensor it belongs to and where the value is in the payload, either a plain number or
+//! a field of a JSON object. A message matching several mappings yields several values, e.g. the
+//! temperature and the humidity of one Tasmota message.
+
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use chrono::Utc;
+use iot_db_accessor::{MeasurementKind, NewSensorData, SensorStore};
+use rumqttc::{
+    matches, valid_filter, AsyncClient, Event, MqttOptions, Packet, Publish, QoS, SubscribeFilter,
+    SubscribeReasonCode,
+};
+use tracing::{info, warn};
+
+use crate::framing::Protocol;
+use crate::ingest::Ingest;
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_CLIENT_ID: &str = "iot-data-bridge";
+/// wait before connecting again to a broker that failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The broker and the topics to subscribe to
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// user name and password
+    pub credentials: Option<(String, String)>,
+    pub mappings: Vec<TopicMapping>,
+}
+
+impl MqttConfig {
+    /// The configuration of the `IOT_MQTT_*` variables, `None` if `IOT_MQTT_BROKER` is not set
+    pub fn from_env() -> anyhow::Result<Option<MqttConfig>> {
+        let Ok(broker) = env::var("IOT_MQTT_BROKER") else {
+            return Ok(None);
+        };
+        let (host, port) = parse_broker(&broker).context("IOT_MQTT_BROKER")?;
+        let credentials = credentials(
+            env::var("IOT_MQTT_USERNAME").ok(),
+            env::var("IOT_MQTT_PASSWORD").ok(),
+        )?;
+        let mappings = parse_mappings(&env::var("IOT_MQTT_TOPICS").unwrap_or_default())
+            .context("IOT_MQTT_TOPICS")?;
+        if mappings.is_empty() {
+            bail!("IOT_MQTT_TOPICS: no topic mappings configured");
+        }
+        Ok(Some(MqttConfig {
+            host,
+            port,
+            client_id: env::var("IOT_MQTT_CLIENT_ID").unwrap_or(DEFAULT_CLIENT_ID.to_string()),
+            credentials,
+            mappings,
+        }))
+    }
+
+    /// e.g. `localhost:1883` or `[::1]:1883`
+    pub fn broker(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    fn options(&self) -> MqttOptions {
+        let mut options = MqttOptions::new(&self.client_id, &self.host, self.port);
+        if let Some((username, password)) = &self.credentials {
+            options.set_credentials(username, password);
+        }
+        options
+    }
+}
+
+/// Host and port of `host[:port]`, an IPv6 address has to be in brackets, e.g. `[::1]:1883`
+fn parse_broker(broker: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = match broker.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed
+                .split_once(']')
+                .with_context(|| format!("missing ']' in '{}'", broker))?;
+            match rest {
+                "" => (host, None),
+                _ => match rest.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => bail!("expected ':port' after the address, not '{}'", rest),
+                },
+            }
+        }
+        None => match broker.split_once(':') {
+            Some((_, port)) if port.contains(':') => {
+                bail!(
+                    "IPv6 address '{}' has to be in brackets, e.g. [::1]:1883",
+                    broker
+                )
+            }
+            Some((host, port)) => (host, Some(port)),
+            None => (broker, None),
+        },
+    };
+    if host.is_empty() {
+        bail!("missing host in '{}'", broker);
+    }
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .with_context(|| format!("invalid port '{}'", port))?,
+        None => DEFAULT_PORT,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// A password needs a user name, a user name alone logs in with an empty password
+fn credentials(
+    username: Option<String>,
+    password: Option<String>,
+) -> anyhow::Result<Option<(String, String)>> {
+    match (username, password) {
+        (Some(username), password) => Ok(Some((username, password.unwrap_or_default()))),
+        (None, Some(_)) => bail!("IOT_MQTT_PASSWORD is set without IOT_MQTT_USERNAME"),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Mappings separated by `;`, e.g. `esp32/+/temperature; tele/+/SENSOR kind=voltage path=Voltage`
+pub fn parse_mappings(mappings: &str) -> anyhow::Result<Vec<TopicMapping>> {
+    mappings
+        .split(';')
+        .map(str::trim)
+        .filter(|mapping| !mapping.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// How the messages of a topic filter become values
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicMapping {
+    /// `+` matches one level of the topic, a trailing `#` all remaining levels
+    pub filter: String,
+    pub kind: MeasurementKind,
+    /// defaults to the default unit of the kind
+    pub unit: Option<String>,
+    /// JSON pointer to the value, `None` if the payload is a plain number
+    pub pointer: Option<String>,
+    /// name of the sensor, `{1}`, `{2}`, ... are replaced by the topic levels matching the
+    /// wildcards, defaults to the topic
+    pub sensor: Option<String>,
+}
+
+impl FromStr for TopicMapping {
+    type Err = anyhow::Error;
+
+    /// The filter followed by optional `kind=`, `unit=`, `path=` and `sensor=` settings, e.g.
+    /// `tele/+/SENSOR kind=voltage path=ENERGY.Voltage sensor=plug-{1}`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut fields = s.split_whitespace();
+        let filter = fields.next().unwrap_or_default();
+        if !valid_filter(filter) {
+            bail!("invalid topic filter '{}'", filter);
+        }
+        let mut mapping = TopicMapping {
+            filter: filter.to_string(),
+            kind: MeasurementKind::Temperature,
+            unit: None,
+            pointer: None,
+            sensor: None,
+        };
+        for field in fields {
+            match field.split_once('=') {
+                Some(("kind", kind)) => mapping.kind = kind.parse()?,
+                Some(("unit", unit)) => mapping.unit = Some(unit.to_string()),
+                Some(("path", path)) => mapping.pointer = Some(json_pointer(path)),
+                Some(("sensor", sensor)) => mapping.sensor = Some(sensor.to_string()),
+                _ => bail!("invalid setting '{}' of topic filter '{}'", field, filter),
+            }
+        }
+        Ok(mapping)
+    }
+}
+
+// `ENERGY.Voltage` or `$.ENERGY.Voltage` as JSON pointer `/ENERGY/Voltage`, array elements are
+// selected by their index, e.g. `values.0`
+fn json_pointer(path: &str) -> String {
+    let path = path.strip_prefix("$.").unwrap_or(path);
+    path.split('.')
+        .map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+impl TopicMapping {
+    /// The name of the sensor of `topic`, `None` if the topic doesn't match the filter
+    pub fn sensor_name(&self, topic: &str) -> Option<String> {
+        if !matches(topic, &self.filter) {
+            return None;
+        }
+        let Some(template) = &self.sensor else {
+            return Some(topic.to_string());
+        };
+        let levels: Vec<_> = topic.split('/').collect();
+        let wildcards =
+            self.filter
+                .split('/')
+                .enumerate()
+                .filter_map(|(index, level)| match level {
+                    "+" => Some(levels[index].to_string()),
+                    "#" => Some(levels.get(index..).unwrap_or_default().join("/")),
+                    _ => None,
+                });
+        let mut name = template.clone();
+        for (index, wildcard) in wildcards.enumerate() {
+            name = name.replace(&format!("{{{}}}", index + 1), &wildcard);
+        }
+        Some(name)
+    }
+
+    /// The value in the payload of a message
+    pub fn value(&self, payload: &[u8]) -> Result<f64, String> {
+        let payload = std::str::from_utf8(payload).map_err(|e| e.to_string())?;
+        let Some(pointer) = &self.pointer else {
+            return parse_number(payload);
+        };
+        let json: serde_json::Value = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+        match json.pointer(pointer) {
+            Some(serde_json::Value::Number(value)) => value
+                .as_f64()
+                .ok_or_else(|| format!("{} is out of range", value)),
+            // e.g. sensors formatting their values with a fixed precision
+            Some(serde_json::Value::String(value)) => parse_number(value),
+            Some(value) => Err(format!("{} at {} is not a number", value, pointer)),
+            None => Err(format!("no value at {}", pointer)),
+        }
+    }
+
+    pub fn sensor_data(&self, sensor_id: i64, value: f64) -> NewSensorData {
+        NewSensorData {
+            sensor_id,
+            timestamp: Utc::now(),
+            kind: self.kind,
+            unit: self
+                .unit
+                .clone()
+                .unwrap_or_else(|| self.kind.default_unit().to_string()),
+            value,
+            message_id: None,
+            device_timestamp: None,
+        }
+    }
+}
+
+fn parse_number(value: &str) -> Result<f64, String> {
+    let value = value.trim();
+    value
+        .parse()
+        .map_err(|_| format!("'{}' is not a number", value))
+}
+
+/// Subscribes to the topics of the mappings and ingests the values of the received messages,
+/// the connection is renewed whenever it failed
+pub async fn subscribe<S: SensorStore + Clone>(config: MqttConfig, ingest: Ingest<S>) {
+    let broker = config.broker();
+    let (client, mut eventloop) = AsyncClient::new(config.options(), 10);
+    let mut filters: Vec<_> = config
+        .mappings
+        .iter()
+        .map(|mapping| mapping.filter.clone())
+        .collect();
+    filters.sort();
+    filters.dedup();
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}", broker);
+                ingest.counters().connection(Protocol::Mqtt);
+                // the session is clean, so the topics are subscribed again after each reconnect
+                let subscriptions = filters
+                    .iter()
+                    .map(|filter| SubscribeFilter::new(filter.clone(), QoS::AtLeastOnce));
+                if let Err(e) = client.try_subscribe_many(subscriptions) {
+                    warn!("Failed to subscribe to MQTT topics: {}", e);
+                }
+            }
+            Ok(Event::Incoming(Packet::SubAck(suback))) => {
+                for (filter, code) in filters.iter().zip(suback.return_codes) {
+                    if code == SubscribeReasonCode::Failure {
+                        warn!("MQTT broker {} refused subscription to {}", broker, filter);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                receive(&config.mappings, &publish, &ingest).await
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Connection to MQTT broker {} failed: {}", broker, e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn receive<S: SensorStore + Clone>(
+    mappings: &[TopicMapping],
+    publish: &Publish,
+    ingest: &Ingest<S>,
+) {
+    for mapping in mappings {
+        let Some(name) = mapping.sensor_name(&publish.topic) else {
+            continue;
+        };
+        let value = match mapping.value(&publish.payload) {
+            Ok(value) => value,
+            Err(e) => {
+                ingest.counters().invalid(Protocol::Mqtt);
+                warn!("Invalid sensor data on topic {}: {}", publish.topic, e);
+                continue;
+            }
+        };
+        match ingest.sensor_id(&name).await {
+            Ok(sensor_id) => {
+                let sensor_data = mapping.sensor_data(sensor_id, value);
+                ingest
+                    .accept(sensor_data, Protocol::Mqtt, &publish.topic)
+                    .await
+            }
+            Err(e) => warn!("Failed to register sensor {}: {:?}", name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use iot_db_accessor::{MeasurementKind, MemoryStore, SensorStore};
+    use rumqttc::{
+        ConnAck, ConnectReturnCode, Packet, PingResp, Publish, QoS, SubAck, SubscribeReasonCode,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{credentials, parse_broker, parse_mappings, subscribe, MqttConfig, TopicMapping};
+    use crate::spawn_ingest;
+    use crate::tests::wait_for_values;
+
+    fn mapping(mapping: &str) -> TopicMapping {
+        mapping.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_broker() {
+        for (broker, host, port) in [
+            ("localhost", "localhost", 1883),
+            ("localhost:8883", "localhost", 8883),
+            ("192.168.1.2:1884", "192.168.1.2", 1884),
+            ("[::1]", "::1", 1883),
+            ("[fe80::1]:8883", "fe80::1", 8883),
+        ] {
+            assert_eq!(
+                parse_broker(broker).unwrap(),
+                (host.to_string(), port),
+                "{}",
+                broker
+            );
+        }
+        for invalid in [
+            "::1",
+            "fe80::1:1883",
+            "[::1",
+            "[::1]1883",
+            "[]:1883",
+            ":1883",
+            "a:b",
+        ] {
+            assert!(parse_broker(invalid).is_err(), "{}", invalid);
+        }
+
+        let config = |host: &str| MqttConfig {
+            host: host.to_string(),
+            port: 1883,
+            client_id: "test".to_string(),
+            credentials: None,
+            mappings: Vec::new(),
+        };
+        assert_eq!(config("localhost").broker(), "localhost:1883");
+        assert_eq!(config("::1").broker(), "[::1]:1883");
+    }
+
+    #[test]
+    fn test_credentials() {
+        let user = || Some("user".to_string());
+        let password = || Some("secret".to_string());
+        assert_eq!(
+            credentials(user(), password()).unwrap(),
+            Some(("user".to_string(), "secret".to_string()))
+        );
+        assert_eq!(
+            credentials(user(), None).unwrap(),
+            Some(("user".to_string(), String::new()))
+        );
+        assert_eq!(credentials(None, None).unwrap(), None);
+        assert!(credentials(None, password()).is_err());
+    }
+
+    #[test]
+    fn test_parse_mappings() {
+        let mappings = parse_mappings(
+            "esp32/+/temperature; \
+             tele/+/SENSOR kind=voltage path=$.ENERGY.Voltage sensor=plug-{1};",
+        )
+        .unwrap();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].kind, MeasurementKind::Temperature);
+        assert_eq!(mappings[0].pointer, None);
+        assert_eq!(mappings[1].kind, MeasurementKind::Voltage);
+        assert_eq!(mappings[1].pointer.as_deref(), Some("/ENERGY/Voltage"));
+        assert_eq!(mappings[1].sensor.as_deref(), Some("plug-{1}"));
+
+        for invalid in ["a/#/b", "a/b kind=luminosity", "a/b path", "a/b size=3"] {
+            assert!(invalid.parse::<TopicMapping>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_sensor_name() {
+        let plain = mapping("esp32/+/temperature");
+        assert_eq!(
+            plain.sensor_name("esp32/kitchen/temperature").as_deref(),
+            Some("esp32/kitchen/temperature")
+        );
+        assert_eq!(plain.sensor_name("esp32/kitchen/humidity"), None);
+
+        let named = mapping("home/+/+/# sensor={2}-{1}/{3}");
+        assert_eq!(
+            named.sensor_name("home/attic/esp32/a/b").as_deref(),
+            Some("esp32-attic/a/b")
+        );
+    }
+
+    #[test]
+    fn test_value() {
+        let plain = mapping("a/b");
+        assert_eq!(plain.value(b" 21.5\n"), Ok(21.5));
+        assert!(plain.value(b"warm").is_err());
+
+        let json = mapping("a/b path=StatusSNS.values.1");
+        assert_eq!(
+            json.value(br#"{"StatusSNS": {"values": [1, 2.5]}}"#),
+            Ok(2.5)
+        );
+        assert_eq!(
+            json.value(br#"{"StatusSNS": {"values": [1, "3"]}}"#),
+            Ok(3.)
+        );
+        assert!(json
+            .value(br#"{"StatusSNS": {"values": [1, null]}}"#)
+            .is_err());
+        assert!(json.value(br#"{"StatusSNS": {}}"#).is_err());
+        assert!(json.value(b"2.5").is_err());
+    }
+
+    // a broker for one client, which publishes `messages` once the client subscribed
+    async fn broker(listener: TcpListener, messages: Vec<Publish>) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut received = BytesMut::new();
+        let mut sent = BytesMut::new();
+        loop {
+            let packet = match rumqttc::mqttbytes::v4::read(&mut received, 1024) {
+                Ok(packet) => packet,
+                Err(rumqttc::Error::InsufficientBytes(_)) => {
+                    if socket.read_buf(&mut received).await.unwrap() == 0 {
+                        return;
+                    }
+                    continue;
+                }
+                Err(e) => panic!("invalid packet: {:?}", e),
+            };
+            match packet {
+                Packet::Connect(_) => {
+                    ConnAck::new(ConnectReturnCode::Success, false).write(&mut sent)
+                }
+                Packet::Subscribe(subscribe) => {
+                    let codes = subscribe
+                        .filters
+                        .iter()
+                        .map(|_| SubscribeReasonCode::Success(QoS::AtMostOnce))
+                        .collect();
+                    SubAck::new(subscribe.pkid, codes).write(&mut sent).unwrap();
+                    for message in &messages {
+                        message.write(&mut sent).unwrap();
+                    }
+                    Ok(0)
+                }
+                Packet::PingReq => PingResp.write(&mut sent),
+                _ => Ok(0),
+            }
+            .unwrap();
+            socket.write_all(&sent).await.unwrap();
+            sent.clear();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_published_values() {
+        let store = MemoryStore::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let message =
+            |topic: &str, payload: &str| Publish::new(topic, QoS::AtMostOnce, payload.as_bytes());
+        tokio::spawn(broker(
+            listener,
+            vec![
+                message("esp32/kitchen/temperature", "21.5"),
+                message(
+                    "tele/plug1/SENSOR",
+                    r#"{"AM2301": {"Temperature": 19.5, "Humidity": 48}}"#,
+                ),
+                // invalid payloads are skipped
+                message("esp32/kitchen/temperature", "warm"),
+                message("tele/plug1/SENSOR", r#"{"AM2301": {}}"#),
+                message("esp32/kitchen/temperature", "22"),
+            ],
+        ));
+
+        let config = MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            client_id: "test".to_string(),
+            credentials: None,
+            mappings: parse_mappings(
+                "esp32/+/temperature; \
+                 tele/+/SENSOR path=AM2301.Temperature sensor=plug-{1}; \
+                 tele/+/SENSOR kind=humidity path=AM2301.Humidity sensor=plug-{1}",
+            )
+            .unwrap(),
+        };
+        tokio::spawn(subscribe(config, spawn_ingest(store.clone())));
+
+        let values = wait_for_values(&store, 4).await;
+        let kinds: Vec<_> = values
+            .iter()
+            .map(|value| (value.kind, value.value))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (MeasurementKind::Temperature, 21.5),
+                (MeasurementKind::Temperature, 19.5),
+                (MeasurementKind::Humidity, 48.),
+                (MeasurementKind::Temperature, 22.)
+            ]
+        );
+        let sensors = store.list_sensors().await.unwrap();
+        let name = |sensor_id| {
+            sensors
+                .iter()
+                .find(|sensor| sensor.id == sensor_id)
+                .map(|sensor| sensor.name.as_str())
+        };
+        assert_eq!(name(values[0].sensor_id), Some("esp32/kitchen/temperature"));
+        assert_eq!(name(values[1].sensor_id), Some("plug-plug1"));
+        assert_eq!(values[2].sensor_id, values[1].sensor_id);
+    }
+}
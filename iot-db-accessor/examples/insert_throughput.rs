@@ -0,0 +1,75 @@
+//! Compares the throughput of single inserts with batched inserts.
+//!
+//! Uses a temporary database file, run with:
+//! `cargo run --release -p iot-db-accessor --example insert_throughput`
+
+use std::time::Instant;
+
+use iot_db_accessor::{
+    add_sensor_data, add_sensor_data_batch, migrate, MeasurementKind, NewSensorData,
+    DEFAULT_SENSOR_ID,
+};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::SqlitePool;
+
+const VALUES: usize = 5_000;
+const BATCH_SIZE: usize = 100;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let path = std::env::temp_dir().join("iot-insert-throughput.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let options = SqliteConnectOptions::new()
+        .filename(&path)
+        .create_if_missing(true);
+    let pool = SqlitePool::connect_with(options).await?;
+    migrate(&pool).await?;
+
+    let kind = MeasurementKind::Temperature;
+    let values: Vec<_> = (0..VALUES)
+        .map(|i| NewSensorData {
+            sensor_id: DEFAULT_SENSOR_ID,
+            timestamp: chrono::Utc::now(),
+            kind,
+            unit: kind.default_unit().to_string(),
+            value: i as f64,
+            message_id: None,
+            device_timestamp: None,
+        })
+        .collect();
+
+    let start = Instant::now();
+    for value in &values {
+        add_sensor_data(
+            &pool,
+            value.sensor_id,
+            value.timestamp,
+            value.kind,
+            &value.unit,
+            value.value,
+        )
+        .await?;
+    }
+    report("single inserts", start);
+
+    let start = Instant::now();
+    for batch in values.chunks(BATCH_SIZE) {
+        add_sensor_data_batch(&pool, batch).await?;
+    }
+    report(&format!("batches of {}", BATCH_SIZE), start);
+
+    pool.close().await;
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+fn report(name: &str, start: Instant) {
+    let elapsed = start.elapsed();
+    println!(
+        "{:>16}: {} values in {:.2?} ({:.0} values/s)",
+        name,
+        VALUES,
+        elapsed,
+        VALUES as f64 / elapsed.as_secs_f64()
+    );
+}
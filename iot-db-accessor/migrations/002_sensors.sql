@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS sensors (
+    id   INTEGER PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL UNIQUE
+);
+
+-- existing values were recorded without a sensor identity
+INSERT INTO sensors (id, name) VALUES (1, 'default');
+
+-- sqlite cannot add a foreign key column with a default, so the table is rebuilt
+CREATE TABLE sensor_values_new (
+    id        INTEGER PRIMARY KEY NOT NULL,
+    sensor_id INTEGER NOT NULL DEFAULT 1 REFERENCES sensors(id),
+    timestamp DATETIME DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+    value     REAL
+);
+INSERT INTO sensor_values_new (id, sensor_id, timestamp, value)
+    SELECT id, 1, timestamp, value FROM sensor_values;
+DROP TABLE sensor_values;
+ALTER TABLE sensor_values_new RENAME TO sensor_values;
+
+CREATE INDEX sensor_values_sensor_timestamp ON sensor_values (sensor_id, timestamp);
@@ -0,0 +1,3 @@
+-- existing values were all measured by the PicoW temperature sensor
+ALTER TABLE sensor_values ADD COLUMN kind TEXT NOT NULL DEFAULT 'temperature';
+ALTER TABLE sensor_values ADD COLUMN unit TEXT NOT NULL DEFAULT '°C';
@@ -0,0 +1,34 @@
+-- rollups keep sum and count, so averages over several buckets stay exact
+CREATE TABLE IF NOT EXISTS sensor_values_1m (
+    sensor_id    INTEGER NOT NULL REFERENCES sensors(id),
+    kind         TEXT NOT NULL,
+    unit         TEXT NOT NULL,
+    bucket_start DATETIME NOT NULL,
+    min          REAL NOT NULL,
+    max          REAL NOT NULL,
+    sum          REAL NOT NULL,
+    count        INTEGER NOT NULL,
+    PRIMARY KEY (sensor_id, kind, unit, bucket_start)
+);
+CREATE INDEX sensor_values_1m_bucket_start ON sensor_values_1m (bucket_start);
+
+CREATE TABLE IF NOT EXISTS sensor_values_1h (
+    sensor_id    INTEGER NOT NULL REFERENCES sensors(id),
+    kind         TEXT NOT NULL,
+    unit         TEXT NOT NULL,
+    bucket_start DATETIME NOT NULL,
+    min          REAL NOT NULL,
+    max          REAL NOT NULL,
+    sum          REAL NOT NULL,
+    count        INTEGER NOT NULL,
+    PRIMARY KEY (sensor_id, kind, unit, bucket_start)
+);
+CREATE INDEX sensor_values_1h_bucket_start ON sensor_values_1h (bucket_start);
+
+-- everything before rolled_up_until is contained in the rollup table of the tier
+CREATE TABLE IF NOT EXISTS rollup_watermarks (
+    tier            TEXT PRIMARY KEY NOT NULL,
+    rolled_up_until DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS sensor_values_timestamp ON sensor_values (timestamp);
@@ -0,0 +1,31 @@
+-- timestamps are stored as RFC 3339 in UTC, e.g. `2024-01-01T09:00:00+00:00`, the way sqlx
+-- encodes `DateTime<Utc>`, so they still compare correctly as text.
+-- Existing values were recorded with `Utc::now().naive_utc()` or the column default, both in UTC.
+
+-- the column default changes, so the table is rebuilt
+CREATE TABLE sensor_values_new (
+    id        INTEGER PRIMARY KEY NOT NULL,
+    sensor_id INTEGER NOT NULL DEFAULT 1 REFERENCES sensors(id),
+    timestamp DATETIME DEFAULT(STRFTIME('%Y-%m-%dT%H:%M:%f+00:00', 'NOW')),
+    value     REAL,
+    kind      TEXT NOT NULL DEFAULT 'temperature',
+    unit      TEXT NOT NULL DEFAULT '°C'
+);
+-- the column default wrote zero milliseconds as `.000`, sqlx omits them
+INSERT INTO sensor_values_new (id, sensor_id, timestamp, value, kind, unit)
+    SELECT id, sensor_id,
+           REPLACE(
+               CASE WHEN timestamp LIKE '%.000' THEN SUBSTR(timestamp, 1, 19) ELSE timestamp END,
+               ' ', 'T'
+           ) || '+00:00',
+           value, kind, unit
+    FROM sensor_values;
+DROP TABLE sensor_values;
+ALTER TABLE sensor_values_new RENAME TO sensor_values;
+
+CREATE INDEX sensor_values_sensor_timestamp ON sensor_values (sensor_id, timestamp);
+CREATE INDEX sensor_values_timestamp ON sensor_values (timestamp);
+
+UPDATE sensor_values_1m SET bucket_start = REPLACE(bucket_start, ' ', 'T') || '+00:00';
+UPDATE sensor_values_1h SET bucket_start = REPLACE(bucket_start, ' ', 'T') || '+00:00';
+UPDATE rollup_watermarks SET rolled_up_until = REPLACE(rolled_up_until, ' ', 'T') || '+00:00';
@@ -0,0 +1,31 @@
+-- every value is checked against the plausibility rule of its kind when it is inserted
+ALTER TABLE sensor_values ADD COLUMN quality TEXT NOT NULL DEFAULT 'good';
+ALTER TABLE sensor_values ADD COLUMN quality_reason TEXT;
+
+-- limits are given in the default unit of the kind, NULL disables a check
+CREATE TABLE IF NOT EXISTS plausibility_rules (
+    kind                  TEXT PRIMARY KEY NOT NULL,
+    min                   REAL,
+    max                   REAL,
+    max_change_per_minute REAL
+);
+INSERT INTO plausibility_rules (kind, min, max, max_change_per_minute) VALUES
+    ('temperature', -50, 100, 10),
+    ('humidity', 0, 100, 20),
+    ('pressure', 300, 1100, 5),
+    ('voltage', 0, 60, NULL);
+
+-- values recorded before outside of the limits are flagged as well, only values in the default
+-- unit are checked, which are all values the bridge recorded so far
+-- (rollups that were already computed keep them)
+UPDATE sensor_values
+SET quality = 'rejected',
+    quality_reason = 'outside of the plausible range ' || (
+        SELECT min || ' to ' || max FROM plausibility_rules WHERE plausibility_rules.kind = sensor_values.kind
+    )
+WHERE EXISTS (
+    SELECT 1 FROM plausibility_rules
+    WHERE plausibility_rules.kind = sensor_values.kind
+      AND (sensor_values.value < plausibility_rules.min OR sensor_values.value > plausibility_rules.max)
+)
+  AND unit IN ('°C', '%', 'hPa', 'V');
@@ -0,0 +1,13 @@
+-- marks periods like "window opened", an annotation without sensor applies to all sensors,
+-- an instant has the same start and end
+CREATE TABLE IF NOT EXISTS annotations (
+    id        INTEGER PRIMARY KEY NOT NULL,
+    sensor_id INTEGER REFERENCES sensors(id),
+    starts_at DATETIME NOT NULL,
+    ends_at   DATETIME NOT NULL,
+    text      TEXT NOT NULL,
+    -- json array of strings
+    tags      TEXT NOT NULL DEFAULT '[]',
+    CHECK (ends_at >= starts_at)
+);
+CREATE INDEX annotations_starts_at ON annotations (starts_at);
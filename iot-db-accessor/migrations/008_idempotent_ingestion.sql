@@ -0,0 +1,9 @@
+-- identities given by the clients, so a value that is sent again is not stored twice
+ALTER TABLE sensor_values ADD COLUMN message_id TEXT;
+ALTER TABLE sensor_values ADD COLUMN device_timestamp DATETIME;
+
+-- values without identity can't be told apart and are never duplicates
+CREATE UNIQUE INDEX sensor_values_message_id ON sensor_values (sensor_id, message_id)
+    WHERE message_id IS NOT NULL;
+CREATE UNIQUE INDEX sensor_values_device_timestamp ON sensor_values (sensor_id, kind, device_timestamp)
+    WHERE device_timestamp IS NOT NULL;
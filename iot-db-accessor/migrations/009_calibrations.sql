@@ -0,0 +1,72 @@
+-- a calibration corrects the values of one sensor and kind measured since valid_from to
+-- value * gain + offset, until the next calibration of the sensor and kind becomes valid
+CREATE TABLE IF NOT EXISTS calibrations (
+    id         INTEGER PRIMARY KEY NOT NULL,
+    sensor_id  INTEGER NOT NULL REFERENCES sensors(id),
+    kind       TEXT NOT NULL,
+    valid_from DATETIME NOT NULL,
+    offset     REAL NOT NULL DEFAULT 0,
+    gain       REAL NOT NULL DEFAULT 1,
+    UNIQUE (sensor_id, kind, valid_from),
+    -- a positive gain keeps the order of the values, so minima of rollups stay minima
+    CHECK (gain > 0)
+);
+
+-- the stored values stay raw, calibrations are applied when the values are read
+CREATE VIEW calibrated_sensor_values AS
+SELECT sensor_values.id, sensor_values.sensor_id, sensor_values.timestamp, sensor_values.kind,
+       sensor_values.unit,
+       sensor_values.value * COALESCE(calibrations.gain, 1) + COALESCE(calibrations.offset, 0)
+           AS value,
+       sensor_values.value AS raw_value,
+       sensor_values.quality, sensor_values.quality_reason
+FROM sensor_values
+LEFT JOIN calibrations ON calibrations.id = (
+    SELECT id FROM calibrations
+    WHERE calibrations.sensor_id = sensor_values.sensor_id
+      AND calibrations.kind = sensor_values.kind
+      AND calibrations.valid_from <= sensor_values.timestamp
+    ORDER BY valid_from DESC
+    LIMIT 1
+);
+
+-- rollups are calibrated with the calibration valid at the start of their bucket
+CREATE VIEW calibrated_sensor_values_1m AS
+SELECT sensor_values_1m.sensor_id, sensor_values_1m.kind, sensor_values_1m.unit,
+       sensor_values_1m.bucket_start,
+       sensor_values_1m.min * COALESCE(calibrations.gain, 1) + COALESCE(calibrations.offset, 0)
+           AS min,
+       sensor_values_1m.max * COALESCE(calibrations.gain, 1) + COALESCE(calibrations.offset, 0)
+           AS max,
+       sensor_values_1m.sum * COALESCE(calibrations.gain, 1)
+           + COALESCE(calibrations.offset, 0) * sensor_values_1m.count AS sum,
+       sensor_values_1m.count
+FROM sensor_values_1m
+LEFT JOIN calibrations ON calibrations.id = (
+    SELECT id FROM calibrations
+    WHERE calibrations.sensor_id = sensor_values_1m.sensor_id
+      AND calibrations.kind = sensor_values_1m.kind
+      AND calibrations.valid_from <= sensor_values_1m.bucket_start
+    ORDER BY valid_from DESC
+    LIMIT 1
+);
+
+CREATE VIEW calibrated_sensor_values_1h AS
+SELECT sensor_values_1h.sensor_id, sensor_values_1h.kind, sensor_values_1h.unit,
+       sensor_values_1h.bucket_start,
+       sensor_values_1h.min * COALESCE(calibrations.gain, 1) + COALESCE(calibrations.offset, 0)
+           AS min,
+       sensor_values_1h.max * COALESCE(calibrations.gain, 1) + COALESCE(calibrations.offset, 0)
+           AS max,
+       sensor_values_1h.sum * COALESCE(calibrations.gain, 1)
+           + COALESCE(calibrations.offset, 0) * sensor_values_1h.count AS sum,
+       sensor_values_1h.count
+FROM sensor_values_1h
+LEFT JOIN calibrations ON calibrations.id = (
+    SELECT id FROM calibrations
+    WHERE calibrations.sensor_id = sensor_values_1h.sensor_id
+      AND calibrations.kind = sensor_values_1h.kind
+      AND calibrations.valid_from <= sensor_values_1h.bucket_start
+    ORDER BY valid_from DESC
+    LIMIT 1
+);
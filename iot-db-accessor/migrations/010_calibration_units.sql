@@ -0,0 +1,94 @@
+-- the offset of a calibration is in the unit of the values it corrects, so a calibration only
+-- applies to values of its unit, existing calibrations were meant for the default unit
+DROP VIEW calibrated_sensor_values;
+DROP VIEW calibrated_sensor_values_1m;
+DROP VIEW calibrated_sensor_values_1h;
+
+CREATE TABLE calibrations_with_unit (
+    id         INTEGER PRIMARY KEY NOT NULL,
+    sensor_id  INTEGER NOT NULL REFERENCES sensors(id),
+    kind       TEXT NOT NULL,
+    unit       TEXT NOT NULL,
+    valid_from DATETIME NOT NULL,
+    offset     REAL NOT NULL DEFAULT 0,
+    gain       REAL NOT NULL DEFAULT 1,
+    UNIQUE (sensor_id, kind, unit, valid_from),
+    -- a positive gain keeps the order of the values, so minima of rollups stay minima
+    CHECK (gain > 0)
+);
+
+INSERT INTO calibrations_with_unit (id, sensor_id, kind, unit, valid_from, offset, gain)
+SELECT id, sensor_id, kind,
+       CASE kind
+           WHEN 'temperature' THEN '°C'
+           WHEN 'humidity' THEN '%'
+           WHEN 'pressure' THEN 'hPa'
+           WHEN 'voltage' THEN 'V'
+       END,
+       valid_from, offset, gain
+FROM calibrations;
+
+DROP TABLE calibrations;
+ALTER TABLE calibrations_with_unit RENAME TO calibrations;
+
+-- the stored values stay raw, calibrations are applied when the values are read
+CREATE VIEW calibrated_sensor_values AS
+SELECT sensor_values.id, sensor_values.sensor_id, sensor_values.timestamp, sensor_values.kind,
+       sensor_values.unit,
+       sensor_values.value * COALESCE(calibrations.gain, 1) + COALESCE(calibrations.offset, 0)
+           AS value,
+       sensor_values.value AS raw_value,
+       sensor_values.quality, sensor_values.quality_reason
+FROM sensor_values
+LEFT JOIN calibrations ON calibrations.id = (
+    SELECT id FROM calibrations
+    WHERE calibrations.sensor_id = sensor_values.sensor_id
+      AND calibrations.kind = sensor_values.kind
+      AND calibrations.unit = sensor_values.unit
+      AND calibrations.valid_from <= sensor_values.timestamp
+    ORDER BY valid_from DESC
+    LIMIT 1
+);
+
+-- rollups are calibrated with the calibration valid at the start of their bucket
+CREATE VIEW calibrated_sensor_values_1m AS
+SELECT sensor_values_1m.sensor_id, sensor_values_1m.kind, sensor_values_1m.unit,
+       sensor_values_1m.bucket_start,
+       sensor_values_1m.min * COALESCE(calibrations.gain, 1) + COALESCE(calibrations.offset, 0)
+           AS min,
+       sensor_values_1m.max * COALESCE(calibrations.gain, 1) + COALESCE(calibrations.offset, 0)
+           AS max,
+       sensor_values_1m.sum * COALESCE(calibrations.gain, 1)
+           + COALESCE(calibrations.offset, 0) * sensor_values_1m.count AS sum,
+       sensor_values_1m.count
+FROM sensor_values_1m
+LEFT JOIN calibrations ON calibrations.id = (
+    SELECT id FROM calibrations
+    WHERE calibrations.sensor_id = sensor_values_1m.sensor_id
+      AND calibrations.kind = sensor_values_1m.kind
+      AND calibrations.unit = sensor_values_1m.unit
+      AND calibrations.valid_from <= sensor_values_1m.bucket_start
+    ORDER BY valid_from DESC
+    LIMIT 1
+);
+
+CREATE VIEW calibrated_sensor_values_1h AS
+SELECT sensor_values_1h.sensor_id, sensor_values_1h.kind, sensor_values_1h.unit,
+       sensor_values_1h.bucket_start,
+       sensor_values_1h.min * COALESCE(calibrations.gain, 1) + COALESCE(calibrations.offset, 0)
+           AS min,
+       sensor_values_1h.max * COALESCE(calibrations.gain, 1) + COALESCE(calibrations.offset, 0)
+           AS max,
+       sensor_values_1h.sum * COALESCE(calibrations.gain, 1)
+           + COALESCE(calibrations.offset, 0) * sensor_values_1h.count AS sum,
+       sensor_values_1h.count
+FROM sensor_values_1h
+LEFT JOIN calibrations ON calibrations.id = (
+    SELECT id FROM calibrations
+    WHERE calibrations.sensor_id = sensor_values_1h.sensor_id
+      AND calibrations.kind = sensor_values_1h.kind
+      AND calibrations.unit = sensor_values_1h.unit
+      AND calibrations.valid_from <= sensor_values_1h.bucket_start
+    ORDER BY valid_from DESC
+    LIMIT 1
+);
@@ -0,0 +1,63 @@
+-- minute buckets whose raw values were inserted or changed after the minute was rolled up,
+-- the next retention run aggregates them again
+CREATE TABLE IF NOT EXISTS stale_rollups (
+    sensor_id    INTEGER NOT NULL,
+    kind         TEXT NOT NULL,
+    unit         TEXT NOT NULL,
+    bucket_start DATETIME NOT NULL,
+    PRIMARY KEY (sensor_id, kind, unit, bucket_start)
+);
+
+-- everything before deleted_until was removed from the table of the tier, the raw values
+-- before it are only the late ones, which are not yet contained in the rollups
+CREATE TABLE IF NOT EXISTS retention_cutoffs (
+    tier          TEXT PRIMARY KEY NOT NULL,
+    deleted_until DATETIME NOT NULL
+);
+
+-- the ON CONFLICT clause of an upsert into sensor_values overrides an OR IGNORE in the triggers,
+-- so existing stale buckets are skipped explicitly
+CREATE TRIGGER sensor_values_late_insert AFTER INSERT ON sensor_values
+BEGIN
+    INSERT INTO stale_rollups (sensor_id, kind, unit, bucket_start)
+    SELECT NEW.sensor_id, NEW.kind, NEW.unit, strftime('%Y-%m-%dT%H:%M:00+00:00', NEW.timestamp)
+    WHERE NEW.timestamp < (SELECT rolled_up_until FROM rollup_watermarks WHERE tier = '1m')
+      AND NOT EXISTS (
+          SELECT 1 FROM stale_rollups
+          WHERE sensor_id = NEW.sensor_id AND kind = NEW.kind AND unit = NEW.unit
+            AND bucket_start = strftime('%Y-%m-%dT%H:%M:00+00:00', NEW.timestamp)
+      );
+END;
+
+-- the bucket the value left is stale as well as the one it moved to
+CREATE TRIGGER sensor_values_late_update AFTER UPDATE ON sensor_values
+BEGIN
+    INSERT INTO stale_rollups (sensor_id, kind, unit, bucket_start)
+    SELECT OLD.sensor_id, OLD.kind, OLD.unit, strftime('%Y-%m-%dT%H:%M:00+00:00', OLD.timestamp)
+    WHERE OLD.timestamp < (SELECT rolled_up_until FROM rollup_watermarks WHERE tier = '1m')
+      AND NOT EXISTS (
+          SELECT 1 FROM stale_rollups
+          WHERE sensor_id = OLD.sensor_id AND kind = OLD.kind AND unit = OLD.unit
+            AND bucket_start = strftime('%Y-%m-%dT%H:%M:00+00:00', OLD.timestamp)
+      );
+    INSERT INTO stale_rollups (sensor_id, kind, unit, bucket_start)
+    SELECT NEW.sensor_id, NEW.kind, NEW.unit, strftime('%Y-%m-%dT%H:%M:00+00:00', NEW.timestamp)
+    WHERE NEW.timestamp < (SELECT rolled_up_until FROM rollup_watermarks WHERE tier = '1m')
+      AND NOT EXISTS (
+          SELECT 1 FROM stale_rollups
+          WHERE sensor_id = NEW.sensor_id AND kind = NEW.kind AND unit = NEW.unit
+            AND bucket_start = strftime('%Y-%m-%dT%H:%M:00+00:00', NEW.timestamp)
+      );
+END;
+
+-- the cutoffs of earlier runs were not recorded, the oldest kept rows are the best guess
+INSERT INTO retention_cutoffs (tier, deleted_until)
+SELECT 'raw', MIN(rolled_up_until, COALESCE(
+    (SELECT strftime('%Y-%m-%dT%H:%M:00+00:00', MIN(timestamp)) FROM sensor_values),
+    rolled_up_until))
+FROM rollup_watermarks WHERE tier = '1m';
+INSERT INTO retention_cutoffs (tier, deleted_until)
+SELECT '1m', MIN(rolled_up_until, COALESCE(
+    (SELECT strftime('%Y-%m-%dT%H:00:00+00:00', MIN(bucket_start)) FROM sensor_values_1m),
+    rolled_up_until))
+FROM rollup_watermarks WHERE tier = '1h';
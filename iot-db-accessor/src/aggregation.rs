@@ -0,0 +1,435 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::retention::{retention_cutoffs, rollup_watermarks};
+use crate::{sensor_filter, Error, MeasurementKind, Result};
+
+/// Width of the time buckets values are aggregated in, e.g. `15m` or `1d`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BucketWidth {
+    seconds: u32,
+}
+
+impl BucketWidth {
+    pub fn from_seconds(seconds: u32) -> Result<Self> {
+        if seconds == 0 {
+            return Err(Error::invalid_input("bucket width must not be zero"));
+        }
+        Ok(BucketWidth { seconds })
+    }
+
+    pub fn seconds(&self) -> u32 {
+        self.seconds
+    }
+}
+
+impl FromStr for BucketWidth {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        BucketWidth::from_seconds(parse_seconds(s)?)
+    }
+}
+
+/// Parses a number followed by one of the units `s`, `m`, `h` or `d` into seconds
+pub(crate) fn parse_seconds(s: &str) -> Result<u32> {
+    let split = s.len() - s.chars().last().map_or(0, char::len_utf8);
+    let (count, unit) = s.split_at(split);
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(Error::invalid_input(format!(
+                "invalid duration '{}', expected e.g. 1m, 15m, 1h or 1d",
+                s
+            )))
+        }
+    };
+    let count: u32 = count
+        .parse()
+        .map_err(|_| Error::invalid_input(format!("invalid duration '{}'", s)))?;
+    count
+        .checked_mul(factor)
+        .ok_or_else(|| Error::invalid_input(format!("duration '{}' is too large", s)))
+}
+
+/// Parses a duration like `30s`, `5m`, `1h` or `1d`
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    Ok(Duration::seconds(parse_seconds(s)?.into()))
+}
+
+/// Aggregate function computed per bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Min,
+    Max,
+    Avg,
+    Count,
+}
+
+impl Aggregate {
+    pub const ALL: [Aggregate; 4] = [
+        Aggregate::Min,
+        Aggregate::Max,
+        Aggregate::Avg,
+        Aggregate::Count,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::Avg => "avg",
+            Aggregate::Count => "count",
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Aggregate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Aggregate::ALL
+            .into_iter()
+            .find(|aggregate| aggregate.as_str() == s)
+            .ok_or_else(|| Error::invalid_input(format!("unknown aggregate '{}'", s)))
+    }
+}
+
+/// One time bucket in the default unit of the kind, aggregates that were not requested are `None`
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow, Serialize)]
+pub struct AggregatedBucket {
+    pub bucket_start: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<i64>,
+}
+
+/// Aggregates the values of one kind in `[from, to)` per bucket, buckets without values are omitted.
+/// An empty `sensors` slice aggregates the values of all sensors.
+///
+/// Rejected values are never aggregated, neither are they part of the rollups. Values of other
+/// units are converted to the default unit of the kind, like [`MeasurementKind::to_default_unit`].
+///
+/// If the width and the range are aligned to whole minutes or hours the rollup tiers are used,
+/// so the result also covers raw values that were already removed by the retention policy.
+/// Otherwise a range that starts before the removed values is rejected as invalid input.
+pub async fn aggregate_sensordata(
+    pool: &SqlitePool,
+    sensors: &[i64],
+    kind: MeasurementKind,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    width: BucketWidth,
+    aggregates: &[Aggregate],
+) -> Result<Vec<AggregatedBucket>> {
+    let sensors = sensor_filter(sensors)?;
+    let width = width.seconds();
+
+    // raw values are used from `raw_from`, minute rollups from `minute_from` up to `raw_from`
+    // and hour rollups before `minute_from`
+    let (minute_watermark, hour_watermark) = rollup_watermarks(pool).await?;
+    let aligned = |seconds: i64| {
+        i64::from(width) % seconds == 0
+            && from.timestamp() % seconds == 0
+            && to.timestamp() % seconds == 0
+    };
+    let (raw_deleted_until, minute_deleted_until) = retention_cutoffs(pool).await?;
+    let (raw_from, minute_from) = if aligned(60 * 60) {
+        (minute_watermark, hour_watermark)
+    } else if aligned(60) {
+        if *from < minute_deleted_until {
+            return Err(Error::invalid_input(format!(
+                "minute rollups before {} were removed, align the width and the range to hours",
+                minute_deleted_until
+            )));
+        }
+        (minute_watermark, DateTime::UNIX_EPOCH)
+    } else {
+        if *from < raw_deleted_until {
+            return Err(Error::invalid_input(format!(
+                "raw values before {} were removed, align the width and the range to minutes",
+                raw_deleted_until
+            )));
+        }
+        (DateTime::UNIX_EPOCH, DateTime::UNIX_EPOCH)
+    };
+
+    let mut recs = sqlx::query_as_unchecked!(
+        AggregatedBucket,
+        r#"
+    WITH source (sensor_id, kind, unit, timestamp, min, max, sum, count) AS (
+        SELECT sensor_id, kind, unit, timestamp, value, value, value, 1
+        FROM calibrated_sensor_values
+        WHERE timestamp >= $6 AND value IS NOT NULL AND quality != 'rejected'
+        UNION ALL
+        SELECT sensor_id, kind, unit, bucket_start, min, max, sum, count
+        FROM calibrated_sensor_values_1m
+        WHERE bucket_start >= $7 AND bucket_start < $6
+        UNION ALL
+        SELECT sensor_id, kind, unit, bucket_start, min, max, sum, count
+        FROM calibrated_sensor_values_1h
+        WHERE bucket_start < $7
+    ),
+    -- the default unit is value * scale + shift, the scale is positive so minima stay minima
+    converted (timestamp, min, max, sum, count) AS (
+        SELECT timestamp, min * scale + shift, max * scale + shift, sum * scale + shift * count,
+               count
+        FROM (
+            SELECT *,
+                   CASE unit
+                       WHEN '°F' THEN 5.0 / 9.0
+                       WHEN 'Pa' THEN 0.01
+                       WHEN 'kPa' THEN 10.0
+                       WHEN 'mV' THEN 0.001
+                       ELSE 1.0
+                   END AS scale,
+                   CASE unit
+                       WHEN '°F' THEN -32.0 * 5.0 / 9.0
+                       WHEN 'K' THEN -273.15
+                       ELSE 0.0
+                   END AS shift
+            FROM source
+            WHERE timestamp >= $2 AND timestamp < $3
+              AND kind = $4
+              AND ($5 = '[]' OR sensor_id IN (SELECT value FROM json_each($5)))
+        )
+    )
+    SELECT datetime((CAST(strftime('%s', timestamp) AS INTEGER) / $1) * $1, 'unixepoch')
+               AS bucket_start,
+           MIN(min) AS min,
+           MAX(max) AS max,
+           SUM(sum) / SUM(count) AS avg,
+           SUM(count) AS count
+    FROM converted
+    GROUP BY bucket_start
+    ORDER BY bucket_start
+    "#,
+        width,
+        from,
+        to,
+        kind,
+        sensors,
+        raw_from,
+        minute_from
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for rec in recs.iter_mut() {
+        if !aggregates.contains(&Aggregate::Min) {
+            rec.min = None;
+        }
+        if !aggregates.contains(&Aggregate::Max) {
+            rec.max = None;
+        }
+        if !aggregates.contains(&Aggregate::Avg) {
+            rec.avg = None;
+        }
+        if !aggregates.contains(&Aggregate::Count) {
+            rec.count = None;
+        }
+    }
+    Ok(recs)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use sqlx::SqlitePool;
+
+    use super::{aggregate_sensordata, Aggregate, BucketWidth};
+    use crate::{add_sensor_data, register_sensor, to_utc_datetime, MeasurementKind};
+
+    #[test]
+    fn test_parse_bucket_width() {
+        assert_eq!("1m".parse::<BucketWidth>().unwrap().seconds(), 60);
+        assert_eq!("15m".parse::<BucketWidth>().unwrap().seconds(), 900);
+        assert_eq!("1h".parse::<BucketWidth>().unwrap().seconds(), 3600);
+        assert_eq!("1d".parse::<BucketWidth>().unwrap().seconds(), 86400);
+        assert!("0m".parse::<BucketWidth>().is_err());
+        assert!("1w".parse::<BucketWidth>().is_err());
+        assert!("m".parse::<BucketWidth>().is_err());
+        assert!("".parse::<BucketWidth>().is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_aggregate_hourly(pool: SqlitePool) -> sqlx::Result<()> {
+        let sensor = register_sensor(&pool, "picow-1").await.unwrap();
+        let other = register_sensor(&pool, "picow-2").await.unwrap();
+        let kind = MeasurementKind::Temperature;
+        for (sensor, timestamp, value) in [
+            (sensor, "2024-01-01 09:00:00", 10.),
+            (sensor, "2024-01-01 09:30:00", 11.),
+            (sensor, "2024-01-01 09:59:59", 15.),
+            (sensor, "2024-01-01 10:00:00", 13.),
+            (sensor, "2024-01-01 12:10:00", 20.),
+            (other, "2024-01-01 09:10:00", 100.),
+        ] {
+            add_sensor_data(
+                &pool,
+                sensor,
+                to_utc_datetime(timestamp).unwrap(),
+                kind,
+                "°C",
+                value,
+            )
+            .await
+            .unwrap();
+        }
+        add_sensor_data(
+            &pool,
+            sensor,
+            to_utc_datetime("2024-01-01 09:10:00").unwrap(),
+            MeasurementKind::Humidity,
+            "%",
+            50.,
+        )
+        .await
+        .unwrap();
+
+        let buckets = aggregate_sensordata(
+            &pool,
+            &[sensor],
+            kind,
+            &to_utc_datetime("2024-01-01 09:00:00").unwrap(),
+            &to_utc_datetime("2024-01-01 12:00:00").unwrap(),
+            "1h".parse().unwrap(),
+            &Aggregate::ALL,
+        )
+        .await
+        .unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(
+            buckets[0].bucket_start,
+            to_utc_datetime("2024-01-01 09:00:00").unwrap()
+        );
+        assert_eq!(buckets[0].min, Some(10.));
+        assert_eq!(buckets[0].max, Some(15.));
+        assert_eq!(buckets[0].avg, Some(12.));
+        assert_eq!(buckets[0].count, Some(3));
+        assert_eq!(
+            buckets[1].bucket_start,
+            to_utc_datetime("2024-01-01 10:00:00").unwrap()
+        );
+        assert_eq!(buckets[1].count, Some(1));
+
+        let buckets = aggregate_sensordata(
+            &pool,
+            &[],
+            kind,
+            &to_utc_datetime("2024-01-01 00:00:00").unwrap(),
+            &to_utc_datetime("2024-01-02 00:00:00").unwrap(),
+            "1d".parse().unwrap(),
+            &[Aggregate::Max, Aggregate::Count],
+        )
+        .await
+        .unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].min, None);
+        assert_eq!(buckets[0].max, Some(100.));
+        assert_eq!(buckets[0].avg, None);
+        assert_eq!(buckets[0].count, Some(6));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_aggregate_mixed_units(pool: SqlitePool) -> sqlx::Result<()> {
+        let sensor = register_sensor(&pool, "picow-1").await.unwrap();
+        let from = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        let to = to_utc_datetime("2024-01-01 10:00:00").unwrap();
+        // 10 °C, 20 °C and 30 °C in one bucket
+        for (minute, unit, value) in [(0, "°C", 10.), (5, "°F", 68.), (10, "K", 303.15)] {
+            add_sensor_data(
+                &pool,
+                sensor,
+                from + Duration::minutes(minute),
+                MeasurementKind::Temperature,
+                unit,
+                value,
+            )
+            .await
+            .unwrap();
+        }
+        let buckets = aggregate_sensordata(
+            &pool,
+            &[sensor],
+            MeasurementKind::Temperature,
+            &from,
+            &to,
+            "1h".parse().unwrap(),
+            &Aggregate::ALL,
+        )
+        .await
+        .unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert!((buckets[0].min.unwrap() - 10.).abs() < 1e-9);
+        assert!((buckets[0].max.unwrap() - 30.).abs() < 1e-9);
+        assert!((buckets[0].avg.unwrap() - 20.).abs() < 1e-9);
+
+        // the conversion of the query matches the one of the kinds for every unit
+        let plausible = [
+            (MeasurementKind::Temperature, "°C", 20.),
+            (MeasurementKind::Temperature, "°F", 68.),
+            (MeasurementKind::Temperature, "K", 293.15),
+            (MeasurementKind::Humidity, "%", 50.),
+            (MeasurementKind::Pressure, "hPa", 1000.),
+            (MeasurementKind::Pressure, "Pa", 100_000.),
+            (MeasurementKind::Pressure, "kPa", 100.),
+            (MeasurementKind::Voltage, "V", 3.3),
+            (MeasurementKind::Voltage, "mV", 3300.),
+        ];
+        let units: usize = MeasurementKind::ALL
+            .iter()
+            .map(|kind| kind.units().len())
+            .sum();
+        assert_eq!(plausible.len(), units);
+        for (kind, unit, value) in plausible {
+            add_sensor_data(&pool, sensor, to, kind, unit, value)
+                .await
+                .unwrap();
+            let buckets = aggregate_sensordata(
+                &pool,
+                &[sensor],
+                kind,
+                &to,
+                &(to + Duration::seconds(1)),
+                "1s".parse().unwrap(),
+                &[Aggregate::Avg],
+            )
+            .await
+            .unwrap();
+            let expected = kind.to_default_unit(unit, value);
+            assert!(
+                (buckets[0].avg.unwrap() - expected).abs() < 1e-9,
+                "{} {}",
+                kind,
+                unit
+            );
+            sqlx::query("DELETE FROM sensor_values WHERE timestamp = $1")
+                .bind(to)
+                .execute(&pool)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
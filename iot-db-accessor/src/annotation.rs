@@ -0,0 +1,320 @@
+//! Annotations mark periods like "window opened" or "sensor relocated", so jumps in the values
+//! can be explained later.
+//!
+//! An annotation without sensor applies to all sensors. Tags are free text and stored as json array.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{sensor_filter, Error, Result, SensorData};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Annotation {
+    pub id: i64,
+    pub sensor_id: Option<i64>,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    pub text: String,
+    pub tags: Vec<String>,
+}
+
+impl Annotation {
+    /// Whether the annotation overlaps `[from, to)`
+    pub fn overlaps(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> bool {
+        self.starts_at < *to && self.ends_at >= *from
+    }
+}
+
+/// An annotation that is not yet stored, or the new content of a stored one
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewAnnotation {
+    pub sensor_id: Option<i64>,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    pub text: String,
+    pub tags: Vec<String>,
+}
+
+impl NewAnnotation {
+    /// Checks that the text is not blank, the range is not reversed and the tags are not blank
+    pub fn validate(&self) -> Result<()> {
+        if self.text.trim().is_empty() {
+            return Err(Error::invalid_input("annotation text must not be empty"));
+        }
+        if self.ends_at < self.starts_at {
+            return Err(Error::invalid_input(format!(
+                "annotation ends at {} before it starts at {}",
+                self.ends_at, self.starts_at
+            )));
+        }
+        if self.tags.iter().any(|tag| tag.trim().is_empty()) {
+            return Err(Error::invalid_input("annotation tags must not be empty"));
+        }
+        Ok(())
+    }
+
+    /// The annotation stored with `id`
+    pub fn into_annotation(self, id: i64) -> Annotation {
+        Annotation {
+            id,
+            sensor_id: self.sensor_id,
+            starts_at: self.starts_at,
+            ends_at: self.ends_at,
+            text: self.text,
+            tags: self.tags,
+        }
+    }
+}
+
+/// Values of a range together with the annotations overlapping it
+#[derive(Debug, Clone, Serialize)]
+pub struct AnnotatedSensorData {
+    pub values: Vec<SensorData>,
+    pub annotations: Vec<Annotation>,
+}
+
+// the tags column is decoded after the query
+struct AnnotationRow {
+    id: i64,
+    sensor_id: Option<i64>,
+    starts_at: DateTime<Utc>,
+    ends_at: DateTime<Utc>,
+    text: String,
+    tags: String,
+}
+
+impl TryFrom<AnnotationRow> for Annotation {
+    type Error = Error;
+
+    fn try_from(row: AnnotationRow) -> Result<Self> {
+        Ok(Annotation {
+            id: row.id,
+            sensor_id: row.sensor_id,
+            starts_at: row.starts_at,
+            ends_at: row.ends_at,
+            text: row.text,
+            tags: serde_json::from_str(&row.tags)?,
+        })
+    }
+}
+
+pub async fn add_annotation(pool: &SqlitePool, annotation: &NewAnnotation) -> Result<i64> {
+    annotation.validate()?;
+    let tags = serde_json::to_string(&annotation.tags)?;
+    let id = sqlx::query!(
+        r#"
+    INSERT INTO annotations (sensor_id, starts_at, ends_at, text, tags)
+    VALUES ($1, $2, $3, $4, $5)
+        "#,
+        annotation.sensor_id,
+        annotation.starts_at,
+        annotation.ends_at,
+        annotation.text,
+        tags
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn get_annotation(pool: &SqlitePool, id: i64) -> Result<Annotation> {
+    let row = sqlx::query_as_unchecked!(
+        AnnotationRow,
+        r#"
+    SELECT id, sensor_id, starts_at, ends_at, text, tags
+    FROM annotations
+    WHERE id = $1
+    "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| Error::NotFound(format!("annotation {}", id)))?;
+    row.try_into()
+}
+
+/// Replaces the content of the annotation
+pub async fn update_annotation(
+    pool: &SqlitePool,
+    id: i64,
+    annotation: &NewAnnotation,
+) -> Result<()> {
+    annotation.validate()?;
+    let tags = serde_json::to_string(&annotation.tags)?;
+    let updated = sqlx::query!(
+        r#"
+    UPDATE annotations
+    SET sensor_id = $2, starts_at = $3, ends_at = $4, text = $5, tags = $6
+    WHERE id = $1
+        "#,
+        id,
+        annotation.sensor_id,
+        annotation.starts_at,
+        annotation.ends_at,
+        annotation.text,
+        tags
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        return Err(Error::NotFound(format!("annotation {}", id)));
+    }
+    Ok(())
+}
+
+pub async fn delete_annotation(pool: &SqlitePool, id: i64) -> Result<()> {
+    let deleted = sqlx::query!("DELETE FROM annotations WHERE id = $1", id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(Error::NotFound(format!("annotation {}", id)));
+    }
+    Ok(())
+}
+
+/// Lists the annotations overlapping `[from, to)` ascending by start. An empty `sensors` slice
+/// lists the annotations of all sensors, annotations without sensor are always listed.
+pub async fn list_annotations(
+    pool: &SqlitePool,
+    sensors: &[i64],
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> Result<Vec<Annotation>> {
+    let sensors = sensor_filter(sensors)?;
+    let rows = sqlx::query_as_unchecked!(
+        AnnotationRow,
+        r#"
+    SELECT id, sensor_id, starts_at, ends_at, text, tags
+    FROM annotations
+    WHERE starts_at < $2 AND ends_at >= $1
+      AND ($3 = '[]' OR sensor_id IS NULL OR sensor_id IN (SELECT value FROM json_each($3)))
+    ORDER BY starts_at, id
+    "#,
+        from,
+        to,
+        sensors
+    )
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter().map(Annotation::try_from).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::SqlitePool;
+
+    use super::{
+        add_annotation, delete_annotation, get_annotation, list_annotations, update_annotation,
+        NewAnnotation,
+    };
+    use crate::{
+        get_date_with_default, get_end_date_with_default, register_sensor, to_utc_datetime, Error,
+    };
+
+    fn new_annotation(sensor_id: Option<i64>, starts_at: &str, ends_at: &str) -> NewAnnotation {
+        NewAnnotation {
+            sensor_id,
+            starts_at: to_utc_datetime(starts_at).unwrap(),
+            ends_at: to_utc_datetime(ends_at).unwrap(),
+            text: "window opened".to_string(),
+            tags: vec!["window".to_string()],
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_crud(pool: SqlitePool) -> sqlx::Result<()> {
+        let sensor = register_sensor(&pool, "picow-1").await.unwrap();
+        let annotation = new_annotation(Some(sensor), "2024-01-01 09:00:00", "2024-01-01 09:15:00");
+        let id = add_annotation(&pool, &annotation).await.unwrap();
+        assert_eq!(
+            get_annotation(&pool, id).await.unwrap(),
+            annotation.clone().into_annotation(id)
+        );
+
+        let changed = NewAnnotation {
+            text: "heater maintenance".to_string(),
+            tags: vec!["heater".to_string(), "maintenance".to_string()],
+            sensor_id: None,
+            ..annotation
+        };
+        update_annotation(&pool, id, &changed).await.unwrap();
+        let stored = get_annotation(&pool, id).await.unwrap();
+        assert_eq!(stored.text, "heater maintenance");
+        assert_eq!(stored.tags, ["heater", "maintenance"]);
+        assert_eq!(stored.sensor_id, None);
+
+        delete_annotation(&pool, id).await.unwrap();
+        for error in [
+            get_annotation(&pool, id).await.unwrap_err(),
+            update_annotation(&pool, id, &changed).await.unwrap_err(),
+            delete_annotation(&pool, id).await.unwrap_err(),
+        ] {
+            assert!(matches!(error, Error::NotFound(_)), "{:?}", error);
+        }
+
+        // reversed range, blank text and unknown sensor
+        let reversed = new_annotation(None, "2024-01-01 10:00:00", "2024-01-01 09:00:00");
+        let blank = NewAnnotation {
+            text: " ".to_string(),
+            ..new_annotation(None, "2024-01-01 09:00:00", "2024-01-01 09:00:00")
+        };
+        let unknown = new_annotation(Some(42), "2024-01-01 09:00:00", "2024-01-01 09:00:00");
+        for invalid in [reversed, blank] {
+            let error = add_annotation(&pool, &invalid).await.unwrap_err();
+            assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+        }
+        let error = add_annotation(&pool, &unknown).await.unwrap_err();
+        assert!(
+            matches!(error, Error::ConstraintViolation(_)),
+            "{:?}",
+            error
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_list_overlapping(pool: SqlitePool) -> sqlx::Result<()> {
+        let first = register_sensor(&pool, "picow-1").await.unwrap();
+        let second = register_sensor(&pool, "picow-2").await.unwrap();
+        let annotations = [
+            // starts before the range
+            new_annotation(Some(first), "2024-01-01 08:00:00", "2024-01-01 09:30:00"),
+            // an instant in the range, for all sensors
+            new_annotation(None, "2024-01-01 10:00:00", "2024-01-01 10:00:00"),
+            new_annotation(Some(second), "2024-01-01 10:30:00", "2024-01-01 12:00:00"),
+            // ends before, starts at the end of the range
+            new_annotation(None, "2024-01-01 07:00:00", "2024-01-01 08:59:59"),
+            new_annotation(None, "2024-01-01 11:00:00", "2024-01-01 11:30:00"),
+        ];
+        for annotation in &annotations {
+            add_annotation(&pool, annotation).await.unwrap();
+        }
+
+        let from = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        let to = to_utc_datetime("2024-01-01 11:00:00").unwrap();
+        let listed = list_annotations(&pool, &[], &from, &to).await.unwrap();
+        let ids: Vec<_> = listed.iter().map(|annotation| annotation.id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        let listed = list_annotations(&pool, &[first], &from, &to).await.unwrap();
+        let ids: Vec<_> = listed.iter().map(|annotation| annotation.id).collect();
+        assert_eq!(ids, [1, 2]);
+
+        // open ranges
+        let listed = list_annotations(
+            &pool,
+            &[],
+            &get_date_with_default(&None),
+            &get_end_date_with_default(&None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(listed.len(), annotations.len());
+
+        Ok(())
+    }
+}
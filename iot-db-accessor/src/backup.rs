@@ -0,0 +1,302 @@
+//! Online backup and restore, the bridge and the webserver keep running meanwhile.
+//!
+//! A backup is a consistent snapshot of the database in a new SQLite file. A restore replaces the
+//! content of all tables with the content of a backup in one transaction, other connections see
+//! either the old or the restored content.
+
+use std::path::{Path, PathBuf};
+
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{Connection, SqliteConnection, SqlitePool};
+
+use crate::schema::{latest_schema_version, schema_version, MIGRATOR};
+use crate::{Error, Result};
+
+/// Writes a snapshot of the database to the new file `path`, writers wait at most for the time
+/// the snapshot is read
+pub async fn backup_database(pool: &SqlitePool, path: &Path) -> Result<()> {
+    if path.exists() {
+        return Err(Error::invalid_input(format!(
+            "backup file {} already exists",
+            path.display()
+        )));
+    }
+    sqlx::query("VACUUM INTO $1")
+        .bind(path_str(path)?)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Replaces the content of the database with the backup at `path` and returns the schema version
+/// of the backup.
+///
+/// Backups of older schema versions are migrated first, the backup file itself is not changed.
+/// Backups of newer schema versions are refused, as their content may not fit the schema.
+pub async fn restore_database(pool: &SqlitePool, path: &Path) -> Result<i64> {
+    let latest = latest_schema_version();
+    if schema_version(pool).await? != Some(latest) {
+        return Err(Error::MigrationMismatch(
+            "the database has to be migrated before a backup is restored".to_string(),
+        ));
+    }
+
+    // the backup is migrated in a copy, so it has the same tables and columns as the database
+    let copy = RestoreCopy::new(path)?;
+    let backup_version = {
+        let options = SqliteConnectOptions::new().filename(&copy.path);
+        let backup = SqlitePool::connect_with(options).await?;
+        let version = schema_version(&backup)
+            .await?
+            .ok_or_else(|| Error::invalid_input(format!("{} is not a backup", path.display())))?;
+        if version > latest {
+            return Err(Error::MigrationMismatch(format!(
+                "backup schema version {} is newer than the supported version {}, please update the binary",
+                version, latest
+            )));
+        }
+        MIGRATOR.run(&backup).await?;
+        backup.close().await;
+        version
+    };
+
+    // ATTACH is not allowed in transactions and would stay on a pooled connection
+    let mut conn = SqliteConnection::connect_with(&pool.connect_options()).await?;
+    sqlx::query("ATTACH DATABASE $1 AS backup")
+        .bind(path_str(&copy.path)?)
+        .execute(&mut conn)
+        .await?;
+    let restored = copy_tables(&mut conn).await;
+    conn.close().await?;
+    restored?;
+    Ok(backup_version)
+}
+
+async fn copy_tables(conn: &mut SqliteConnection) -> Result<()> {
+    let tables: Vec<String> = sqlx::query_scalar(
+        r#"
+    SELECT name FROM main.sqlite_master
+    WHERE type = 'table' AND name NOT LIKE 'sqlite_%' AND name != '_sqlx_migrations'
+    ORDER BY name
+    "#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut tx = conn.begin().await?;
+    // the references are checked when all tables are copied
+    sqlx::query("PRAGMA defer_foreign_keys = ON")
+        .execute(&mut *tx)
+        .await?;
+    for table in tables {
+        let columns: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM pragma_table_info($1) ORDER BY cid")
+                .bind(&table)
+                .fetch_all(&mut *tx)
+                .await?;
+        let columns = columns
+            .iter()
+            .map(|column| quote(column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let table = quote(&table);
+        sqlx::query(&format!("DELETE FROM main.{}", table))
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(&format!(
+            "INSERT INTO main.{table} ({columns}) SELECT {columns} FROM backup.{table}"
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| Error::invalid_input(format!("invalid path {}", path.display())))
+}
+
+/// Copy of a backup in the temp directory, removed when dropped
+struct RestoreCopy {
+    path: PathBuf,
+}
+
+impl RestoreCopy {
+    fn new(backup: &Path) -> Result<Self> {
+        if !backup.is_file() {
+            return Err(Error::NotFound(format!("backup {}", backup.display())));
+        }
+        let path = std::env::temp_dir().join(format!(
+            "iot-restore-{}-{}.sqlite",
+            std::process::id(),
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        std::fs::copy(backup, &path)?;
+        Ok(RestoreCopy { path })
+    }
+}
+
+impl Drop for RestoreCopy {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use sqlx::migrate::Migrator;
+    use sqlx::sqlite::SqliteConnectOptions;
+    use sqlx::SqlitePool;
+
+    use super::{backup_database, restore_database};
+    use crate::schema::{latest_schema_version, MIGRATOR};
+    use crate::{
+        add_annotation, add_sensor_data, list_annotations, list_sensordata, register_sensor,
+        to_utc_datetime, Error, MeasurementKind, NewAnnotation, QualityFilter,
+    };
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "iot-backup-test-{}-{}-{}.sqlite",
+            name,
+            std::process::id(),
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    async fn add_value(pool: &SqlitePool, sensor: i64, timestamp: &str, value: f64) {
+        let timestamp = to_utc_datetime(timestamp).unwrap();
+        let kind = MeasurementKind::Temperature;
+        add_sensor_data(pool, sensor, timestamp, kind, "°C", value)
+            .await
+            .unwrap();
+    }
+
+    async fn values(pool: &SqlitePool) -> Vec<f64> {
+        list_sensordata(pool, &[], QualityFilter::All)
+            .await
+            .unwrap()
+            .iter()
+            .map(|value| value.value)
+            .collect()
+    }
+
+    #[sqlx::test]
+    async fn test_backup_and_restore(pool: SqlitePool) -> sqlx::Result<()> {
+        let sensor = register_sensor(&pool, "picow-1").await.unwrap();
+        add_value(&pool, sensor, "2024-01-01 09:00:00", 10.).await;
+        let annotation = NewAnnotation {
+            sensor_id: Some(sensor),
+            starts_at: to_utc_datetime("2024-01-01 09:00:00").unwrap(),
+            ends_at: to_utc_datetime("2024-01-01 09:00:00").unwrap(),
+            text: "mounted".to_string(),
+            tags: vec![],
+        };
+        add_annotation(&pool, &annotation).await.unwrap();
+
+        let path = temp_path("restore");
+        backup_database(&pool, &path).await.unwrap();
+        let error = backup_database(&pool, &path).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+
+        // changes after the backup are undone by the restore
+        add_value(&pool, sensor, "2024-01-01 09:01:00", 11.).await;
+        let other = register_sensor(&pool, "picow-2").await.unwrap();
+        add_value(&pool, other, "2024-01-01 09:02:00", 12.).await;
+        assert_eq!(values(&pool).await, [10., 11., 12.]);
+
+        let version = restore_database(&pool, &path).await.unwrap();
+        assert_eq!(version, latest_schema_version());
+        assert_eq!(values(&pool).await, [10.]);
+        let annotations = list_annotations(
+            &pool,
+            &[],
+            &to_utc_datetime("2024-01-01 00:00:00").unwrap(),
+            &to_utc_datetime("2024-01-02 00:00:00").unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(annotations.len(), 1);
+        // the database can be written to as before
+        add_value(&pool, sensor, "2024-01-01 09:03:00", 13.).await;
+
+        std::fs::remove_file(&path).unwrap();
+        let error = restore_database(&pool, &path).await.unwrap_err();
+        assert!(matches!(error, Error::NotFound(_)), "{:?}", error);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_restore_schema_versions(pool: SqlitePool) -> sqlx::Result<()> {
+        // a backup before the sensors table was added, its values belong to the default sensor
+        let old = temp_path("old");
+        let options = SqliteConnectOptions::new()
+            .filename(&old)
+            .create_if_missing(true);
+        let old_pool = SqlitePool::connect_with(options).await?;
+        let migrator = Migrator {
+            migrations: MIGRATOR
+                .iter()
+                .filter(|migration| migration.version < 2)
+                .cloned()
+                .collect(),
+            ignore_missing: false,
+            locking: true,
+        };
+        migrator.run(&old_pool).await.unwrap();
+        sqlx::query(
+            "INSERT INTO sensor_values (timestamp, value) VALUES ('2024-01-01 09:00:00', 10)",
+        )
+        .execute(&old_pool)
+        .await?;
+        old_pool.close().await;
+        let before = std::fs::read(&old).unwrap();
+
+        assert_eq!(restore_database(&pool, &old).await.unwrap(), 1);
+        assert_eq!(values(&pool).await, [10.]);
+        // the backup itself is not migrated
+        assert_eq!(std::fs::read(&old).unwrap(), before);
+
+        // a backup of a newer binary
+        let newer = temp_path("newer");
+        backup_database(&pool, &newer).await.unwrap();
+        let newer_pool =
+            SqlitePool::connect_with(SqliteConnectOptions::new().filename(&newer)).await?;
+        sqlx::query(
+            r#"
+    INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+    VALUES (9999, 'future', TRUE, x'00', 0)
+            "#,
+        )
+        .execute(&newer_pool)
+        .await?;
+        newer_pool.close().await;
+        let error = restore_database(&pool, &newer).await.unwrap_err();
+        assert!(matches!(error, Error::MigrationMismatch(_)), "{:?}", error);
+
+        // a database without migrations is no backup
+        let empty = temp_path("empty");
+        let options = SqliteConnectOptions::new()
+            .filename(&empty)
+            .create_if_missing(true);
+        SqlitePool::connect_with(options).await?.close().await;
+        let error = restore_database(&pool, &empty).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+
+        for path in [old, newer, empty] {
+            std::fs::remove_file(path).unwrap();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,373 @@
+//! Calibrations correct sensors that read too high or too low, e.g. the internal temperature
+//! sensor of the RP2040, without reflashing them.
+//!
+//! The stored values stay raw, the calibration valid at the timestamp of a value is applied when it
+//! is read, so adding a calibration with an earlier `valid_from` re-calibrates historical values.
+//! Plausibility checks are done on the raw values when they are stored.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{sensor_filter, Error, MeasurementKind, Result};
+
+/// Values of the sensor, kind and unit measured since `valid_from` are corrected to
+/// `value * gain + offset`, until the next calibration becomes valid
+#[derive(Debug, Clone, PartialEq, Serialize, sqlx::FromRow)]
+pub struct Calibration {
+    pub id: i64,
+    pub sensor_id: i64,
+    pub kind: MeasurementKind,
+    /// only values in this unit are corrected
+    pub unit: String,
+    pub valid_from: DateTime<Utc>,
+    /// in `unit`
+    pub offset: f64,
+    pub gain: f64,
+}
+
+impl Calibration {
+    pub fn apply(&self, value: f64) -> f64 {
+        value * self.gain + self.offset
+    }
+}
+
+/// A calibration that is not yet stored
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewCalibration {
+    pub sensor_id: i64,
+    pub kind: MeasurementKind,
+    pub unit: String,
+    pub valid_from: DateTime<Utc>,
+    pub offset: f64,
+    pub gain: f64,
+}
+
+impl NewCalibration {
+    /// Checks that the unit belongs to the kind, the offset is a real number and the gain a
+    /// positive one
+    pub fn validate(&self) -> Result<()> {
+        self.kind.validate_unit(&self.unit)?;
+        if !self.offset.is_finite() {
+            return Err(Error::invalid_input(format!(
+                "invalid calibration offset: {}",
+                self.offset
+            )));
+        }
+        if !self.gain.is_finite() || self.gain <= 0. {
+            return Err(Error::invalid_input(format!(
+                "calibration gain must be positive, not {}",
+                self.gain
+            )));
+        }
+        Ok(())
+    }
+
+    /// The calibration stored with `id`
+    pub fn into_calibration(self, id: i64) -> Calibration {
+        Calibration {
+            id,
+            sensor_id: self.sensor_id,
+            kind: self.kind,
+            unit: self.unit,
+            valid_from: self.valid_from,
+            offset: self.offset,
+            gain: self.gain,
+        }
+    }
+}
+
+/// The calibration of the sensor, kind and unit valid at `timestamp`, the calibrations have to be
+/// sorted ascending by `valid_from`
+pub(crate) fn calibration_at<'a>(
+    calibrations: &'a [Calibration],
+    sensor_id: i64,
+    kind: MeasurementKind,
+    unit: &str,
+    timestamp: &DateTime<Utc>,
+) -> Option<&'a Calibration> {
+    calibrations.iter().rev().find(|calibration| {
+        calibration.sensor_id == sensor_id
+            && calibration.kind == kind
+            && calibration.unit == unit
+            && calibration.valid_from <= *timestamp
+    })
+}
+
+/// Stores the calibration, a calibration of the same sensor, kind and unit valid from the same time is
+/// a constraint violation
+pub async fn add_calibration(pool: &SqlitePool, calibration: &NewCalibration) -> Result<i64> {
+    calibration.validate()?;
+    // the conflict must not fail the statement: sqlx steps a failed statement again until the
+    // caller is gone, which would store the calibration after all once the other one was deleted.
+    // All rows are read, the insert is only committed once the statement is done.
+    let ids = sqlx::query_scalar!(
+        r#"
+    INSERT INTO calibrations (sensor_id, kind, unit, valid_from, offset, gain)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    ON CONFLICT (sensor_id, kind, unit, valid_from) DO NOTHING
+    RETURNING id
+        "#,
+        calibration.sensor_id,
+        calibration.kind,
+        calibration.unit,
+        calibration.valid_from,
+        calibration.offset,
+        calibration.gain
+    )
+    .fetch_all(pool)
+    .await?;
+    ids.first().copied().ok_or_else(|| {
+        Error::ConstraintViolation(format!(
+            "sensor {} already has a {} calibration in {} valid from {}",
+            calibration.sensor_id, calibration.kind, calibration.unit, calibration.valid_from
+        ))
+    })
+}
+
+pub async fn delete_calibration(pool: &SqlitePool, id: i64) -> Result<()> {
+    let deleted = sqlx::query!("DELETE FROM calibrations WHERE id = $1", id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(Error::NotFound(format!("calibration {}", id)));
+    }
+    Ok(())
+}
+
+/// Lists the calibrations of the given sensors ascending by `valid_from`, an empty slice lists the
+/// calibrations of all sensors
+pub async fn list_calibrations(pool: &SqlitePool, sensors: &[i64]) -> Result<Vec<Calibration>> {
+    let sensors = sensor_filter(sensors)?;
+    let recs = sqlx::query_as_unchecked!(
+        Calibration,
+        r#"
+    SELECT id, sensor_id, kind, unit, valid_from, offset, gain
+    FROM calibrations
+    WHERE ($1 = '[]' OR sensor_id IN (SELECT value FROM json_each($1)))
+    ORDER BY valid_from, id
+    "#,
+        sensors
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(recs)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use sqlx::SqlitePool;
+
+    use super::{add_calibration, delete_calibration, list_calibrations, NewCalibration};
+    use crate::{
+        add_sensor_data, aggregate_sensordata, apply_retention, list_sensordata, register_sensor,
+        to_utc_datetime, Aggregate, BucketWidth, Error, MeasurementKind, QualityFilter,
+        RetentionPolicy,
+    };
+
+    fn new_calibration(sensor_id: i64, valid_from: &str, offset: f64) -> NewCalibration {
+        NewCalibration {
+            sensor_id,
+            kind: MeasurementKind::Temperature,
+            unit: "°C".to_string(),
+            valid_from: to_utc_datetime(valid_from).unwrap(),
+            offset,
+            gain: 1.,
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_calibrate(pool: SqlitePool) -> sqlx::Result<()> {
+        let sensor = register_sensor(&pool, "picow-1").await.unwrap();
+        let other = register_sensor(&pool, "picow-2").await.unwrap();
+        for (sensor, timestamp, kind, unit, value) in [
+            (
+                sensor,
+                "2024-01-01 09:00:00",
+                MeasurementKind::Temperature,
+                "°C",
+                24.,
+            ),
+            (
+                sensor,
+                "2024-01-01 10:00:00",
+                MeasurementKind::Temperature,
+                "°C",
+                25.,
+            ),
+            (
+                sensor,
+                "2024-01-01 10:00:00",
+                MeasurementKind::Humidity,
+                "%",
+                40.,
+            ),
+            (
+                other,
+                "2024-01-01 10:00:00",
+                MeasurementKind::Temperature,
+                "°C",
+                21.,
+            ),
+        ] {
+            let timestamp = to_utc_datetime(timestamp).unwrap();
+            add_sensor_data(&pool, sensor, timestamp, kind, unit, value)
+                .await
+                .unwrap();
+        }
+        let values = || async {
+            list_sensordata(&pool, &[], QualityFilter::All)
+                .await
+                .unwrap()
+                .iter()
+                .map(|value| value.value)
+                .collect::<Vec<_>>()
+        };
+
+        // the sensor reads 4 °C too high since it was mounted, and was recalibrated at 10:00
+        let first = add_calibration(&pool, &new_calibration(sensor, "2024-01-01 00:00:00", -4.))
+            .await
+            .unwrap();
+        let second = NewCalibration {
+            gain: 1.5,
+            ..new_calibration(sensor, "2024-01-01 10:00:00", -17.)
+        };
+        add_calibration(&pool, &second).await.unwrap();
+        assert_eq!(values().await, [20., 20.5, 40., 21.]);
+        assert_eq!(list_calibrations(&pool, &[other]).await.unwrap(), []);
+        let calibrations = list_calibrations(&pool, &[sensor]).await.unwrap();
+        assert_eq!(calibrations.len(), 2);
+        assert_eq!(calibrations[1].apply(25.), 20.5);
+
+        // the raw values are kept
+        delete_calibration(&pool, first).await.unwrap();
+        assert_eq!(values().await, [24., 20.5, 40., 21.]);
+
+        let error = delete_calibration(&pool, first).await.unwrap_err();
+        assert!(matches!(error, Error::NotFound(_)), "{:?}", error);
+        let error = add_calibration(&pool, &second).await.unwrap_err();
+        assert!(
+            matches!(error, Error::ConstraintViolation(_)),
+            "{:?}",
+            error
+        );
+        let invalid = NewCalibration { gain: 0., ..second };
+        let error = add_calibration(&pool, &invalid).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_calibrate_units(pool: SqlitePool) -> sqlx::Result<()> {
+        let sensor = register_sensor(&pool, "picow-1").await.unwrap();
+        let timestamp = to_utc_datetime("2024-01-01 10:00:00").unwrap();
+        for (unit, value) in [("°C", 25.), ("°F", 77.)] {
+            add_sensor_data(
+                &pool,
+                sensor,
+                timestamp,
+                MeasurementKind::Temperature,
+                unit,
+                value,
+            )
+            .await
+            .unwrap();
+        }
+        let values = || async {
+            list_sensordata(&pool, &[], QualityFilter::All)
+                .await
+                .unwrap()
+                .iter()
+                .map(|value| (value.unit.clone(), value.value))
+                .collect::<Vec<_>>()
+        };
+
+        // the offset in °C is not applied to values in °F
+        let celsius = new_calibration(sensor, "2024-01-01 00:00:00", -4.);
+        add_calibration(&pool, &celsius).await.unwrap();
+        assert_eq!(
+            values().await,
+            [("°C".to_string(), 21.), ("°F".to_string(), 77.)]
+        );
+        let fahrenheit = NewCalibration {
+            unit: "°F".to_string(),
+            offset: -7.,
+            ..celsius.clone()
+        };
+        add_calibration(&pool, &fahrenheit).await.unwrap();
+        assert_eq!(
+            values().await,
+            [("°C".to_string(), 21.), ("°F".to_string(), 70.)]
+        );
+
+        let invalid = NewCalibration {
+            unit: "hPa".to_string(),
+            ..celsius
+        };
+        let error = add_calibration(&pool, &invalid).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_calibrate_rollups(pool: SqlitePool) -> sqlx::Result<()> {
+        let sensor = register_sensor(&pool, "picow-1").await.unwrap();
+        for (timestamp, value) in [("2024-01-01 09:00:00", 20.), ("2024-01-01 09:00:30", 22.)] {
+            let timestamp = to_utc_datetime(timestamp).unwrap();
+            add_sensor_data(
+                &pool,
+                sensor,
+                timestamp,
+                MeasurementKind::Temperature,
+                "°C",
+                value,
+            )
+            .await
+            .unwrap();
+        }
+        // the raw values are replaced by their minute rollup
+        let policy = RetentionPolicy {
+            raw: Duration::hours(1),
+            ..Default::default()
+        };
+        apply_retention(
+            &pool,
+            &policy,
+            to_utc_datetime("2024-01-01 12:00:00").unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let calibration = NewCalibration {
+            gain: 2.,
+            ..new_calibration(sensor, "2024-01-01 00:00:00", -20.)
+        };
+        add_calibration(&pool, &calibration).await.unwrap();
+        let buckets = aggregate_sensordata(
+            &pool,
+            &[],
+            MeasurementKind::Temperature,
+            &to_utc_datetime("2024-01-01 09:00:00").unwrap(),
+            &to_utc_datetime("2024-01-01 10:00:00").unwrap(),
+            BucketWidth::from_seconds(60).unwrap(),
+            &Aggregate::ALL,
+        )
+        .await
+        .unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].min, Some(20.));
+        assert_eq!(buckets[0].max, Some(24.));
+        assert_eq!(buckets[0].avg, Some(22.));
+        assert_eq!(buckets[0].count, Some(2));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use sqlx::error::ErrorKind;
+use sqlx::migrate::MigrateError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors of the database access, classified so callers can react to each kind differently
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("constraint violation: {0}")]
+    ConstraintViolation(String),
+    /// the database is locked by another connection or process, retrying may succeed
+    #[error("database is busy: {0}")]
+    Busy(String),
+    /// the database schema does not match the migrations of this binary
+    #[error("migration mismatch: {0}")]
+    MigrationMismatch(String),
+    #[error("i/o error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("database error: {0}")]
+    Database(sqlx::Error),
+}
+
+impl Error {
+    pub(crate) fn invalid_input(message: impl Into<String>) -> Self {
+        Error::InvalidInput(message.into())
+    }
+
+    /// Process exit code following the BSD `sysexits.h` conventions
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::NotFound(_) => 66,            // EX_NOINPUT
+            Error::InvalidInput(_) => 65,        // EX_DATAERR
+            Error::ConstraintViolation(_) => 65, // EX_DATAERR
+            Error::Busy(_) => 75,                // EX_TEMPFAIL
+            Error::MigrationMismatch(_) => 78,   // EX_CONFIG
+            Error::Io(_) => 74,                  // EX_IOERR
+            Error::Database(_) => 70,            // EX_SOFTWARE
+        }
+    }
+}
+
+// SQLITE_BUSY and SQLITE_LOCKED, extended result codes keep them in the lowest byte
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+impl From<sqlx::Error> for Error {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::RowNotFound => Error::NotFound("no matching row".to_string()),
+            sqlx::Error::Configuration(e) => Error::InvalidInput(e.to_string()),
+            sqlx::Error::Io(e) => Error::Io(e),
+            sqlx::Error::PoolTimedOut => Error::Busy(error.to_string()),
+            sqlx::Error::Database(ref e) => {
+                let code = e.code().and_then(|code| code.parse::<i32>().ok());
+                if matches!(
+                    code.map(|code| code & 0xff),
+                    Some(SQLITE_BUSY | SQLITE_LOCKED)
+                ) {
+                    return Error::Busy(e.message().to_string());
+                }
+                match e.kind() {
+                    ErrorKind::UniqueViolation
+                    | ErrorKind::ForeignKeyViolation
+                    | ErrorKind::NotNullViolation
+                    | ErrorKind::CheckViolation => {
+                        Error::ConstraintViolation(e.message().to_string())
+                    }
+                    _ => Error::Database(error),
+                }
+            }
+            _ => Error::Database(error),
+        }
+    }
+}
+
+impl From<MigrateError> for Error {
+    fn from(error: MigrateError) -> Self {
+        match error {
+            MigrateError::Execute(e) => e.into(),
+            _ => Error::MigrationMismatch(error.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::InvalidInput(error.to_string())
+    }
+}
+
+impl From<chrono::RoundingError> for Error {
+    fn from(error: chrono::RoundingError) -> Self {
+        Error::InvalidInput(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::SqlitePool;
+
+    use super::Error;
+    use crate::{add_sensor_data, to_utc_datetime, MeasurementKind};
+
+    #[test]
+    fn test_invalid_timestamp() {
+        assert!(to_utc_datetime("2024-01-01 09:00:00").is_ok());
+        let error = to_utc_datetime("yesterday").unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+        assert_eq!(error.exit_code(), 65);
+    }
+
+    #[sqlx::test]
+    async fn test_constraint_violation(pool: SqlitePool) -> sqlx::Result<()> {
+        let timestamp = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        let kind = MeasurementKind::Temperature;
+        let error = add_sensor_data(&pool, 42, timestamp, kind, "°C", 1.)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, Error::ConstraintViolation(_)),
+            "{:?}",
+            error
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_busy(pool: SqlitePool) -> sqlx::Result<()> {
+        // a second connection without busy timeout cannot write while the first one holds the lock
+        let mut writer = pool.acquire().await?;
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *writer).await?;
+        let mut other = pool.acquire().await?;
+        sqlx::query("PRAGMA busy_timeout = 0")
+            .execute(&mut *other)
+            .await?;
+        let error: Error = sqlx::query("BEGIN IMMEDIATE")
+            .execute(&mut *other)
+            .await
+            .unwrap_err()
+            .into();
+        assert!(matches!(error, Error::Busy(_)), "{:?}", error);
+        sqlx::query("ROLLBACK").execute(&mut *writer).await?;
+
+        Ok(())
+    }
+}
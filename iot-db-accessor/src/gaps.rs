@@ -0,0 +1,232 @@
+//! Gaps are periods in which a sensor should have sent values but did not, e.g. because the PicoW
+//! dropped off the Wi-Fi.
+//!
+//! A value is due `interval` after the previous value of the same sensor, a gap lasts from then
+//! until the next value arrives. Values of all kinds and qualities count, a rejected value still
+//! shows that the sensor was online.
+
+use chrono::{DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{sensor_filter, Error, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Gap {
+    pub sensor_id: i64,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+}
+
+impl Gap {
+    pub fn duration(&self) -> Duration {
+        self.ends_at - self.starts_at
+    }
+}
+
+/// Finds the gaps in values that are pushed ascending by sensor and timestamp
+pub(crate) struct GapDetector {
+    sensors: Vec<i64>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    interval: Duration,
+    threshold: Duration,
+    // sensor and timestamp of the last pushed value
+    last: Option<(i64, DateTime<Utc>)>,
+    seen: Vec<i64>,
+    gaps: Vec<Gap>,
+}
+
+impl GapDetector {
+    /// An empty `sensors` slice checks the sensors that have values in `[from, to)`
+    pub(crate) fn new(
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        interval: Duration,
+        threshold: Duration,
+    ) -> Result<Self> {
+        if interval <= Duration::zero() {
+            return Err(Error::invalid_input("interval must be positive"));
+        }
+        if threshold < Duration::zero() {
+            return Err(Error::invalid_input("threshold must not be negative"));
+        }
+        Ok(GapDetector {
+            sensors: sensors.to_vec(),
+            from: *from,
+            to: *to,
+            interval,
+            threshold,
+            last: None,
+            seen: Vec::new(),
+            gaps: Vec::new(),
+        })
+    }
+
+    pub(crate) fn push(&mut self, sensor_id: i64, timestamp: DateTime<Utc>) {
+        let previous = match self.last {
+            Some((last_sensor, last_timestamp)) if last_sensor == sensor_id => last_timestamp,
+            _ => {
+                self.finish_sensor();
+                self.from
+            }
+        };
+        self.check(sensor_id, previous, timestamp);
+        self.last = Some((sensor_id, timestamp));
+    }
+
+    /// The gaps ascending by start
+    pub(crate) fn finish(mut self) -> Vec<Gap> {
+        self.finish_sensor();
+        // requested sensors without any value were missing the whole range
+        for sensor_id in std::mem::take(&mut self.sensors) {
+            if !self.seen.contains(&sensor_id) {
+                self.check(sensor_id, self.from, self.to);
+            }
+        }
+        self.gaps.sort_by_key(|gap| (gap.starts_at, gap.sensor_id));
+        self.gaps
+    }
+
+    // the end of the range counts as the next value of the last sensor
+    fn finish_sensor(&mut self) {
+        if let Some((sensor_id, timestamp)) = self.last.take() {
+            self.check(sensor_id, timestamp, self.to);
+            self.seen.push(sensor_id);
+        }
+    }
+
+    fn check(&mut self, sensor_id: i64, previous: DateTime<Utc>, next: DateTime<Utc>) {
+        let gap = Gap {
+            sensor_id,
+            starts_at: previous + self.interval,
+            ends_at: next,
+        };
+        if gap.duration() > self.threshold {
+            self.gaps.push(gap);
+        }
+    }
+}
+
+/// Lists the gaps longer than `threshold` in `[from, to)` ascending by start, where a sensor sent
+/// no value although one was due every `interval`.
+///
+/// The start and the end of the range count as values, so a sensor that stopped sending has a gap
+/// up to `to`. An empty `sensors` slice checks the sensors that have values in the range, a
+/// requested sensor without values has one gap over the range.
+pub async fn list_gaps(
+    pool: &SqlitePool,
+    sensors: &[i64],
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    interval: Duration,
+    threshold: Duration,
+) -> Result<Vec<Gap>> {
+    let mut detector = GapDetector::new(sensors, from, to, interval, threshold)?;
+    let sensors = sensor_filter(sensors)?;
+    let mut values = sqlx::query!(
+        r#"
+    SELECT sensor_id, timestamp AS "timestamp!: DateTime<Utc>"
+    FROM sensor_values
+    WHERE timestamp >= $1 AND timestamp < $2
+      AND ($3 = '[]' OR sensor_id IN (SELECT value FROM json_each($3)))
+    ORDER BY sensor_id, timestamp
+    "#,
+        from,
+        to,
+        sensors
+    )
+    .fetch(pool);
+    while let Some(value) = values.try_next().await? {
+        detector.push(value.sensor_id, value.timestamp);
+    }
+    Ok(detector.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use sqlx::SqlitePool;
+
+    use super::{list_gaps, Gap};
+    use crate::{add_sensor_data_batch, register_sensor, to_utc_datetime, Error, NewSensorData};
+
+    fn gap(sensor_id: i64, starts_at: &str, ends_at: &str) -> Gap {
+        Gap {
+            sensor_id,
+            starts_at: to_utc_datetime(starts_at).unwrap(),
+            ends_at: to_utc_datetime(ends_at).unwrap(),
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_list_gaps(pool: SqlitePool) -> sqlx::Result<()> {
+        let first = register_sensor(&pool, "picow-1").await.unwrap();
+        let silent = register_sensor(&pool, "picow-2").await.unwrap();
+        // a value per minute, offline from 09:06 to 09:20 and after 09:22
+        let start = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        let values: Vec<_> = (0..6)
+            .chain(20..23)
+            .map(|minute| NewSensorData::temperature(first, start + Duration::minutes(minute), 20.))
+            .collect();
+        add_sensor_data_batch(&pool, &values).await.unwrap();
+
+        let to = to_utc_datetime("2024-01-01 09:30:00").unwrap();
+        let gaps = list_gaps(
+            &pool,
+            &[],
+            &start,
+            &to,
+            Duration::minutes(1),
+            Duration::minutes(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            gaps,
+            [
+                gap(first, "2024-01-01 09:06:00", "2024-01-01 09:20:00"),
+                gap(first, "2024-01-01 09:23:00", "2024-01-01 09:30:00"),
+            ]
+        );
+        assert_eq!(gaps[0].duration(), Duration::minutes(14));
+
+        // a requested sensor without values was missing all the time
+        let gaps = list_gaps(
+            &pool,
+            &[silent],
+            &start,
+            &to,
+            Duration::minutes(1),
+            Duration::minutes(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            gaps,
+            [gap(silent, "2024-01-01 09:01:00", "2024-01-01 09:30:00")]
+        );
+
+        // only the long outage exceeds a larger threshold
+        let gaps = list_gaps(
+            &pool,
+            &[first],
+            &start,
+            &to,
+            Duration::minutes(1),
+            Duration::minutes(10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(gaps.len(), 1);
+
+        let error = list_gaps(&pool, &[], &start, &to, Duration::zero(), Duration::zero())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+
+        Ok(())
+    }
+}
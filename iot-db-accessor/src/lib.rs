@@ -1,45 +1,160 @@
-use anyhow::Result;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Utc};
 use serde::Serialize;
-use sqlx::SqlitePool;
+use sqlx::{SqliteConnection, SqlitePool};
+
+mod aggregation;
+mod annotation;
+mod backup;
+mod calibration;
+mod error;
+mod gaps;
+mod measurement;
+mod notify;
+mod pagination;
+mod pool;
+mod quality;
+mod query;
+mod retention;
+mod schema;
+mod store;
+mod stream;
+mod time;
+
+pub use aggregation::{
+    aggregate_sensordata, parse_duration, Aggregate, AggregatedBucket, BucketWidth,
+};
+pub use annotation::{
+    add_annotation, delete_annotation, get_annotation, list_annotations, update_annotation,
+    AnnotatedSensorData, Annotation, NewAnnotation,
+};
+pub use backup::{backup_database, restore_database};
+pub use calibration::{
+    add_calibration, delete_calibration, list_calibrations, Calibration, NewCalibration,
+};
+pub use error::{Error, Result};
+pub use gaps::{list_gaps, Gap};
+pub use measurement::MeasurementKind;
+pub use notify::SensorDataFeed;
+pub use pagination::{list_sensordata_page, Cursor, Page};
+pub use pool::DatabaseConfig;
+pub use quality::{
+    list_plausibility_rules, set_plausibility_rule, Assessment, PlausibilityRule, Quality,
+    QualityFilter,
+};
+pub use query::{SensorQuery, SortOrder};
+pub use retention::{apply_retention, spawn_retention_job, RetentionPolicy, RetentionReport};
+pub use schema::{connect_and_migrate, latest_schema_version, migrate, schema_version};
+pub use store::{MemoryStore, SensorStore};
+pub use stream::{stream_sensordata, stream_sensordata_between};
+pub use time::{
+    display_timezone_from_env, format_timestamp, parse_timestamp, parse_timezone, to_utc_datetime,
+};
+
+pub use chrono_tz::Tz;
+
+/// Id of the sensor that owns all values recorded before sensors were introduced
+pub const DEFAULT_SENSOR_ID: i64 = 1;
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct Sensor {
+    pub id: i64,
+    pub name: String,
+}
 
 #[derive(Debug, Clone, sqlx::FromRow, Serialize)]
 pub struct SensorData {
     pub id: i64,
-    pub timestamp: chrono::NaiveDateTime,
+    pub sensor_id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub kind: MeasurementKind,
+    pub unit: String,
+    /// corrected by the calibration of the sensor, the stored value stays raw
     pub value: f64,
+    pub quality: Quality,
+    /// why the value is not good
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality_reason: Option<String>,
 }
 
-pub async fn add_sensor_data(
-    pool: &SqlitePool,
-    timestamp: NaiveDateTime,
-    value: f64,
-) -> Result<i64> {
+/// A sensor value that is not yet stored in the database.
+///
+/// A value with `message_id` or `device_timestamp` is identified by it per sensor (and kind for the
+/// device timestamp), sending it again does not store it twice.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewSensorData {
+    pub sensor_id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub kind: MeasurementKind,
+    pub unit: String,
+    pub value: f64,
+    /// id the client assigned to the value, e.g. a sequence number
+    pub message_id: Option<String>,
+    /// when the device measured the value by its own clock
+    pub device_timestamp: Option<DateTime<Utc>>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl NewSensorData {
+    /// A temperature in °C without identity
+    pub fn temperature(sensor_id: i64, timestamp: DateTime<Utc>, value: f64) -> Self {
+        NewSensorData {
+            sensor_id,
+            timestamp,
+            kind: MeasurementKind::Temperature,
+            unit: "°C".to_string(),
+            value,
+            message_id: None,
+            device_timestamp: None,
+        }
+    }
+}
+
+/// What happens when a value with the identity of a stored value is added
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnDuplicate {
+    /// the stored value is kept, e.g. for retries
+    #[default]
+    Ignore,
+    /// the stored value is overwritten, e.g. for corrections
+    Replace,
+}
+
+/// Returns the id of the sensor with the given name, the sensor is created if it does not exist
+pub async fn register_sensor(pool: &SqlitePool, name: &str) -> Result<i64> {
     let mut conn = pool.acquire().await?;
 
-    // Insert the task, then obtain the ID of this row
-    let id = sqlx::query!(
+    sqlx::query!(
         r#"
-    INSERT INTO sensor_values (timestamp, value)
-    VALUES ($1, $2)
+    INSERT INTO sensors (name)
+    VALUES ($1)
+    ON CONFLICT (name) DO NOTHING
         "#,
-        timestamp,
-        value
+        name
     )
     .execute(&mut *conn)
-    .await?
-    .last_insert_rowid();
+    .await?;
+
+    let id = sqlx::query_scalar!(
+        r#"
+    SELECT id
+    FROM sensors
+    WHERE name = $1
+        "#,
+        name
+    )
+    .fetch_one(&mut *conn)
+    .await?;
 
     Ok(id)
 }
 
-pub async fn list_sensordata(pool: &SqlitePool) -> Result<Vec<SensorData>> {
-    let recs = sqlx::query_as_unchecked!(
-        SensorData,
+pub async fn list_sensors(pool: &SqlitePool) -> Result<Vec<Sensor>> {
+    let recs = sqlx::query_as!(
+        Sensor,
         r#"
-    SELECT id, timestamp, value
-    FROM sensor_values
-    ORDER BY timestamp
+    SELECT id, name
+    FROM sensors
+    ORDER BY id
     "#
     )
     .fetch_all(pool)
@@ -47,36 +162,255 @@ pub async fn list_sensordata(pool: &SqlitePool) -> Result<Vec<SensorData>> {
     Ok(recs)
 }
 
+pub async fn add_sensor_data(
+    pool: &SqlitePool,
+    sensor_id: i64,
+    timestamp: DateTime<Utc>,
+    kind: MeasurementKind,
+    unit: &str,
+    value: f64,
+) -> Result<i64> {
+    let value = NewSensorData {
+        sensor_id,
+        timestamp,
+        kind,
+        unit: unit.to_string(),
+        value,
+        message_id: None,
+        device_timestamp: None,
+    };
+    add_sensor_data_idempotent(pool, &value, OnDuplicate::Ignore).await
+}
+
+/// Inserts the value and returns its id. If a value with the same identity is already stored,
+/// its id is returned and it is kept or replaced depending on `on_duplicate`.
+pub async fn add_sensor_data_idempotent(
+    pool: &SqlitePool,
+    value: &NewSensorData,
+    on_duplicate: OnDuplicate,
+) -> Result<i64> {
+    validate_sensor_value(value.kind, &value.unit, value.value)?;
+    // the statement of the insert is only completed when the transaction is committed
+    let mut tx = pool.begin().await?;
+    let id = insert_sensor_data(&mut tx, value, on_duplicate).await?;
+    tx.commit().await?;
+    notify::notify_inserted();
+    Ok(id)
+}
+
+/// Inserts all values in one transaction and returns their ids in the same order.
+/// If one value is invalid nothing is inserted, implausible values are inserted with their quality.
+/// Values with the identity of a stored value, or of an earlier value of the batch, are ignored
+/// and get the id of that value.
+pub async fn add_sensor_data_batch(
+    pool: &SqlitePool,
+    values: &[NewSensorData],
+) -> Result<Vec<i64>> {
+    for value in values {
+        validate_sensor_value(value.kind, &value.unit, value.value)?;
+    }
+    let mut tx = pool.begin().await?;
+
+    let mut ids = Vec::with_capacity(values.len());
+    for value in values {
+        ids.push(insert_sensor_data(&mut tx, value, OnDuplicate::Ignore).await?);
+    }
+
+    tx.commit().await?;
+    notify::notify_inserted();
+    Ok(ids)
+}
+
+async fn insert_sensor_data(
+    conn: &mut SqliteConnection,
+    value: &NewSensorData,
+    on_duplicate: OnDuplicate,
+) -> Result<i64> {
+    // earlier values of a batch count as previous values
+    let assessment = quality::assess_sensor_value(
+        &mut *conn,
+        value.sensor_id,
+        &value.timestamp,
+        value.kind,
+        &value.unit,
+        value.value,
+    )
+    .await?;
+
+    // without identity there is no conflict
+    let id = match on_duplicate {
+        OnDuplicate::Ignore => {
+            sqlx::query_scalar!(
+                r#"
+    INSERT INTO sensor_values
+        (sensor_id, timestamp, kind, unit, value, quality, quality_reason, message_id, device_timestamp)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    ON CONFLICT (sensor_id, message_id) WHERE message_id IS NOT NULL DO NOTHING
+    ON CONFLICT (sensor_id, kind, device_timestamp) WHERE device_timestamp IS NOT NULL DO NOTHING
+    RETURNING id
+            "#,
+                value.sensor_id,
+                value.timestamp,
+                value.kind,
+                value.unit,
+                value.value,
+                assessment.quality,
+                assessment.reason,
+                value.message_id,
+                value.device_timestamp
+            )
+            .fetch_optional(&mut *conn)
+            .await?
+        }
+        OnDuplicate::Replace => {
+            sqlx::query_scalar!(
+                r#"
+    INSERT INTO sensor_values
+        (sensor_id, timestamp, kind, unit, value, quality, quality_reason, message_id, device_timestamp)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    ON CONFLICT (sensor_id, message_id) WHERE message_id IS NOT NULL DO UPDATE
+    SET timestamp = excluded.timestamp, kind = excluded.kind, unit = excluded.unit,
+        value = excluded.value, quality = excluded.quality,
+        quality_reason = excluded.quality_reason, message_id = excluded.message_id,
+        device_timestamp = excluded.device_timestamp
+    ON CONFLICT (sensor_id, kind, device_timestamp) WHERE device_timestamp IS NOT NULL DO UPDATE
+    SET timestamp = excluded.timestamp, kind = excluded.kind, unit = excluded.unit,
+        value = excluded.value, quality = excluded.quality,
+        quality_reason = excluded.quality_reason, message_id = excluded.message_id,
+        device_timestamp = excluded.device_timestamp
+    RETURNING id
+            "#,
+                value.sensor_id,
+                value.timestamp,
+                value.kind,
+                value.unit,
+                value.value,
+                assessment.quality,
+                assessment.reason,
+                value.message_id,
+                value.device_timestamp
+            )
+            .fetch_optional(&mut *conn)
+            .await?
+        }
+    };
+    if let Some(id) = id {
+        return Ok(id);
+    }
+
+    // the value was ignored, the keys are checked in the order of the ON CONFLICT clauses
+    if let Some(message_id) = &value.message_id {
+        let id = sqlx::query_scalar!(
+            "SELECT id FROM sensor_values WHERE sensor_id = $1 AND message_id = $2",
+            value.sensor_id,
+            message_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some(id) = id {
+            return Ok(id);
+        }
+    }
+    if let Some(device_timestamp) = &value.device_timestamp {
+        let id = sqlx::query_scalar!(
+            r#"
+    SELECT id
+    FROM sensor_values
+    WHERE sensor_id = $1 AND kind = $2 AND device_timestamp = $3
+            "#,
+            value.sensor_id,
+            value.kind,
+            device_timestamp
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some(id) = id {
+            return Ok(id);
+        }
+    }
+    Err(Error::ConstraintViolation(format!(
+        "value of sensor {} at {} was ignored, but no stored value has its identity",
+        value.sensor_id, value.timestamp
+    )))
+}
+
+/// Lists the values of the given sensors ascending, an empty slice lists the values of all sensors.
+/// Loads everything at once, use [`list_sensordata_page`] or [`stream_sensordata`] for large tables.
+pub async fn list_sensordata(
+    pool: &SqlitePool,
+    sensors: &[i64],
+    quality: QualityFilter,
+) -> Result<Vec<SensorData>> {
+    SensorQuery::new()
+        .sensors(sensors)
+        .quality(quality)
+        .fetch_all(pool)
+        .await
+}
+
+/// Lists the values of the given sensors in `[from, to)` ascending, an empty slice lists the values of all sensors
+pub async fn list_sensordata_between(
+    pool: &SqlitePool,
+    sensors: &[i64],
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    quality: QualityFilter,
+) -> Result<Vec<SensorData>> {
+    SensorQuery::new()
+        .sensors(sensors)
+        .from(*from)
+        .to(*to)
+        .quality(quality)
+        .fetch_all(pool)
+        .await
+}
+
+/// Lists the latest `rows` values of the given sensors after `since` descending, an empty slice
+/// lists the values of all sensors
 pub async fn list_last_values_descending_since(
     pool: &SqlitePool,
-    since: &NaiveDateTime,
+    sensors: &[i64],
+    since: &DateTime<Utc>,
     rows: u32,
+    quality: QualityFilter,
 ) -> Result<Vec<SensorData>> {
-    let recs = sqlx::query_as_unchecked!(
-        SensorData,
-        r#"
-    SELECT id, timestamp, value
-    FROM sensor_values
-    WHERE timestamp > $2
-    ORDER BY timestamp DESC
-    LIMIT $1
-    "#,
-        rows,
-        since
-    )
-    .fetch_all(pool)
-    .await?;
-    Ok(recs)
+    SensorQuery::new()
+        .sensors(sensors)
+        .after(*since)
+        .order(SortOrder::Descending)
+        .limit(rows)
+        .quality(quality)
+        .fetch_all(pool)
+        .await
 }
 
-pub fn get_date_with_default(date: &Option<NaiveDateTime>) -> NaiveDateTime {
-    date.unwrap_or_else(|| to_naivedatetime("1970-01-01 00:00:00"))
+/// Checks that the unit belongs to the kind and that the value is a real number
+pub fn validate_sensor_value(kind: MeasurementKind, unit: &str, value: f64) -> Result<()> {
+    kind.validate_unit(unit)?;
+    if !value.is_finite() {
+        return Err(Error::invalid_input(format!(
+            "invalid {} value: {}",
+            kind, value
+        )));
+    }
+    Ok(())
 }
 
-pub fn to_naivedatetime(input: &str) -> NaiveDateTime {
-    let parse_from_str = NaiveDateTime::parse_from_str;
-    const DT_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
-    parse_from_str(input, DT_FORMAT).unwrap()
+// sqlite has no array parameters, the sensor ids are passed as json array
+pub(crate) fn sensor_filter(sensors: &[i64]) -> Result<String> {
+    Ok(serde_json::to_string(sensors)?)
+}
+
+pub fn get_date_with_default(date: &Option<DateTime<Utc>>) -> DateTime<Utc> {
+    date.unwrap_or(DateTime::UNIX_EPOCH)
+}
+
+/// The end of a range, without a date the range is open, up to the last timestamp that still
+/// compares correctly as text in the database
+pub fn get_end_date_with_default(date: &Option<DateTime<Utc>>) -> DateTime<Utc> {
+    date.unwrap_or_else(|| {
+        DateTime::from_timestamp(253_402_300_799, 0).expect("9999-12-31T23:59:59Z is valid")
+    })
 }
 
 #[cfg(test)]
@@ -84,23 +418,305 @@ pub fn to_naivedatetime(input: &str) -> NaiveDateTime {
 mod test {
     use sqlx::SqlitePool;
 
-    use crate::{add_sensor_data, list_sensordata, to_naivedatetime};
+    use crate::{
+        add_sensor_data, add_sensor_data_batch, add_sensor_data_idempotent,
+        list_last_values_descending_since, list_sensordata, list_sensors, register_sensor,
+        to_utc_datetime, MeasurementKind, NewSensorData, OnDuplicate, QualityFilter,
+        DEFAULT_SENSOR_ID,
+    };
 
     #[sqlx::test]
     async fn test_add_and_list(pool: SqlitePool) -> sqlx::Result<()> {
-        assert_eq!(list_sensordata(&pool).await.unwrap().len(), 0);
-        let id = add_sensor_data(&pool, to_naivedatetime("2024-01-01 09:00:00"), 10.00).await;
+        assert_eq!(
+            list_sensordata(&pool, &[], QualityFilter::All)
+                .await
+                .unwrap()
+                .len(),
+            0
+        );
+        let id = add_sensor_data(
+            &pool,
+            DEFAULT_SENSOR_ID,
+            to_utc_datetime("2024-01-01 09:00:00").unwrap(),
+            MeasurementKind::Temperature,
+            "°C",
+            10.00,
+        )
+        .await;
         assert_eq!(id.unwrap(), 1);
-        let entries = list_sensordata(&pool).await.unwrap();
+        let entries = list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap();
         assert_eq!(entries.len(), 1);
         let sensor_data = &entries[0];
         assert_eq!(sensor_data.id, 1);
+        assert_eq!(sensor_data.sensor_id, DEFAULT_SENSOR_ID);
         assert_eq!(
             sensor_data.timestamp,
-            to_naivedatetime("2024-01-01 09:00:00")
+            to_utc_datetime("2024-01-01 09:00:00").unwrap()
         );
+        assert_eq!(sensor_data.kind, MeasurementKind::Temperature);
+        assert_eq!(sensor_data.unit, "°C");
         assert_eq!(sensor_data.value, 10.);
 
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn test_mixed_kinds(pool: SqlitePool) -> sqlx::Result<()> {
+        let timestamp = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        let sensor = DEFAULT_SENSOR_ID;
+        add_sensor_data(
+            &pool,
+            sensor,
+            timestamp,
+            MeasurementKind::Humidity,
+            "%",
+            45.,
+        )
+        .await
+        .unwrap();
+        add_sensor_data(&pool, sensor, timestamp, MeasurementKind::Voltage, "V", 3.3)
+            .await
+            .unwrap();
+        let kinds: Vec<_> = list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.kind, entry.unit))
+            .collect();
+        assert!(kinds.contains(&(MeasurementKind::Humidity, "%".to_string())));
+        assert!(kinds.contains(&(MeasurementKind::Voltage, "V".to_string())));
+
+        // invalid unit or value is rejected
+        let result = add_sensor_data(&pool, sensor, timestamp, MeasurementKind::Humidity, "V", 1.);
+        assert!(result.await.is_err());
+        let result = add_sensor_data(
+            &pool,
+            sensor,
+            timestamp,
+            MeasurementKind::Voltage,
+            "V",
+            f64::NAN,
+        );
+        assert!(result.await.is_err());
+        assert_eq!(
+            list_sensordata(&pool, &[], QualityFilter::All)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_register_sensor(pool: SqlitePool) -> sqlx::Result<()> {
+        let first = register_sensor(&pool, "picow-1").await.unwrap();
+        let second = register_sensor(&pool, "picow-2").await.unwrap();
+        assert_ne!(first, second);
+        assert_ne!(first, DEFAULT_SENSOR_ID);
+        assert_eq!(register_sensor(&pool, "picow-1").await.unwrap(), first);
+
+        let names: Vec<_> = list_sensors(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|sensor| sensor.name)
+            .collect();
+        assert_eq!(names, ["default", "picow-1", "picow-2"]);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_filter_by_sensor(pool: SqlitePool) -> sqlx::Result<()> {
+        let first = register_sensor(&pool, "picow-1").await.unwrap();
+        let second = register_sensor(&pool, "picow-2").await.unwrap();
+        let third = register_sensor(&pool, "picow-3").await.unwrap();
+        let timestamp = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        let kind = MeasurementKind::Temperature;
+        for (sensor, value) in [(first, 10.), (second, 20.), (third, 30.)] {
+            add_sensor_data(&pool, sensor, timestamp, kind, "°C", value)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            list_sensordata(&pool, &[], QualityFilter::All)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+        let entries = list_sensordata(&pool, &[second], QualityFilter::All)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].value, 20.);
+
+        let since = to_utc_datetime("2024-01-01 00:00:00").unwrap();
+        let entries = list_last_values_descending_since(
+            &pool,
+            &[first, third],
+            &since,
+            10,
+            QualityFilter::All,
+        )
+        .await
+        .unwrap();
+        let sensor_ids: Vec<_> = entries.iter().map(|entry| entry.sensor_id).collect();
+        assert_eq!(entries.len(), 2);
+        assert!(sensor_ids.contains(&first) && sensor_ids.contains(&third));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_add_batch(pool: SqlitePool) -> sqlx::Result<()> {
+        let new_value = |timestamp, value| {
+            NewSensorData::temperature(
+                DEFAULT_SENSOR_ID,
+                to_utc_datetime(timestamp).unwrap(),
+                value,
+            )
+        };
+        let values = [
+            new_value("2024-01-01 09:00:00", 10.),
+            new_value("2024-01-01 09:00:01", 11.),
+            new_value("2024-01-01 09:00:02", 12.),
+        ];
+        let ids = add_sensor_data_batch(&pool, &values).await.unwrap();
+        assert_eq!(ids, [1, 2, 3]);
+        let entries = list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap();
+        let stored: Vec<_> = entries.iter().map(|entry| entry.value).collect();
+        assert_eq!(stored, [10., 11., 12.]);
+
+        // one invalid value rejects the whole batch
+        let values = [
+            new_value("2024-01-01 09:00:03", 13.),
+            new_value("2024-01-01 09:00:04", f64::INFINITY),
+        ];
+        assert!(add_sensor_data_batch(&pool, &values).await.is_err());
+        assert_eq!(
+            list_sensordata(&pool, &[], QualityFilter::All)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        // an unknown sensor violates the foreign key and rolls back the transaction
+        let mut unknown_sensor = new_value("2024-01-01 09:00:05", 14.);
+        unknown_sensor.sensor_id = 42;
+        let values = [new_value("2024-01-01 09:00:04", 13.), unknown_sensor];
+        assert!(add_sensor_data_batch(&pool, &values).await.is_err());
+        assert_eq!(
+            list_sensordata(&pool, &[], QualityFilter::All)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_idempotent(pool: SqlitePool) -> sqlx::Result<()> {
+        let new_value = |timestamp, value| {
+            NewSensorData::temperature(
+                DEFAULT_SENSOR_ID,
+                to_utc_datetime(timestamp).unwrap(),
+                value,
+            )
+        };
+        let mut sent = new_value("2024-01-01 09:00:00", 10.);
+        sent.message_id = Some("7".to_string());
+        let id = add_sensor_data_idempotent(&pool, &sent, OnDuplicate::Ignore)
+            .await
+            .unwrap();
+        // a retry with a later arrival time
+        let mut retried = new_value("2024-01-01 09:00:05", 10.);
+        retried.message_id = Some("7".to_string());
+        assert_eq!(
+            add_sensor_data_idempotent(&pool, &retried, OnDuplicate::Ignore)
+                .await
+                .unwrap(),
+            id
+        );
+        let entries = list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].timestamp,
+            to_utc_datetime("2024-01-01 09:00:00").unwrap()
+        );
+
+        // a correction replaces the stored value
+        let mut corrected = retried.clone();
+        corrected.value = 11.;
+        assert_eq!(
+            add_sensor_data_idempotent(&pool, &corrected, OnDuplicate::Replace)
+                .await
+                .unwrap(),
+            id
+        );
+        let entries = list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].value, 11.);
+
+        // the device timestamp identifies a value per kind
+        let device_timestamp = Some(to_utc_datetime("2024-01-01 08:59:58").unwrap());
+        let mut temperature = new_value("2024-01-01 09:01:00", 12.);
+        temperature.device_timestamp = device_timestamp;
+        let mut humidity = temperature.clone();
+        humidity.kind = MeasurementKind::Humidity;
+        humidity.unit = "%".to_string();
+        let ids = add_sensor_data_batch(&pool, &[temperature.clone(), humidity, temperature])
+            .await
+            .unwrap();
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(ids[0], ids[2]);
+        assert_eq!(
+            list_sensordata(&pool, &[], QualityFilter::All)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        // the id of the value the message id belongs to is returned before the other one
+        let mut both = new_value("2024-01-01 09:01:30", 14.);
+        both.message_id = Some("7".to_string());
+        both.device_timestamp = device_timestamp;
+        assert_eq!(
+            add_sensor_data_idempotent(&pool, &both, OnDuplicate::Ignore)
+                .await
+                .unwrap(),
+            id
+        );
+        both.message_id = Some("8".to_string());
+        assert_eq!(
+            add_sensor_data_idempotent(&pool, &both, OnDuplicate::Ignore)
+                .await
+                .unwrap(),
+            ids[0]
+        );
+
+        // values without identity are never duplicates
+        let plain = new_value("2024-01-01 09:02:00", 13.);
+        let ids = add_sensor_data_batch(&pool, &[plain.clone(), plain])
+            .await
+            .unwrap();
+        assert_ne!(ids[0], ids[1]);
+
+        Ok(())
+    }
 }
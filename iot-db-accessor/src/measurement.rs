@@ -0,0 +1,125 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// What a sensor value describes, stored as lowercase text in the `kind` column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum MeasurementKind {
+    Temperature,
+    Humidity,
+    Pressure,
+    Voltage,
+}
+
+impl MeasurementKind {
+    pub const ALL: [MeasurementKind; 4] = [
+        MeasurementKind::Temperature,
+        MeasurementKind::Humidity,
+        MeasurementKind::Pressure,
+        MeasurementKind::Voltage,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MeasurementKind::Temperature => "temperature",
+            MeasurementKind::Humidity => "humidity",
+            MeasurementKind::Pressure => "pressure",
+            MeasurementKind::Voltage => "voltage",
+        }
+    }
+
+    /// Units accepted for this kind, the first one is the default
+    pub fn units(&self) -> &'static [&'static str] {
+        match self {
+            MeasurementKind::Temperature => &["°C", "°F", "K"],
+            MeasurementKind::Humidity => &["%"],
+            MeasurementKind::Pressure => &["hPa", "Pa", "kPa"],
+            MeasurementKind::Voltage => &["V", "mV"],
+        }
+    }
+
+    pub fn default_unit(&self) -> &'static str {
+        self.units()[0]
+    }
+
+    /// Converts a value given in `unit` to the default unit of this kind, unknown units are kept
+    pub fn to_default_unit(&self, unit: &str, value: f64) -> f64 {
+        match (self, unit) {
+            (MeasurementKind::Temperature, "°F") => (value - 32.) * 5. / 9.,
+            (MeasurementKind::Temperature, "K") => value - 273.15,
+            (MeasurementKind::Pressure, "Pa") => value / 100.,
+            (MeasurementKind::Pressure, "kPa") => value * 10.,
+            (MeasurementKind::Voltage, "mV") => value / 1000.,
+            _ => value,
+        }
+    }
+
+    /// Checks that `unit` can be used for values of this kind
+    pub fn validate_unit(&self, unit: &str) -> Result<()> {
+        if !self.units().contains(&unit) {
+            return Err(Error::invalid_input(format!(
+                "invalid unit '{}' for {}, expected one of {:?}",
+                unit,
+                self,
+                self.units()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MeasurementKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for MeasurementKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        MeasurementKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| Error::invalid_input(format!("unknown measurement kind '{}'", s)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MeasurementKind;
+
+    #[test]
+    fn test_parse_kind() {
+        for kind in MeasurementKind::ALL {
+            assert_eq!(kind.as_str().parse::<MeasurementKind>().unwrap(), kind);
+        }
+        assert!("luminosity".parse::<MeasurementKind>().is_err());
+    }
+
+    #[test]
+    fn test_validate_unit() {
+        assert!(MeasurementKind::Temperature.validate_unit("°C").is_ok());
+        assert!(MeasurementKind::Humidity.validate_unit("%").is_ok());
+        assert!(MeasurementKind::Humidity.validate_unit("°C").is_err());
+        assert!(MeasurementKind::Voltage.validate_unit("").is_err());
+    }
+
+    #[test]
+    fn test_to_default_unit() {
+        let temperature = MeasurementKind::Temperature;
+        assert_eq!(temperature.to_default_unit("°C", 21.5), 21.5);
+        assert_eq!(temperature.to_default_unit("°F", 212.), 100.);
+        assert_eq!(temperature.to_default_unit("K", 273.15), 0.);
+        assert_eq!(
+            MeasurementKind::Pressure.to_default_unit("kPa", 101.3),
+            1013.
+        );
+        assert_eq!(MeasurementKind::Voltage.to_default_unit("mV", 3300.), 3.3);
+    }
+}
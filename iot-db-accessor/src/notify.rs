@@ -0,0 +1,275 @@
+//! Push notifications of newly inserted sensor values.
+//!
+//! A [`SensorDataFeed`] broadcasts every value inserted into the database. Inserts through this
+//! crate wake the feeds of the same process right away. Values written by other processes, like
+//! the `iot-data-bridge`, are noticed through `PRAGMA data_version`, which changes whenever another
+//! connection commits and costs no table access. Either way the new rows are read by id, so each
+//! value is published exactly once and in insert order.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::{BoxStream, StreamExt};
+use sqlx::{Connection, SqliteConnection, SqlitePool};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::{Result, SensorData};
+
+/// values a subscriber may fall behind before it misses some
+const FEED_CAPACITY: usize = 1024;
+/// how often `PRAGMA data_version` is checked for writes of other processes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// longest wait between checks while the database keeps failing
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// rows read at once when catching up
+const FETCH_SIZE: i64 = 500;
+
+// woken by every insert of this process, the feeds check their database for new rows
+static INSERTED: Notify = Notify::const_new();
+
+pub(crate) fn notify_inserted() {
+    INSERTED.notify_waiters();
+}
+
+/// Broadcast of newly inserted sensor values, clones share the same subscribers
+#[derive(Debug, Clone)]
+pub struct SensorDataFeed {
+    sender: broadcast::Sender<SensorData>,
+    // the watching task stops once no clone of the feed and no subscriber is left
+    handles: Arc<()>,
+}
+
+impl Default for SensorDataFeed {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(FEED_CAPACITY);
+        SensorDataFeed {
+            sender,
+            handles: Arc::new(()),
+        }
+    }
+}
+
+impl SensorDataFeed {
+    /// A feed that only publishes what is passed to [`SensorDataFeed::publish`]
+    pub fn new() -> Self {
+        SensorDataFeed::default()
+    }
+
+    /// Watches the database and publishes all values inserted from now on.
+    /// The watching task runs on its own connection, so it does not take one from the pool, until
+    /// the feed, its clones and all streams and receivers of it are dropped.
+    pub async fn spawn(pool: SqlitePool) -> Result<Self> {
+        let feed = SensorDataFeed::new();
+        // a dedicated connection, `data_version` only reports commits of other connections
+        let mut conn = SqliteConnection::connect_with(&pool.connect_options()).await?;
+        let mut last_id = max_id(&mut conn).await?;
+        let mut data_version = data_version(&mut conn).await?;
+
+        let sender = feed.sender.clone();
+        let handles = Arc::downgrade(&feed.handles);
+        tokio::spawn(async move {
+            // grows while the database keeps failing, so the errors are not logged every poll
+            let mut delay = POLL_INTERVAL;
+            loop {
+                if handles.strong_count() == 0 && sender.receiver_count() == 0 {
+                    break;
+                }
+                tokio::select! {
+                    _ = INSERTED.notified(), if delay == POLL_INTERVAL => {}
+                    _ = tokio::time::sleep(delay) => {
+                        match data_version_changed(&mut conn, &mut data_version).await {
+                            // after a failure the new rows are read even without a change
+                            Ok(false) if delay == POLL_INTERVAL => continue,
+                            Ok(_) => {}
+                            Err(e) => {
+                                delay = (delay * 2).min(MAX_RETRY_DELAY);
+                                warn!(
+                                    "Failed to check for new sensor values, retrying in {:?}: {:?}",
+                                    delay, e
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                }
+                match publish_new_rows(&mut conn, &sender, &mut last_id).await {
+                    Ok(()) => delay = POLL_INTERVAL,
+                    Err(e) => {
+                        delay = (delay * 2).min(MAX_RETRY_DELAY);
+                        warn!(
+                            "Failed to read new sensor values, retrying in {:?}: {:?}",
+                            delay, e
+                        );
+                    }
+                }
+            }
+            let _ = conn.close().await;
+        });
+        Ok(feed)
+    }
+
+    pub fn publish(&self, value: SensorData) {
+        // nobody listens, that is fine
+        let _ = self.sender.send(value);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SensorData> {
+        self.sender.subscribe()
+    }
+
+    /// Subscribes and yields the values as stream, values missed by a slow consumer are skipped
+    pub fn stream(&self) -> BoxStream<'static, SensorData> {
+        futures::stream::unfold(self.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(value) => return Some((value, receiver)),
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Subscriber is too slow, skipped {} sensor values", missed)
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+async fn data_version(conn: &mut SqliteConnection) -> Result<i64> {
+    Ok(sqlx::query_scalar("PRAGMA data_version")
+        .fetch_one(conn)
+        .await?)
+}
+
+async fn data_version_changed(conn: &mut SqliteConnection, last_version: &mut i64) -> Result<bool> {
+    let version = data_version(conn).await?;
+    let changed = version != *last_version;
+    *last_version = version;
+    Ok(changed)
+}
+
+async fn max_id(conn: &mut SqliteConnection) -> Result<i64> {
+    let id = sqlx::query_scalar!(r#"SELECT MAX(id) AS "id: i64" FROM sensor_values"#)
+        .fetch_one(conn)
+        .await?;
+    Ok(id.unwrap_or(0))
+}
+
+async fn publish_new_rows(
+    conn: &mut SqliteConnection,
+    sender: &broadcast::Sender<SensorData>,
+    last_id: &mut i64,
+) -> Result<()> {
+    // without AUTOINCREMENT the ids of deleted rows are reused, e.g. after the retention removed
+    // all values or a backup was restored
+    *last_id = (*last_id).min(max_id(&mut *conn).await?);
+    loop {
+        let recs = sqlx::query_as_unchecked!(
+            SensorData,
+            r#"
+    SELECT id, sensor_id, timestamp, kind, unit, value, quality, quality_reason
+    FROM calibrated_sensor_values
+    WHERE id > $1
+    ORDER BY id
+    LIMIT $2
+    "#,
+            *last_id,
+            FETCH_SIZE
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        let complete = (recs.len() as i64) < FETCH_SIZE;
+        for rec in recs {
+            *last_id = rec.id;
+            let _ = sender.send(rec);
+        }
+        if complete {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::SqlitePool;
+    use tokio::time::timeout;
+
+    use super::{SensorDataFeed, POLL_INTERVAL};
+    use crate::{add_sensor_data, to_utc_datetime, MeasurementKind, DEFAULT_SENSOR_ID};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[sqlx::test]
+    async fn test_feed(pool: SqlitePool) -> sqlx::Result<()> {
+        let timestamp = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        let kind = MeasurementKind::Temperature;
+        // values inserted before the feed was started are not published
+        add_sensor_data(&pool, DEFAULT_SENSOR_ID, timestamp, kind, "°C", 1.)
+            .await
+            .unwrap();
+        let feed = SensorDataFeed::spawn(pool.clone()).await.unwrap();
+        let mut values = feed.stream();
+
+        // inserted in the same process
+        let id = add_sensor_data(&pool, DEFAULT_SENSOR_ID, timestamp, kind, "°C", 2.)
+            .await
+            .unwrap();
+        let value = timeout(TIMEOUT, values.next()).await.unwrap().unwrap();
+        assert_eq!(value.id, id);
+        assert_eq!(value.value, 2.);
+
+        // written past this crate, like another process would, is noticed by the data version
+        sqlx::query("INSERT INTO sensor_values (sensor_id, timestamp, value) VALUES (1, '2024-01-01T09:00:01+00:00', 3)")
+            .execute(&pool)
+            .await?;
+        let value = timeout(TIMEOUT, values.next()).await.unwrap().unwrap();
+        assert_eq!(value.value, 3.);
+
+        // the stream keeps the feed running, the ids of the deleted rows are used again
+        drop(feed);
+        sqlx::query("DELETE FROM sensor_values")
+            .execute(&pool)
+            .await?;
+        tokio::time::sleep(4 * POLL_INTERVAL).await;
+        let id = add_sensor_data(&pool, DEFAULT_SENSOR_ID, timestamp, kind, "°C", 4.)
+            .await
+            .unwrap();
+        assert_eq!(id, 1);
+        let value = timeout(TIMEOUT, values.next()).await.unwrap().unwrap();
+        assert_eq!(value.id, id);
+        assert_eq!(value.value, 4.);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_feed_keeps_pool_free(pool: SqlitePool) -> sqlx::Result<()> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with((*pool.connect_options()).clone())
+            .await?;
+        let feed = SensorDataFeed::spawn(pool.clone()).await.unwrap();
+        let mut values = feed.stream();
+
+        // the only connection of the pool is still available for inserts
+        let timestamp = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        let insert = add_sensor_data(
+            &pool,
+            DEFAULT_SENSOR_ID,
+            timestamp,
+            MeasurementKind::Temperature,
+            "°C",
+            1.,
+        );
+        let id = timeout(TIMEOUT, insert).await.unwrap().unwrap();
+        let value = timeout(TIMEOUT, values.next()).await.unwrap().unwrap();
+        assert_eq!(value.id, id);
+
+        Ok(())
+    }
+}
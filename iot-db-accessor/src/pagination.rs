@@ -0,0 +1,226 @@
+//! Keyset pagination over the sensor values.
+//!
+//! Values are ordered by `(timestamp, id)`, a [`Cursor`] points at the last value of a page and
+//! the next page starts right after it. Unlike an offset, the cursor stays valid while new values
+//! are inserted and the database does not have to skip over all previous pages.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+use sqlx::SqlitePool;
+
+use crate::quality::quality_filter;
+use crate::{sensor_filter, Error, QualityFilter, Result, SensorData};
+
+/// Position of a value in the `(timestamp, id)` order, serialized as an opaque string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: i64,
+}
+
+impl Cursor {
+    /// Cursor pointing at `value`, a page requested with it starts after the value
+    pub fn after(value: &SensorData) -> Self {
+        Cursor {
+            timestamp: value.timestamp,
+            id: value.id,
+        }
+    }
+
+    pub(crate) fn is_before(&self, value: &SensorData) -> bool {
+        (self.timestamp, self.id) < (value.timestamp, value.id)
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:09}_{}",
+            self.timestamp.timestamp(),
+            self.timestamp.timestamp_subsec_nanos(),
+            self.id
+        )
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::invalid_input(format!("invalid cursor '{}'", s));
+        let (timestamp, id) = s.split_once('_').ok_or_else(invalid)?;
+        let (seconds, nanos) = timestamp.split_once('.').ok_or_else(invalid)?;
+        let seconds = seconds.parse().map_err(|_| invalid())?;
+        let nanos = nanos.parse().map_err(|_| invalid())?;
+        Ok(Cursor {
+            timestamp: DateTime::from_timestamp(seconds, nanos).ok_or_else(invalid)?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// One page of results, `next_cursor` is `None` on the last page
+#[derive(Debug, Clone, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<Cursor>,
+}
+
+impl Page<SensorData> {
+    /// Builds the page from up to `limit + 1` rows, the additional row only tells that more follow
+    pub(crate) fn from_rows(mut rows: Vec<SensorData>, limit: u32) -> Self {
+        let next_cursor = if rows.len() > limit as usize {
+            rows.truncate(limit as usize);
+            rows.last().map(Cursor::after)
+        } else {
+            None
+        };
+        Page {
+            items: rows,
+            next_cursor,
+        }
+    }
+}
+
+pub(crate) fn check_limit(limit: u32) -> Result<()> {
+    if limit == 0 {
+        return Err(Error::invalid_input("page limit must not be zero"));
+    }
+    Ok(())
+}
+
+/// Lists up to `limit` values of the given sensors ascending, starting after `after`.
+/// An empty slice lists the values of all sensors.
+pub async fn list_sensordata_page(
+    pool: &SqlitePool,
+    sensors: &[i64],
+    after: Option<&Cursor>,
+    limit: u32,
+    quality: QualityFilter,
+) -> Result<Page<SensorData>> {
+    check_limit(limit)?;
+    let sensors = sensor_filter(sensors)?;
+    let quality = quality_filter(quality)?;
+    let after_timestamp = after.map(|cursor| cursor.timestamp);
+    let after_id = after.map(|cursor| cursor.id);
+    // one more row than requested tells if there is a next page
+    let rows = i64::from(limit) + 1;
+    let recs = sqlx::query_as_unchecked!(
+        SensorData,
+        r#"
+    SELECT id, sensor_id, timestamp, kind, unit, value, quality, quality_reason
+    FROM calibrated_sensor_values
+    WHERE ($1 = '[]' OR sensor_id IN (SELECT value FROM json_each($1)))
+      AND ($2 IS NULL OR (timestamp, id) > ($2, $3))
+      AND quality IN (SELECT value FROM json_each($5))
+    ORDER BY timestamp, id
+    LIMIT $4
+    "#,
+        sensors,
+        after_timestamp,
+        after_id,
+        rows,
+        quality
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(Page::from_rows(recs, limit))
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::SqlitePool;
+
+    use super::{list_sensordata_page, Cursor};
+    use crate::{
+        add_sensor_data_batch, list_sensordata, to_utc_datetime, Error, NewSensorData,
+        QualityFilter, DEFAULT_SENSOR_ID,
+    };
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let cursor = Cursor {
+            timestamp: to_utc_datetime("2024-01-01 09:00:00").unwrap()
+                + chrono::Duration::nanoseconds(123_456_789),
+            id: 42,
+        };
+        assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), cursor);
+        for invalid in ["", "42", "1704099600_42", "1704099600.0_x"] {
+            let error = invalid.parse::<Cursor>().unwrap_err();
+            assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_pages(pool: SqlitePool) -> sqlx::Result<()> {
+        // values sharing a timestamp must neither be skipped nor repeated between pages
+        let timestamps = [
+            "2024-01-01 09:00:00",
+            "2024-01-01 09:00:00",
+            "2024-01-01 09:00:00",
+            "2024-01-01 09:00:01",
+            "2024-01-01 08:59:59",
+        ];
+        let values: Vec<_> = timestamps
+            .iter()
+            .enumerate()
+            .map(|(i, timestamp)| {
+                NewSensorData::temperature(
+                    DEFAULT_SENSOR_ID,
+                    to_utc_datetime(timestamp).unwrap(),
+                    i as f64,
+                )
+            })
+            .collect();
+        add_sensor_data_batch(&pool, &values).await.unwrap();
+
+        let mut cursor = None;
+        let mut paged = Vec::new();
+        let mut pages = 0;
+        loop {
+            let page = list_sensordata_page(&pool, &[], cursor.as_ref(), 2, QualityFilter::All)
+                .await
+                .unwrap();
+            assert!(page.items.len() <= 2);
+            paged.extend(page.items.iter().map(|value| value.value));
+            pages += 1;
+            match page.next_cursor {
+                Some(next) => cursor = Some(next.to_string().parse().unwrap()),
+                None => break,
+            }
+        }
+        assert_eq!(pages, 3);
+        assert_eq!(paged, [4., 0., 1., 2., 3.]);
+        let all: Vec<_> = list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap()
+            .iter()
+            .map(|value| value.value)
+            .collect();
+        assert_eq!(paged, all);
+
+        // a full last page has no next cursor
+        let page = list_sensordata_page(&pool, &[], None, 5, QualityFilter::All)
+            .await
+            .unwrap();
+        assert_eq!(page.items.len(), 5);
+        assert_eq!(page.next_cursor, None);
+        assert!(
+            list_sensordata_page(&pool, &[], None, 0, QualityFilter::All)
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! Connection settings shared by all binaries.
+//!
+//! The defaults let the bridge write while the webserver and the explorer read: in WAL mode
+//! readers don't block the writer, and connections wait for a lock instead of failing with
+//! `database is locked`.
+
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::SqlitePool;
+
+use crate::aggregation::parse_seconds;
+use crate::schema::migrate;
+use crate::{Error, Result};
+
+/// How the database is opened
+#[derive(Debug, Clone)]
+pub struct DatabaseConfig {
+    /// e.g. `sqlite:./database.sqlite`
+    pub url: String,
+    pub journal_mode: SqliteJournalMode,
+    /// `Normal` is safe in WAL mode, only the last transactions may be lost on a power failure
+    pub synchronous: SqliteSynchronous,
+    /// how long a connection waits for a lock held by another connection
+    pub busy_timeout: Duration,
+    /// connections of the pool
+    pub max_connections: u32,
+    /// whether a missing database file is created
+    pub create_if_missing: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: "sqlite:./database.sqlite".to_string(),
+            journal_mode: SqliteJournalMode::Wal,
+            synchronous: SqliteSynchronous::Normal,
+            busy_timeout: Duration::from_secs(5),
+            max_connections: 10,
+            create_if_missing: true,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// The default settings for the database at `url`
+    pub fn new(url: &str) -> Self {
+        DatabaseConfig {
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Reads the settings from `DATABASE_URL`, `IOT_DB_JOURNAL_MODE` (e.g. `wal` or `delete`),
+    /// `IOT_DB_SYNCHRONOUS` (`off`, `normal`, `full` or `extra`), `IOT_DB_BUSY_TIMEOUT` (e.g. `5s`),
+    /// `IOT_DB_POOL_SIZE` and `IOT_DB_CREATE_IF_MISSING` (`true` or `false`).
+    /// `DATABASE_URL` is required, the other variables keep their default if they are unset.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let url = lookup("DATABASE_URL")
+            .ok_or_else(|| Error::invalid_input("DATABASE_URL is not set"))?;
+        let mut config = DatabaseConfig::new(&url);
+        if let Some(value) = lookup("IOT_DB_JOURNAL_MODE") {
+            config.journal_mode = SqliteJournalMode::from_str(&value)
+                .or_else(|_| invalid_value("IOT_DB_JOURNAL_MODE", &value))?;
+        }
+        if let Some(value) = lookup("IOT_DB_SYNCHRONOUS") {
+            config.synchronous = SqliteSynchronous::from_str(&value)
+                .or_else(|_| invalid_value("IOT_DB_SYNCHRONOUS", &value))?;
+        }
+        if let Some(value) = lookup("IOT_DB_BUSY_TIMEOUT") {
+            let seconds = parse_seconds(&value)
+                .map_err(|e| Error::invalid_input(format!("IOT_DB_BUSY_TIMEOUT: {}", e)))?;
+            config.busy_timeout = Duration::from_secs(seconds.into());
+        }
+        if let Some(value) = lookup("IOT_DB_POOL_SIZE") {
+            config.max_connections = match value.parse() {
+                Ok(size) if size > 0 => size,
+                _ => invalid_value("IOT_DB_POOL_SIZE", &value)?,
+            };
+        }
+        if let Some(value) = lookup("IOT_DB_CREATE_IF_MISSING") {
+            config.create_if_missing = value
+                .parse()
+                .or_else(|_| invalid_value("IOT_DB_CREATE_IF_MISSING", &value))?;
+        }
+        Ok(config)
+    }
+
+    pub fn connect_options(&self) -> Result<SqliteConnectOptions> {
+        Ok(SqliteConnectOptions::from_str(&self.url)?
+            .journal_mode(self.journal_mode)
+            .synchronous(self.synchronous)
+            .busy_timeout(self.busy_timeout)
+            .create_if_missing(self.create_if_missing))
+    }
+
+    /// Opens the database and applies all pending migrations
+    pub async fn connect_and_migrate(&self) -> Result<SqlitePool> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(self.max_connections)
+            .connect_with(self.connect_options()?)
+            .await?;
+        migrate(&pool).await?;
+        Ok(pool)
+    }
+}
+
+fn invalid_value<T>(name: &str, value: &str) -> Result<T> {
+    Err(Error::invalid_input(format!(
+        "{}: invalid value '{}'",
+        name, value
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+
+    use super::DatabaseConfig;
+    use crate::Error;
+
+    fn from_vars(vars: &[(&str, &str)]) -> crate::Result<DatabaseConfig> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        DatabaseConfig::from_lookup(|name| vars.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn test_from_env() {
+        let config = from_vars(&[("DATABASE_URL", "sqlite:test.sqlite")]).unwrap();
+        assert_eq!(config.url, "sqlite:test.sqlite");
+        assert!(matches!(config.journal_mode, SqliteJournalMode::Wal));
+        assert_eq!(config.busy_timeout, Duration::from_secs(5));
+
+        let config = from_vars(&[
+            ("DATABASE_URL", "sqlite:test.sqlite"),
+            ("IOT_DB_JOURNAL_MODE", "delete"),
+            ("IOT_DB_SYNCHRONOUS", "full"),
+            ("IOT_DB_BUSY_TIMEOUT", "30s"),
+            ("IOT_DB_POOL_SIZE", "2"),
+            ("IOT_DB_CREATE_IF_MISSING", "false"),
+        ])
+        .unwrap();
+        assert!(matches!(config.journal_mode, SqliteJournalMode::Delete));
+        assert!(matches!(config.synchronous, SqliteSynchronous::Full));
+        assert_eq!(config.busy_timeout, Duration::from_secs(30));
+        assert_eq!(config.max_connections, 2);
+        assert!(!config.create_if_missing);
+
+        for invalid in [
+            vec![],
+            vec![("IOT_DB_SYNCHRONOUS", "sometimes")],
+            vec![("IOT_DB_BUSY_TIMEOUT", "5")],
+            vec![("IOT_DB_POOL_SIZE", "0")],
+            vec![("IOT_DB_CREATE_IF_MISSING", "yes")],
+        ] {
+            let mut vars = invalid.clone();
+            if !invalid.is_empty() {
+                vars.push(("DATABASE_URL", "sqlite:test.sqlite"));
+            }
+            let error = from_vars(&vars).unwrap_err();
+            assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let path =
+            std::env::temp_dir().join(format!("iot-pool-test-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut config = DatabaseConfig::new(&format!("sqlite:{}", path.display()));
+
+        config.create_if_missing = false;
+        assert!(config.connect_and_migrate().await.is_err());
+
+        config.create_if_missing = true;
+        config.busy_timeout = Duration::from_millis(1500);
+        let pool = config.connect_and_migrate().await.unwrap();
+        let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        let synchronous: i64 = sqlx::query_scalar("PRAGMA synchronous")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(synchronous, 1);
+        let busy_timeout: i64 = sqlx::query_scalar("PRAGMA busy_timeout")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(busy_timeout, 1500);
+        pool.close().await;
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! Plausibility checks of new sensor values.
+//!
+//! Every value is checked against the [`PlausibilityRule`] of its kind when it is inserted. A value
+//! outside of the `min`/`max` limits can't be right and is [`Quality::Rejected`], a value that
+//! changed faster than `max_change_per_minute` since the previous value of the sensor may be right
+//! and is [`Quality::Suspect`]. Flagged values are stored anyway, the queries decide with a
+//! [`QualityFilter`] whether they are returned.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{Error, MeasurementKind, Result};
+
+/// Result of the plausibility check, stored as lowercase text in the `quality` column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Quality {
+    Good,
+    Suspect,
+    Rejected,
+}
+
+impl Quality {
+    pub const ALL: [Quality; 3] = [Quality::Good, Quality::Suspect, Quality::Rejected];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quality::Good => "good",
+            Quality::Suspect => "suspect",
+            Quality::Rejected => "rejected",
+        }
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Quality {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Quality::ALL
+            .into_iter()
+            .find(|quality| quality.as_str() == s)
+            .ok_or_else(|| Error::invalid_input(format!("unknown quality '{}'", s)))
+    }
+}
+
+/// Which values a query returns depending on their quality
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QualityFilter {
+    /// flagged values as well
+    All,
+    /// good and suspect values
+    #[default]
+    NotRejected,
+    /// only good values
+    Good,
+}
+
+impl QualityFilter {
+    pub const ALL: [QualityFilter; 3] = [
+        QualityFilter::All,
+        QualityFilter::NotRejected,
+        QualityFilter::Good,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QualityFilter::All => "all",
+            QualityFilter::NotRejected => "not-rejected",
+            QualityFilter::Good => "good",
+        }
+    }
+
+    /// Qualities of the values that are returned
+    pub fn accepted(&self) -> &'static [Quality] {
+        match self {
+            QualityFilter::All => &Quality::ALL,
+            QualityFilter::NotRejected => &[Quality::Good, Quality::Suspect],
+            QualityFilter::Good => &[Quality::Good],
+        }
+    }
+
+    pub fn accepts(&self, quality: Quality) -> bool {
+        self.accepted().contains(&quality)
+    }
+}
+
+impl fmt::Display for QualityFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for QualityFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        QualityFilter::ALL
+            .into_iter()
+            .find(|filter| filter.as_str() == s)
+            .ok_or_else(|| {
+                Error::invalid_input(format!(
+                    "unknown quality filter '{}', expected all, not-rejected or good",
+                    s
+                ))
+            })
+    }
+}
+
+// like the sensor ids, the accepted qualities are passed as json array
+pub(crate) fn quality_filter(filter: QualityFilter) -> Result<String> {
+    Ok(serde_json::to_string(filter.accepted())?)
+}
+
+/// Limits for the values of one kind in its default unit, `None` disables a check
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlausibilityRule {
+    pub kind: MeasurementKind,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub max_change_per_minute: Option<f64>,
+}
+
+/// Quality of a value and why it is not good
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assessment {
+    pub quality: Quality,
+    pub reason: Option<String>,
+}
+
+impl Assessment {
+    pub fn good() -> Self {
+        Assessment {
+            quality: Quality::Good,
+            reason: None,
+        }
+    }
+
+    fn flagged(quality: Quality, reason: String) -> Self {
+        Assessment {
+            quality,
+            reason: Some(reason),
+        }
+    }
+}
+
+impl PlausibilityRule {
+    /// The rule a new database starts with
+    pub fn default_for(kind: MeasurementKind) -> Self {
+        let (min, max, max_change_per_minute) = match kind {
+            MeasurementKind::Temperature => (-50., 100., Some(10.)),
+            MeasurementKind::Humidity => (0., 100., Some(20.)),
+            MeasurementKind::Pressure => (300., 1100., Some(5.)),
+            MeasurementKind::Voltage => (0., 60., None),
+        };
+        PlausibilityRule {
+            kind,
+            min: Some(min),
+            max: Some(max),
+            max_change_per_minute,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Err(Error::invalid_input(format!(
+                    "minimum {} of {} is above the maximum {}",
+                    min, self.kind, max
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a value given in `unit`. `previous` is the latest value of the same sensor and kind
+    /// before `timestamp` that was not rejected, in the default unit.
+    pub fn assess(
+        &self,
+        timestamp: &DateTime<Utc>,
+        unit: &str,
+        value: f64,
+        previous: Option<(DateTime<Utc>, f64)>,
+    ) -> Assessment {
+        let default_unit = self.kind.default_unit();
+        let value = self.kind.to_default_unit(unit, value);
+        if let Some(min) = self.min.filter(|min| value < *min) {
+            return Assessment::flagged(
+                Quality::Rejected,
+                format!(
+                    "{} {} is below the minimum of {} {}",
+                    value, default_unit, min, default_unit
+                ),
+            );
+        }
+        if let Some(max) = self.max.filter(|max| value > *max) {
+            return Assessment::flagged(
+                Quality::Rejected,
+                format!(
+                    "{} {} is above the maximum of {} {}",
+                    value, default_unit, max, default_unit
+                ),
+            );
+        }
+        if let (Some(max_change), Some((previous_timestamp, previous_value))) =
+            (self.max_change_per_minute, previous)
+        {
+            let minutes = (*timestamp - previous_timestamp).num_milliseconds() as f64 / 60_000.;
+            let change = (value - previous_value).abs() / minutes;
+            if minutes > 0. && change > max_change {
+                return Assessment::flagged(
+                    Quality::Suspect,
+                    format!(
+                        "changed by {:.2} {}/min, more than {} {}/min",
+                        change, default_unit, max_change, default_unit
+                    ),
+                );
+            }
+        }
+        Assessment::good()
+    }
+}
+
+pub async fn list_plausibility_rules(pool: &SqlitePool) -> Result<Vec<PlausibilityRule>> {
+    let recs = sqlx::query_as!(
+        PlausibilityRule,
+        r#"
+    SELECT kind AS "kind: MeasurementKind", min, max, max_change_per_minute
+    FROM plausibility_rules
+    ORDER BY kind
+    "#
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(recs)
+}
+
+/// Replaces the rule of the kind, values that are already stored keep their quality
+pub async fn set_plausibility_rule(pool: &SqlitePool, rule: &PlausibilityRule) -> Result<()> {
+    rule.validate()?;
+    sqlx::query!(
+        r#"
+    INSERT INTO plausibility_rules (kind, min, max, max_change_per_minute)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT (kind) DO UPDATE
+    SET min = excluded.min, max = excluded.max, max_change_per_minute = excluded.max_change_per_minute
+        "#,
+        rule.kind,
+        rule.min,
+        rule.max,
+        rule.max_change_per_minute
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Checks a new value against the rule of its kind and the previous value of the sensor
+pub(crate) async fn assess_sensor_value(
+    conn: &mut SqliteConnection,
+    sensor_id: i64,
+    timestamp: &DateTime<Utc>,
+    kind: MeasurementKind,
+    unit: &str,
+    value: f64,
+) -> Result<Assessment> {
+    let rule = sqlx::query_as!(
+        PlausibilityRule,
+        r#"
+    SELECT kind AS "kind: MeasurementKind", min, max, max_change_per_minute
+    FROM plausibility_rules
+    WHERE kind = $1
+    "#,
+        kind
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some(rule) = rule else {
+        return Ok(Assessment::good());
+    };
+
+    let previous = match rule.max_change_per_minute {
+        None => None,
+        Some(_) => sqlx::query!(
+            r#"
+    SELECT timestamp AS "timestamp!: DateTime<Utc>", unit, value AS "value!: f64"
+    FROM sensor_values
+    WHERE sensor_id = $1 AND kind = $2 AND timestamp < $3
+      AND quality != 'rejected' AND value IS NOT NULL
+    ORDER BY timestamp DESC
+    LIMIT 1
+    "#,
+            sensor_id,
+            kind,
+            timestamp
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .map(|rec| (rec.timestamp, kind.to_default_unit(&rec.unit, rec.value))),
+    };
+    Ok(rule.assess(timestamp, unit, value, previous))
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::SqlitePool;
+
+    use super::{
+        list_plausibility_rules, set_plausibility_rule, PlausibilityRule, Quality, QualityFilter,
+    };
+    use crate::{
+        add_sensor_data, list_sensordata, to_utc_datetime, MeasurementKind, DEFAULT_SENSOR_ID,
+    };
+
+    #[test]
+    fn test_assess() {
+        let rule = PlausibilityRule::default_for(MeasurementKind::Temperature);
+        let timestamp = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        let minute_before = Some((to_utc_datetime("2024-01-01 08:59:00").unwrap(), 20.));
+
+        assert_eq!(
+            rule.assess(&timestamp, "°C", 25., minute_before).quality,
+            Quality::Good
+        );
+        let assessment = rule.assess(&timestamp, "°C", -273., minute_before);
+        assert_eq!(assessment.quality, Quality::Rejected);
+        assert_eq!(
+            assessment.reason.unwrap(),
+            "-273 °C is below the minimum of -50 °C"
+        );
+        // 100 °F are 37.8 °C
+        let assessment = rule.assess(&timestamp, "°F", 100., minute_before);
+        assert_eq!(assessment.quality, Quality::Suspect);
+        assert_eq!(
+            assessment.reason.unwrap(),
+            "changed by 17.78 °C/min, more than 10 °C/min"
+        );
+        // the same change over an hour is fine, so is the first value
+        let hour_before = Some((to_utc_datetime("2024-01-01 08:00:00").unwrap(), 20.));
+        assert_eq!(
+            rule.assess(&timestamp, "°F", 100., hour_before).quality,
+            Quality::Good
+        );
+        assert_eq!(
+            rule.assess(&timestamp, "°C", 99., None).quality,
+            Quality::Good
+        );
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        for filter in QualityFilter::ALL {
+            assert_eq!(filter.as_str().parse::<QualityFilter>().unwrap(), filter);
+        }
+        assert!("bad".parse::<QualityFilter>().is_err());
+        assert!(QualityFilter::NotRejected.accepts(Quality::Suspect));
+        assert!(!QualityFilter::NotRejected.accepts(Quality::Rejected));
+        assert!(!QualityFilter::Good.accepts(Quality::Suspect));
+    }
+
+    #[sqlx::test]
+    async fn test_flag_on_insert(pool: SqlitePool) -> sqlx::Result<()> {
+        let kind = MeasurementKind::Temperature;
+        for (timestamp, value) in [
+            ("2024-01-01 09:00:00", 20.),
+            // the glitching ADC
+            ("2024-01-01 09:01:00", -273.),
+            // compared to the last value that was not rejected
+            ("2024-01-01 09:02:00", 21.),
+            ("2024-01-01 09:03:00", 45.),
+        ] {
+            let timestamp = to_utc_datetime(timestamp).unwrap();
+            add_sensor_data(&pool, DEFAULT_SENSOR_ID, timestamp, kind, "°C", value)
+                .await
+                .unwrap();
+        }
+
+        let qualities = |filter| {
+            let pool = pool.clone();
+            async move {
+                list_sensordata(&pool, &[], filter)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|value| (value.value, value.quality))
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            qualities(QualityFilter::All).await,
+            [
+                (20., Quality::Good),
+                (-273., Quality::Rejected),
+                (21., Quality::Good),
+                (45., Quality::Suspect)
+            ]
+        );
+        assert_eq!(qualities(QualityFilter::NotRejected).await.len(), 3);
+        assert_eq!(qualities(QualityFilter::Good).await.len(), 2);
+        let values = list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap();
+        assert_eq!(
+            values[3].quality_reason.as_deref(),
+            Some("changed by 24.00 °C/min, more than 10 °C/min")
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_rules(pool: SqlitePool) -> sqlx::Result<()> {
+        let rules = list_plausibility_rules(&pool).await.unwrap();
+        assert_eq!(rules.len(), MeasurementKind::ALL.len());
+        for rule in rules {
+            assert_eq!(rule, PlausibilityRule::default_for(rule.kind));
+        }
+
+        // a greenhouse gets warmer than the default allows
+        let rule = PlausibilityRule {
+            max: Some(150.),
+            ..PlausibilityRule::default_for(MeasurementKind::Temperature)
+        };
+        set_plausibility_rule(&pool, &rule).await.unwrap();
+        let timestamp = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        let kind = MeasurementKind::Temperature;
+        add_sensor_data(&pool, DEFAULT_SENSOR_ID, timestamp, kind, "°C", 120.)
+            .await
+            .unwrap();
+        let values = list_sensordata(&pool, &[], QualityFilter::Good)
+            .await
+            .unwrap();
+        assert_eq!(values.len(), 1);
+
+        let invalid = PlausibilityRule {
+            min: Some(200.),
+            ..rule
+        };
+        assert!(set_plausibility_rule(&pool, &invalid).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,347 @@
+//! Queries over the sensor values composed from optional filters.
+//!
+//! A [`SensorQuery`] is built step by step and executed against the pool, instead of adding an
+//! accessor function for every combination of bounds, filters and ordering. Without any filter it
+//! selects all values that are not rejected, ascending by `(timestamp, id)`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::quality::quality_filter;
+use crate::{
+    get_date_with_default, get_end_date_with_default, sensor_filter, Error, MeasurementKind,
+    QualityFilter, Result, SensorData,
+};
+
+/// Order of the values by `(timestamp, id)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Ascending => "ascending",
+            SortOrder::Descending => "descending",
+        }
+    }
+}
+
+impl fmt::Display for SortOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ascending" | "asc" => Ok(SortOrder::Ascending),
+            "descending" | "desc" => Ok(SortOrder::Descending),
+            _ => Err(Error::invalid_input(format!("unknown sort order '{}'", s))),
+        }
+    }
+}
+
+/// Selects sensor values, e.g. the last 10 good temperatures of sensor 2 above 30 °C are
+/// `SensorQuery::new().sensors(&[2]).kind(MeasurementKind::Temperature).min_value(30.)`
+/// `.quality(QualityFilter::Good).order(SortOrder::Descending).limit(10)`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SensorQuery {
+    from: Option<DateTime<Utc>>,
+    // whether a value at `from` is selected
+    from_inclusive: bool,
+    to: Option<DateTime<Utc>>,
+    sensors: Vec<i64>,
+    kind: Option<MeasurementKind>,
+    min_value: Option<f64>,
+    max_value: Option<f64>,
+    quality: QualityFilter,
+    pub(crate) order: SortOrder,
+    pub(crate) limit: Option<u32>,
+    pub(crate) offset: u32,
+}
+
+impl SensorQuery {
+    pub fn new() -> Self {
+        SensorQuery::default()
+    }
+
+    /// Values at or after `from`
+    pub fn from(mut self, from: DateTime<Utc>) -> Self {
+        self.from = Some(from);
+        self.from_inclusive = true;
+        self
+    }
+
+    /// Values after `after`, e.g. the timestamp of the last value already shown
+    pub fn after(mut self, after: DateTime<Utc>) -> Self {
+        self.from = Some(after);
+        self.from_inclusive = false;
+        self
+    }
+
+    /// Values before `to`
+    pub fn to(mut self, to: DateTime<Utc>) -> Self {
+        self.to = Some(to);
+        self
+    }
+
+    /// Values of the given sensors, an empty slice selects all sensors
+    pub fn sensors(mut self, sensors: &[i64]) -> Self {
+        self.sensors = sensors.to_vec();
+        self
+    }
+
+    pub fn kind(mut self, kind: MeasurementKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Values of at least `min`, compared after the calibration
+    pub fn min_value(mut self, min: f64) -> Self {
+        self.min_value = Some(min);
+        self
+    }
+
+    /// Values of at most `max`, compared after the calibration
+    pub fn max_value(mut self, max: f64) -> Self {
+        self.max_value = Some(max);
+        self
+    }
+
+    pub fn quality(mut self, quality: QualityFilter) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    pub fn order(mut self, order: SortOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// At most `limit` values, all values without a limit
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skips the first `offset` values in the order of the query
+    pub fn offset(mut self, offset: u32) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Checks that the limit is not zero and that the value filters are real numbers
+    pub fn validate(&self) -> Result<()> {
+        if self.limit == Some(0) {
+            return Err(Error::invalid_input("query limit must not be zero"));
+        }
+        for value in [self.min_value, self.max_value].into_iter().flatten() {
+            if value.is_nan() {
+                return Err(Error::invalid_input("value filters must be numbers"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the calibrated value is selected by the filters, the order, limit and offset
+    /// are not considered
+    pub fn matches(&self, value: &SensorData) -> bool {
+        let after_from = self.from.is_none_or(|from| {
+            value.timestamp > from || (self.from_inclusive && value.timestamp == from)
+        });
+        after_from
+            && self.to.is_none_or(|to| value.timestamp < to)
+            && (self.sensors.is_empty() || self.sensors.contains(&value.sensor_id))
+            && self.kind.is_none_or(|kind| value.kind == kind)
+            && self.min_value.is_none_or(|min| value.value >= min)
+            && self.max_value.is_none_or(|max| value.value <= max)
+            && self.quality.accepts(value.quality)
+    }
+
+    /// Loads the selected values at once
+    pub async fn fetch_all(&self, pool: &SqlitePool) -> Result<Vec<SensorData>> {
+        self.validate()?;
+        // open bounds are passed as the earliest and latest timestamps, so the index is used
+        let from = get_date_with_default(&self.from);
+        let from_inclusive = self.from.is_none() || self.from_inclusive;
+        let to = get_end_date_with_default(&self.to);
+        let sensors = sensor_filter(&self.sensors)?;
+        let quality = quality_filter(self.quality)?;
+        // a negative limit is no limit in sqlite
+        let limit = self.limit.map_or(-1, i64::from);
+        // a fixed order per query, so sqlite sorts by walking the timestamp index
+        let recs = match self.order {
+            SortOrder::Ascending => {
+                sqlx::query_as_unchecked!(
+                    SensorData,
+                    r#"
+    SELECT id, sensor_id, timestamp, kind, unit, value, quality, quality_reason
+    FROM calibrated_sensor_values
+    WHERE timestamp >= $1 AND timestamp < $3
+      AND ($2 OR timestamp != $1)
+      AND ($4 = '[]' OR sensor_id IN (SELECT value FROM json_each($4)))
+      AND ($5 IS NULL OR kind = $5)
+      AND ($6 IS NULL OR value >= $6)
+      AND ($7 IS NULL OR value <= $7)
+      AND quality IN (SELECT value FROM json_each($8))
+    ORDER BY timestamp, id
+    LIMIT $9 OFFSET $10
+    "#,
+                    from,
+                    from_inclusive,
+                    to,
+                    sensors,
+                    self.kind,
+                    self.min_value,
+                    self.max_value,
+                    quality,
+                    limit,
+                    self.offset
+                )
+                .fetch_all(pool)
+                .await?
+            }
+            SortOrder::Descending => {
+                sqlx::query_as_unchecked!(
+                    SensorData,
+                    r#"
+    SELECT id, sensor_id, timestamp, kind, unit, value, quality, quality_reason
+    FROM calibrated_sensor_values
+    WHERE timestamp >= $1 AND timestamp < $3
+      AND ($2 OR timestamp != $1)
+      AND ($4 = '[]' OR sensor_id IN (SELECT value FROM json_each($4)))
+      AND ($5 IS NULL OR kind = $5)
+      AND ($6 IS NULL OR value >= $6)
+      AND ($7 IS NULL OR value <= $7)
+      AND quality IN (SELECT value FROM json_each($8))
+    ORDER BY timestamp DESC, id DESC
+    LIMIT $9 OFFSET $10
+    "#,
+                    from,
+                    from_inclusive,
+                    to,
+                    sensors,
+                    self.kind,
+                    self.min_value,
+                    self.max_value,
+                    quality,
+                    limit,
+                    self.offset
+                )
+                .fetch_all(pool)
+                .await?
+            }
+        };
+        Ok(recs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use sqlx::SqlitePool;
+
+    use super::{SensorQuery, SortOrder};
+    use crate::{
+        add_sensor_data_batch, register_sensor, to_utc_datetime, Error, MeasurementKind,
+        NewSensorData, QualityFilter, SensorData, DEFAULT_SENSOR_ID,
+    };
+
+    fn ids(values: &[SensorData]) -> Vec<i64> {
+        values.iter().map(|value| value.id).collect()
+    }
+
+    #[sqlx::test]
+    async fn test_query(pool: SqlitePool) -> sqlx::Result<()> {
+        let other = register_sensor(&pool, "picow-1").await.unwrap();
+        let start = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        // a temperature per minute alternating between the sensors, then a humidity
+        let mut values: Vec<_> = (0..6)
+            .map(|minute| {
+                let sensor_id = if minute % 2 == 0 {
+                    DEFAULT_SENSOR_ID
+                } else {
+                    other
+                };
+                let timestamp = start + Duration::minutes(minute);
+                NewSensorData::temperature(sensor_id, timestamp, 20. + minute as f64)
+            })
+            .collect();
+        values.push(NewSensorData {
+            kind: MeasurementKind::Humidity,
+            unit: "%".to_string(),
+            value: 40.,
+            ..values[5].clone()
+        });
+        let inserted = add_sensor_data_batch(&pool, &values).await.unwrap();
+
+        let all = SensorQuery::new().fetch_all(&pool).await.unwrap();
+        assert_eq!(ids(&all), inserted);
+
+        let query = SensorQuery::new()
+            .from(start + Duration::minutes(1))
+            .to(start + Duration::minutes(4));
+        assert_eq!(ids(&query.fetch_all(&pool).await.unwrap()), inserted[1..4]);
+        let query = SensorQuery::new().after(start + Duration::minutes(4));
+        assert_eq!(ids(&query.fetch_all(&pool).await.unwrap()), inserted[5..]);
+
+        let query = SensorQuery::new()
+            .sensors(&[other])
+            .kind(MeasurementKind::Temperature);
+        let temperatures = query.fetch_all(&pool).await.unwrap();
+        assert_eq!(ids(&temperatures), [inserted[1], inserted[3], inserted[5]]);
+        assert!(temperatures.iter().all(|value| query.matches(value)));
+
+        let query = SensorQuery::new().min_value(22.).max_value(24.);
+        assert_eq!(ids(&query.fetch_all(&pool).await.unwrap()), inserted[2..5]);
+
+        // the page after the first two of the latest values
+        let query = SensorQuery::new()
+            .order(SortOrder::Descending)
+            .limit(2)
+            .offset(2);
+        assert_eq!(
+            ids(&query.fetch_all(&pool).await.unwrap()),
+            [inserted[4], inserted[3]]
+        );
+        let query = SensorQuery::new().offset(5);
+        assert_eq!(ids(&query.fetch_all(&pool).await.unwrap()), inserted[5..]);
+
+        let query = SensorQuery::new().quality(QualityFilter::Good).limit(0);
+        let error = query.fetch_all(&pool).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+        let error = SensorQuery::new()
+            .min_value(f64::NAN)
+            .fetch_all(&pool)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{:?}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_order() {
+        assert_eq!("desc".parse::<SortOrder>().unwrap(), SortOrder::Descending);
+        assert_eq!(
+            SortOrder::Ascending
+                .to_string()
+                .parse::<SortOrder>()
+                .unwrap(),
+            SortOrder::Ascending
+        );
+        assert!("up".parse::<SortOrder>().is_err());
+    }
+}
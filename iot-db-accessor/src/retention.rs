@@ -0,0 +1,674 @@
+//! Retention tiers for sensor values.
+//!
+//! Raw values are rolled up into 1 minute buckets and those into 1 hour buckets.
+//! Each tier is only cleaned up after it was rolled up into the next coarser tier,
+//! so the aggregation queries can combine the tiers without losing values.
+//!
+//! Values stored or changed after their minute was rolled up, e.g. backfilled ones, mark the
+//! minute as stale. The next run aggregates a stale minute again from its raw values, or, if the
+//! raw values of the minute were already removed, adds the late values to its rollups.
+
+use std::env;
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::aggregation::parse_seconds;
+use crate::{Error, Result};
+
+const RAW_TIER: &str = "raw";
+const MINUTE_TIER: &str = "1m";
+const HOUR_TIER: &str = "1h";
+
+/// How long the values of each tier are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// raw values as received from the sensors
+    pub raw: Duration,
+    /// 1 minute rollups
+    pub minute: Duration,
+    /// 1 hour rollups, `None` keeps them forever
+    pub hourly: Option<Duration>,
+    /// how often the background job applies the policy
+    pub interval: std::time::Duration,
+    /// minutes are rolled up once they ended this long ago, values stored late, e.g. after waiting
+    /// in a batch or for a lock, would be missing in the rollups otherwise
+    pub lag: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            raw: Duration::days(7),
+            minute: Duration::days(90),
+            hourly: None,
+            interval: std::time::Duration::from_secs(10 * 60),
+            lag: Duration::minutes(5),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Reads the policy from `IOT_RETENTION_RAW`, `IOT_RETENTION_MINUTE`, `IOT_RETENTION_HOURLY`,
+    /// `IOT_RETENTION_INTERVAL` and `IOT_RETENTION_LAG` (e.g. `7d`), unset variables keep their
+    /// default.
+    /// `IOT_RETENTION_HOURLY` also accepts `forever`.
+    pub fn from_env() -> Result<Self> {
+        let mut policy = RetentionPolicy::default();
+        if let Some(raw) = env_duration("IOT_RETENTION_RAW")? {
+            policy.raw = raw;
+        }
+        if let Some(minute) = env_duration("IOT_RETENTION_MINUTE")? {
+            policy.minute = minute;
+        }
+        match env::var("IOT_RETENTION_HOURLY") {
+            Ok(hourly) if hourly == "forever" => policy.hourly = None,
+            _ => policy.hourly = env_duration("IOT_RETENTION_HOURLY")?,
+        }
+        if let Some(interval) = env_duration("IOT_RETENTION_INTERVAL")? {
+            policy.interval = interval
+                .to_std()
+                .map_err(|_| Error::invalid_input("IOT_RETENTION_INTERVAL must not be negative"))?;
+        }
+        if let Some(lag) = env_duration("IOT_RETENTION_LAG")? {
+            policy.lag = lag;
+        }
+        Ok(policy)
+    }
+}
+
+fn env_duration(name: &str) -> Result<Option<Duration>> {
+    match env::var(name) {
+        Ok(value) => {
+            let seconds = parse_seconds(&value)
+                .map_err(|e| Error::invalid_input(format!("{}: {}", name, e)))?;
+            Ok(Some(Duration::seconds(seconds.into())))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Number of rows removed from each tier by [`apply_retention`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    pub raw_deleted: u64,
+    pub minute_deleted: u64,
+    pub hourly_deleted: u64,
+}
+
+/// Rolls up all complete minutes and hours before `now - policy.lag`, aggregates the buckets of
+/// late values again and removes values older than the policy allows
+pub async fn apply_retention(
+    pool: &SqlitePool,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> Result<RetentionReport> {
+    let mut tx = pool.begin().await?;
+
+    let raw_deleted_until = deleted_until(&mut tx, RAW_TIER).await?;
+    let minute_deleted_until = deleted_until(&mut tx, MINUTE_TIER).await?;
+    refresh_stale_rollups(&mut tx, raw_deleted_until, minute_deleted_until).await?;
+
+    let minute_end = (now - policy.lag).duration_trunc(Duration::minutes(1))?;
+    let minute_start = rolled_up_until(&mut tx, MINUTE_TIER).await?;
+    if minute_start < minute_end {
+        sqlx::query!(
+            r#"
+    INSERT INTO sensor_values_1m (sensor_id, kind, unit, bucket_start, min, max, sum, count)
+    SELECT sensor_id, kind, unit, strftime('%Y-%m-%dT%H:%M:00+00:00', timestamp),
+           MIN(value), MAX(value), SUM(value), COUNT(value)
+    FROM sensor_values
+    WHERE timestamp >= $1 AND timestamp < $2 AND value IS NOT NULL AND quality != 'rejected'
+    GROUP BY 1, 2, 3, 4
+    ON CONFLICT (sensor_id, kind, unit, bucket_start) DO UPDATE
+    SET min = excluded.min, max = excluded.max, sum = excluded.sum, count = excluded.count
+            "#,
+            minute_start,
+            minute_end
+        )
+        .execute(&mut *tx)
+        .await?;
+        set_rolled_up_until(&mut tx, MINUTE_TIER, minute_end).await?;
+    }
+
+    let hour_end = minute_end.duration_trunc(Duration::hours(1))?;
+    let hour_start = rolled_up_until(&mut tx, HOUR_TIER).await?;
+    if hour_start < hour_end {
+        sqlx::query!(
+            r#"
+    INSERT INTO sensor_values_1h (sensor_id, kind, unit, bucket_start, min, max, sum, count)
+    SELECT sensor_id, kind, unit, strftime('%Y-%m-%dT%H:00:00+00:00', bucket_start),
+           MIN(min), MAX(max), SUM(sum), SUM(count)
+    FROM sensor_values_1m
+    WHERE bucket_start >= $1 AND bucket_start < $2
+    GROUP BY 1, 2, 3, 4
+    ON CONFLICT (sensor_id, kind, unit, bucket_start) DO UPDATE
+    SET min = excluded.min, max = excluded.max, sum = excluded.sum, count = excluded.count
+            "#,
+            hour_start,
+            hour_end
+        )
+        .execute(&mut *tx)
+        .await?;
+        set_rolled_up_until(&mut tx, HOUR_TIER, hour_end).await?;
+    }
+
+    // never remove values that are not yet contained in the next tier. Whole minutes are
+    // removed, so a kept minute can be aggregated again from its raw values, and the late values
+    // before the previous cutoff were merged into the rollups above.
+    let raw_cutoff = (now - policy.raw)
+        .duration_trunc(Duration::minutes(1))?
+        .min(minute_end)
+        .max(raw_deleted_until);
+    let raw_deleted = sqlx::query!("DELETE FROM sensor_values WHERE timestamp < $1", raw_cutoff)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    set_deleted_until(&mut tx, RAW_TIER, raw_cutoff).await?;
+
+    // the minutes of raw values are kept, so their late values can be aggregated again
+    let minute_cutoff = (now - policy.minute)
+        .min(raw_cutoff)
+        .duration_trunc(Duration::hours(1))?
+        .min(hour_end)
+        .max(minute_deleted_until);
+    let minute_deleted = sqlx::query!(
+        "DELETE FROM sensor_values_1m WHERE bucket_start < $1",
+        minute_cutoff
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    set_deleted_until(&mut tx, MINUTE_TIER, minute_cutoff).await?;
+
+    let hourly_deleted = match policy.hourly {
+        Some(hourly) => {
+            let hourly_cutoff = now - hourly;
+            sqlx::query!(
+                "DELETE FROM sensor_values_1h WHERE bucket_start < $1",
+                hourly_cutoff
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+        }
+        None => 0,
+    };
+
+    tx.commit().await?;
+    Ok(RetentionReport {
+        raw_deleted,
+        minute_deleted,
+        hourly_deleted,
+    })
+}
+
+/// Aggregates the stale minutes and their hours again. The raw values before `raw_deleted_until`
+/// were all stored late, they are added to the rollups of their minute and, if the minutes of the
+/// hour were removed as well, of their hour.
+async fn refresh_stale_rollups(
+    tx: &mut Transaction<'_, Sqlite>,
+    raw_deleted_until: DateTime<Utc>,
+    minute_deleted_until: DateTime<Utc>,
+) -> Result<()> {
+    let hour_end = rolled_up_until(tx, HOUR_TIER).await?;
+    let hour_merge_end = raw_deleted_until.min(minute_deleted_until).min(hour_end);
+    sqlx::query!(
+        r#"
+    INSERT INTO sensor_values_1h (sensor_id, kind, unit, bucket_start, min, max, sum, count)
+    SELECT sensor_id, kind, unit, strftime('%Y-%m-%dT%H:00:00+00:00', timestamp),
+           MIN(value), MAX(value), SUM(value), COUNT(value)
+    FROM sensor_values
+    WHERE timestamp < $1 AND value IS NOT NULL AND quality != 'rejected'
+    GROUP BY 1, 2, 3, 4
+    ON CONFLICT (sensor_id, kind, unit, bucket_start) DO UPDATE
+    SET min = MIN(sensor_values_1h.min, excluded.min), max = MAX(sensor_values_1h.max, excluded.max),
+        sum = sensor_values_1h.sum + excluded.sum, count = sensor_values_1h.count + excluded.count
+        "#,
+        hour_merge_end
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        r#"
+    INSERT INTO sensor_values_1m (sensor_id, kind, unit, bucket_start, min, max, sum, count)
+    SELECT sensor_id, kind, unit, strftime('%Y-%m-%dT%H:%M:00+00:00', timestamp),
+           MIN(value), MAX(value), SUM(value), COUNT(value)
+    FROM sensor_values
+    WHERE timestamp < $1 AND value IS NOT NULL AND quality != 'rejected'
+    GROUP BY 1, 2, 3, 4
+    ON CONFLICT (sensor_id, kind, unit, bucket_start) DO UPDATE
+    SET min = MIN(sensor_values_1m.min, excluded.min), max = MAX(sensor_values_1m.max, excluded.max),
+        sum = sensor_values_1m.sum + excluded.sum, count = sensor_values_1m.count + excluded.count
+        "#,
+        raw_deleted_until
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    // the raw values of the other stale minutes are complete
+    sqlx::query!(
+        r#"
+    DELETE FROM sensor_values_1m
+    WHERE bucket_start >= $1
+      AND (sensor_id, kind, unit, bucket_start) IN
+          (SELECT sensor_id, kind, unit, bucket_start FROM stale_rollups)
+        "#,
+        raw_deleted_until
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        r#"
+    INSERT INTO sensor_values_1m (sensor_id, kind, unit, bucket_start, min, max, sum, count)
+    SELECT stale.sensor_id, stale.kind, stale.unit, stale.bucket_start,
+           MIN(value), MAX(value), SUM(value), COUNT(value)
+    FROM stale_rollups AS stale
+    JOIN sensor_values
+      ON sensor_values.sensor_id = stale.sensor_id AND sensor_values.kind = stale.kind
+     AND sensor_values.unit = stale.unit AND timestamp >= stale.bucket_start
+     AND timestamp < strftime('%Y-%m-%dT%H:%M:00+00:00', stale.bucket_start, '+1 minute')
+    WHERE stale.bucket_start >= $1 AND value IS NOT NULL AND quality != 'rejected'
+    GROUP BY 1, 2, 3, 4
+        "#,
+        raw_deleted_until
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    // hours whose minutes are kept are aggregated again from them
+    sqlx::query!(
+        r#"
+    DELETE FROM sensor_values_1h
+    WHERE bucket_start >= $1 AND bucket_start < $2
+      AND (sensor_id, kind, unit, bucket_start) IN
+          (SELECT sensor_id, kind, unit, strftime('%Y-%m-%dT%H:00:00+00:00', bucket_start)
+           FROM stale_rollups)
+        "#,
+        minute_deleted_until,
+        hour_end
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        r#"
+    INSERT INTO sensor_values_1h (sensor_id, kind, unit, bucket_start, min, max, sum, count)
+    SELECT sensor_id, kind, unit, strftime('%Y-%m-%dT%H:00:00+00:00', bucket_start) AS hour,
+           MIN(min), MAX(max), SUM(sum), SUM(count)
+    FROM sensor_values_1m
+    WHERE hour >= $1 AND hour < $2
+      AND (sensor_id, kind, unit, hour) IN
+          (SELECT sensor_id, kind, unit, strftime('%Y-%m-%dT%H:00:00+00:00', bucket_start)
+           FROM stale_rollups)
+    GROUP BY 1, 2, 3, 4
+        "#,
+        minute_deleted_until,
+        hour_end
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM stale_rollups")
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Applies the policy periodically until the returned task is aborted
+pub fn spawn_retention_job(pool: SqlitePool, policy: RetentionPolicy) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(policy.interval);
+        loop {
+            interval.tick().await;
+            let now = chrono::Utc::now();
+            match apply_retention(&pool, &policy, now).await {
+                Ok(report) => info!("applied retention policy: {:?}", report),
+                Err(e) => warn!("Failed to apply retention policy: {:?}", e),
+            }
+        }
+    })
+}
+
+/// Exclusive end of the values rolled up into the tier, `UNIX_EPOCH` if nothing was rolled up yet
+pub(crate) async fn rolled_up_until(
+    tx: &mut Transaction<'_, Sqlite>,
+    tier: &str,
+) -> Result<DateTime<Utc>> {
+    let rolled_up_until = sqlx::query_scalar_unchecked!(
+        r#"SELECT rolled_up_until AS "rolled_up_until: DateTime<Utc>" FROM rollup_watermarks WHERE tier = $1"#,
+        tier
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+    Ok(rolled_up_until.unwrap_or(DateTime::UNIX_EPOCH))
+}
+
+async fn set_rolled_up_until(
+    tx: &mut Transaction<'_, Sqlite>,
+    tier: &str,
+    rolled_up_until: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+    INSERT INTO rollup_watermarks (tier, rolled_up_until)
+    VALUES ($1, $2)
+    ON CONFLICT (tier) DO UPDATE SET rolled_up_until = excluded.rolled_up_until
+        "#,
+        tier,
+        rolled_up_until
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Start of the rows still kept in the table of the tier, `UNIX_EPOCH` if nothing was removed yet
+async fn deleted_until(tx: &mut Transaction<'_, Sqlite>, tier: &str) -> Result<DateTime<Utc>> {
+    let deleted_until = sqlx::query_scalar_unchecked!(
+        r#"SELECT deleted_until AS "deleted_until: DateTime<Utc>" FROM retention_cutoffs WHERE tier = $1"#,
+        tier
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+    Ok(deleted_until.unwrap_or(DateTime::UNIX_EPOCH))
+}
+
+async fn set_deleted_until(
+    tx: &mut Transaction<'_, Sqlite>,
+    tier: &str,
+    deleted_until: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+    INSERT INTO retention_cutoffs (tier, deleted_until)
+    VALUES ($1, $2)
+    ON CONFLICT (tier) DO UPDATE SET deleted_until = excluded.deleted_until
+        "#,
+        tier,
+        deleted_until
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Watermarks of the minute and hour tier read in one transaction
+pub(crate) async fn rollup_watermarks(pool: &SqlitePool) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut tx = pool.begin().await?;
+    let minute = rolled_up_until(&mut tx, MINUTE_TIER).await?;
+    let hour = rolled_up_until(&mut tx, HOUR_TIER).await?;
+    tx.commit().await?;
+    Ok((minute, hour))
+}
+
+/// Times before which the raw values and the minute rollups were removed, read in one transaction
+pub(crate) async fn retention_cutoffs(pool: &SqlitePool) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut tx = pool.begin().await?;
+    let raw = deleted_until(&mut tx, RAW_TIER).await?;
+    let minute = deleted_until(&mut tx, MINUTE_TIER).await?;
+    tx.commit().await?;
+    Ok((raw, minute))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use sqlx::SqlitePool;
+
+    use super::{apply_retention, RetentionPolicy};
+    use crate::{
+        add_sensor_data, add_sensor_data_idempotent, aggregate_sensordata, list_sensordata,
+        to_utc_datetime, Aggregate, Error, MeasurementKind, NewSensorData, OnDuplicate,
+        QualityFilter, DEFAULT_SENSOR_ID,
+    };
+
+    async fn count_rows(pool: &SqlitePool, table: &str) -> i64 {
+        sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table))
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_apply_retention(pool: SqlitePool) -> sqlx::Result<()> {
+        let kind = MeasurementKind::Temperature;
+        let timestamps = [
+            "2024-01-01 09:00:00",
+            "2024-01-01 09:00:30",
+            "2024-01-01 09:59:00",
+            "2024-01-02 10:00:00",
+            "2024-01-03 11:00:00",
+            "2024-01-03 11:59:30",
+        ];
+        for (i, timestamp) in timestamps.iter().enumerate() {
+            let timestamp = to_utc_datetime(timestamp).unwrap();
+            add_sensor_data(&pool, DEFAULT_SENSOR_ID, timestamp, kind, "°C", i as f64)
+                .await
+                .unwrap();
+        }
+        let aggregate = |width: &'static str| {
+            let pool = pool.clone();
+            async move {
+                aggregate_sensordata(
+                    &pool,
+                    &[],
+                    kind,
+                    &to_utc_datetime("2024-01-01 00:00:00").unwrap(),
+                    &to_utc_datetime("2024-01-04 00:00:00").unwrap(),
+                    width.parse().unwrap(),
+                    &Aggregate::ALL,
+                )
+                .await
+                .unwrap()
+            }
+        };
+        let hourly_before = aggregate("1h").await;
+        let daily_before = aggregate("1d").await;
+
+        let policy = RetentionPolicy {
+            raw: Duration::days(1),
+            minute: Duration::days(2),
+            // the minute 11:59 is rolled up right after it ended
+            lag: Duration::zero(),
+            ..Default::default()
+        };
+        let now = to_utc_datetime("2024-01-03 12:00:30").unwrap();
+        let report = apply_retention(&pool, &policy, now).await.unwrap();
+        assert_eq!(report.raw_deleted, 4);
+        assert_eq!(report.minute_deleted, 2);
+        assert_eq!(report.hourly_deleted, 0);
+        assert_eq!(
+            list_sensordata(&pool, &[], QualityFilter::All)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(count_rows(&pool, "sensor_values_1m").await, 3);
+        assert_eq!(count_rows(&pool, "sensor_values_1h").await, 3);
+
+        // the tiers transparently replace the removed raw values
+        assert_eq!(aggregate("1h").await, hourly_before);
+        assert_eq!(aggregate("1d").await, daily_before);
+
+        // unaligned widths cannot use the tiers, so they only reach back to the removed values
+        let unaligned = |from: &'static str, width: &'static str| {
+            let pool = pool.clone();
+            async move {
+                aggregate_sensordata(
+                    &pool,
+                    &[],
+                    kind,
+                    &to_utc_datetime(from).unwrap(),
+                    &to_utc_datetime("2024-01-04 00:00:00").unwrap(),
+                    width.parse().unwrap(),
+                    &Aggregate::ALL,
+                )
+                .await
+            }
+        };
+        assert!(matches!(
+            unaligned("2024-01-01 00:00:00", "30s").await,
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(
+            unaligned("2024-01-01 00:00:00", "1m").await,
+            Err(Error::InvalidInput(_))
+        ));
+        assert_eq!(
+            unaligned("2024-01-02 12:00:00", "30s").await.unwrap().len(),
+            2
+        );
+        assert_eq!(
+            unaligned("2024-01-02 00:00:00", "1m").await.unwrap().len(),
+            3
+        );
+
+        // applying the policy again does not change anything
+        let report = apply_retention(&pool, &policy, now).await.unwrap();
+        assert_eq!(report, Default::default());
+        assert_eq!(aggregate("1h").await, hourly_before);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_hourly_retention(pool: SqlitePool) -> sqlx::Result<()> {
+        let kind = MeasurementKind::Temperature;
+        let timestamp = to_utc_datetime("2024-01-01 09:00:00").unwrap();
+        add_sensor_data(&pool, DEFAULT_SENSOR_ID, timestamp, kind, "°C", 1.)
+            .await
+            .unwrap();
+        let policy = RetentionPolicy {
+            raw: Duration::hours(1),
+            minute: Duration::hours(1),
+            hourly: Some(Duration::days(1)),
+            ..Default::default()
+        };
+        let now = to_utc_datetime("2024-01-01 12:00:00").unwrap();
+        apply_retention(&pool, &policy, now).await.unwrap();
+        assert_eq!(count_rows(&pool, "sensor_values_1h").await, 1);
+
+        let now = to_utc_datetime("2024-01-03 12:00:00").unwrap();
+        let report = apply_retention(&pool, &policy, now).await.unwrap();
+        assert_eq!(report.hourly_deleted, 1);
+        assert_eq!(count_rows(&pool, "sensor_values_1h").await, 0);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_late_values_are_rolled_up(pool: SqlitePool) -> sqlx::Result<()> {
+        let kind = MeasurementKind::Temperature;
+        let policy = RetentionPolicy::default();
+        let stored = |timestamp: &'static str, value: f64| {
+            let pool = pool.clone();
+            async move {
+                let timestamp = to_utc_datetime(timestamp).unwrap();
+                add_sensor_data(&pool, DEFAULT_SENSOR_ID, timestamp, kind, "°C", value)
+                    .await
+                    .unwrap();
+            }
+        };
+        stored("2024-01-01 09:59:00", 1.).await;
+        let now = to_utc_datetime("2024-01-01 10:00:10").unwrap();
+        apply_retention(&pool, &policy, now).await.unwrap();
+        // the value of 09:59:50 was stamped before the job ran, but stored after it
+        stored("2024-01-01 09:59:50", 3.).await;
+        let now = now + policy.lag;
+        apply_retention(&pool, &policy, now).await.unwrap();
+
+        let (count, sum): (i64, f64) = sqlx::query_as(
+            "SELECT count, sum FROM sensor_values_1m WHERE bucket_start = '2024-01-01T09:59:00+00:00'",
+        )
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!((count, sum), (2, 4.));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_backfilled_values_are_rolled_up(pool: SqlitePool) -> sqlx::Result<()> {
+        let kind = MeasurementKind::Temperature;
+        let value = |timestamp: &str, value: f64| NewSensorData {
+            message_id: Some(timestamp.to_string()),
+            ..NewSensorData::temperature(
+                DEFAULT_SENSOR_ID,
+                to_utc_datetime(timestamp).unwrap(),
+                value,
+            )
+        };
+        let store = |value: NewSensorData| {
+            let pool = pool.clone();
+            async move {
+                add_sensor_data_idempotent(&pool, &value, OnDuplicate::Replace)
+                    .await
+                    .unwrap();
+            }
+        };
+        let hourly = || {
+            let pool = pool.clone();
+            async move {
+                aggregate_sensordata(
+                    &pool,
+                    &[],
+                    kind,
+                    &to_utc_datetime("2024-01-01 00:00:00").unwrap(),
+                    &to_utc_datetime("2024-01-04 00:00:00").unwrap(),
+                    "1h".parse().unwrap(),
+                    &Aggregate::ALL,
+                )
+                .await
+                .unwrap()
+                .iter()
+                .map(|bucket| (bucket.bucket_start, bucket.avg, bucket.count))
+                .collect::<Vec<_>>()
+            }
+        };
+        let hour = |timestamp: &str| to_utc_datetime(timestamp).unwrap();
+
+        store(value("2024-01-01 09:00:00", 20.)).await;
+        store(value("2024-01-03 11:00:00", 20.)).await;
+        let policy = RetentionPolicy {
+            raw: Duration::days(1),
+            minute: Duration::days(2),
+            lag: Duration::zero(),
+            ..Default::default()
+        };
+        let now = to_utc_datetime("2024-01-03 12:00:00").unwrap();
+        apply_retention(&pool, &policy, now).await.unwrap();
+
+        // backfilled into an hour whose minutes were removed, into a minute whose raw values were
+        // removed and into a minute that is still kept raw
+        store(value("2024-01-01 09:30:00", 22.)).await;
+        store(value("2024-01-02 11:00:00", 24.)).await;
+        store(value("2024-01-02 13:00:00", 26.)).await;
+        // a rolled up value replaced by a retried message
+        store(value("2024-01-03 11:00:00", 21.)).await;
+        apply_retention(&pool, &policy, now).await.unwrap();
+
+        let expected = [
+            (hour("2024-01-01 09:00:00"), Some(21.), Some(2)),
+            (hour("2024-01-02 11:00:00"), Some(24.), Some(1)),
+            (hour("2024-01-02 13:00:00"), Some(26.), Some(1)),
+            (hour("2024-01-03 11:00:00"), Some(21.), Some(1)),
+        ];
+        assert_eq!(hourly().await, expected);
+        assert_eq!(
+            list_sensordata(&pool, &[], QualityFilter::All)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // the late values are added once
+        apply_retention(&pool, &policy, now).await.unwrap();
+        assert_eq!(hourly().await, expected);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+//! Database schema, the migrations in `migrations/` are embedded into the binaries.
+
+use sqlx::migrate::Migrator;
+use sqlx::SqlitePool;
+
+use crate::{DatabaseConfig, Error, Result};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Latest schema version known by this binary
+pub fn latest_schema_version() -> i64 {
+    MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Schema version of the database, `None` if no migration was applied yet
+pub async fn schema_version(pool: &SqlitePool) -> Result<Option<i64>> {
+    let migrations_table = sqlx::query_scalar::<_, String>(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+    )
+    .fetch_optional(pool)
+    .await?;
+    if migrations_table.is_none() {
+        return Ok(None);
+    }
+    let version = sqlx::query_scalar::<_, Option<i64>>(
+        "SELECT MAX(version) FROM _sqlx_migrations WHERE success",
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(version)
+}
+
+/// Applies all pending migrations, fails if the database was migrated by a newer binary
+pub async fn migrate(pool: &SqlitePool) -> Result<()> {
+    let latest = latest_schema_version();
+    if let Some(version) = schema_version(pool).await? {
+        if version > latest {
+            return Err(Error::MigrationMismatch(format!(
+                "database schema version {} is newer than the supported version {}, please update the binary",
+                version,
+                latest
+            )));
+        }
+    }
+    MIGRATOR.run(pool).await?;
+    Ok(())
+}
+
+/// Opens the database with the default [`DatabaseConfig`] (it is created if missing) and applies
+/// all pending migrations
+pub async fn connect_and_migrate(database_url: &str) -> Result<SqlitePool> {
+    DatabaseConfig::new(database_url)
+        .connect_and_migrate()
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::migrate::Migrator;
+    use sqlx::SqlitePool;
+
+    use super::{latest_schema_version, migrate, schema_version, MIGRATOR};
+    use crate::{list_sensordata, to_utc_datetime, Error, Quality, QualityFilter};
+
+    #[sqlx::test(migrations = false)]
+    async fn test_migrate(pool: SqlitePool) -> sqlx::Result<()> {
+        assert_eq!(schema_version(&pool).await.unwrap(), None);
+        migrate(&pool).await.unwrap();
+        let latest = latest_schema_version();
+        assert_eq!(schema_version(&pool).await.unwrap(), Some(latest));
+        // migrating again does nothing
+        migrate(&pool).await.unwrap();
+        assert_eq!(schema_version(&pool).await.unwrap(), Some(latest));
+
+        Ok(())
+    }
+
+    /// The schema before the migration `version`
+    fn migrator_before(version: i64) -> Migrator {
+        Migrator {
+            migrations: MIGRATOR
+                .iter()
+                .filter(|migration| migration.version < version)
+                .cloned()
+                .collect(),
+            ignore_missing: false,
+            locking: true,
+        }
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_migrate_naive_timestamps(pool: SqlitePool) -> sqlx::Result<()> {
+        // the schema before timestamps were stored with offset
+        migrator_before(5).run(&pool).await.unwrap();
+        sqlx::query(
+            r#"
+    INSERT INTO sensor_values (timestamp, value)
+    VALUES ('2024-01-01 09:00:00.000', 1), ('2024-01-01 09:00:00.5', 2), ('2024-01-01 08:59:59', 3)
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        migrate(&pool).await.unwrap();
+        let timestamps: Vec<String> =
+            sqlx::query_scalar("SELECT timestamp FROM sensor_values ORDER BY timestamp")
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(
+            timestamps,
+            [
+                "2024-01-01T08:59:59+00:00",
+                "2024-01-01T09:00:00+00:00",
+                "2024-01-01T09:00:00.5+00:00"
+            ]
+        );
+        let values = list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap();
+        assert_eq!(
+            values[1].timestamp,
+            to_utc_datetime("2024-01-01 09:00:00").unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_migrate_implausible_values(pool: SqlitePool) -> sqlx::Result<()> {
+        migrator_before(6).run(&pool).await.unwrap();
+        sqlx::query(
+            r#"
+    INSERT INTO sensor_values (timestamp, kind, unit, value)
+    VALUES ('2024-01-01T09:00:00+00:00', 'temperature', '°C', 20),
+           ('2024-01-01T09:01:00+00:00', 'temperature', '°C', -273),
+           ('2024-01-01T09:02:00+00:00', 'humidity', '%', 140)
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        migrate(&pool).await.unwrap();
+        let qualities: Vec<_> = list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|value| (value.quality, value.quality_reason))
+            .collect();
+        assert_eq!(
+            qualities,
+            [
+                (Quality::Good, None),
+                (
+                    Quality::Rejected,
+                    Some("outside of the plausible range -50.0 to 100.0".to_string())
+                ),
+                (
+                    Quality::Rejected,
+                    Some("outside of the plausible range 0.0 to 100.0".to_string())
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_newer_database(pool: SqlitePool) -> sqlx::Result<()> {
+        migrate(&pool).await.unwrap();
+        sqlx::query(
+            r#"
+    INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+    VALUES (9999, 'from the future', TRUE, x'00', 0)
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        let error = migrate(&pool).await.unwrap_err();
+        assert!(matches!(error, Error::MigrationMismatch(_)), "{:?}", error);
+        assert!(error.to_string().contains("newer"), "{}", error);
+
+        Ok(())
+    }
+}
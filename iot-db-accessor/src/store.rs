@@ -0,0 +1,1181 @@
+//! Storage abstraction over the sensor values.
+//!
+//! [`SensorStore`] is implemented for [`SqlitePool`] by delegating to the free functions of this
+//! crate and by [`MemoryStore`], which keeps everything in memory and needs no database file.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use futures::stream::{BoxStream, StreamExt};
+use sqlx::SqlitePool;
+
+use crate::calibration::calibration_at;
+use crate::gaps::GapDetector;
+use crate::pagination::check_limit;
+use crate::{
+    validate_sensor_value, Aggregate, AggregatedBucket, AnnotatedSensorData, Annotation,
+    Assessment, BucketWidth, Calibration, Cursor, Error, Gap, MeasurementKind, NewAnnotation,
+    NewCalibration, NewSensorData, OnDuplicate, Page, PlausibilityRule, Quality, QualityFilter,
+    Result, Sensor, SensorData, SensorDataFeed, SensorQuery, SortOrder, DEFAULT_SENSOR_ID,
+};
+
+/// Sensor values can be stored and queried, an empty `sensors` slice selects all sensors and
+/// `quality` selects the values by the result of their plausibility check
+#[async_trait]
+pub trait SensorStore: Send + Sync {
+    /// Returns the id of the sensor with the given name, the sensor is created if it does not exist
+    async fn register_sensor(&self, name: &str) -> Result<i64>;
+
+    async fn list_sensors(&self) -> Result<Vec<Sensor>>;
+
+    /// A value with the identity of a stored value is ignored, the id of the stored value is
+    /// returned, so retries never store a value twice
+    async fn add_sensor_data(&self, value: &NewSensorData) -> Result<i64>;
+
+    /// Inserts all values or none of them, duplicates are ignored like by `add_sensor_data`
+    async fn add_sensor_data_batch(&self, values: &[NewSensorData]) -> Result<Vec<i64>>;
+
+    /// All values ascending
+    async fn list_sensordata(
+        &self,
+        sensors: &[i64],
+        quality: QualityFilter,
+    ) -> Result<Vec<SensorData>>;
+
+    /// Up to `limit` values ascending by `(timestamp, id)`, starting after `after`
+    async fn list_sensordata_page(
+        &self,
+        sensors: &[i64],
+        after: Option<&Cursor>,
+        limit: u32,
+        quality: QualityFilter,
+    ) -> Result<Page<SensorData>>;
+
+    /// Values in `[from, to)` ascending
+    async fn list_sensordata_between(
+        &self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quality: QualityFilter,
+    ) -> Result<Vec<SensorData>>;
+
+    /// All values ascending, read while the stream is polled
+    fn stream_sensordata<'a>(
+        &'a self,
+        sensors: &[i64],
+        quality: QualityFilter,
+    ) -> BoxStream<'a, Result<SensorData>>;
+
+    /// Values in `[from, to)` ascending, read while the stream is polled
+    fn stream_sensordata_between<'a>(
+        &'a self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quality: QualityFilter,
+    ) -> BoxStream<'a, Result<SensorData>>;
+
+    /// The values selected by the query, in its order
+    async fn query_sensordata(&self, query: &SensorQuery) -> Result<Vec<SensorData>>;
+
+    /// The latest `rows` values after `since` descending
+    async fn list_last_values_descending_since(
+        &self,
+        sensors: &[i64],
+        since: &DateTime<Utc>,
+        rows: u32,
+        quality: QualityFilter,
+    ) -> Result<Vec<SensorData>>;
+
+    /// Values of one kind in `[from, to)` aggregated per bucket, rejected values are left out
+    async fn aggregate_sensordata(
+        &self,
+        sensors: &[i64],
+        kind: MeasurementKind,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        width: BucketWidth,
+        aggregates: &[Aggregate],
+    ) -> Result<Vec<AggregatedBucket>>;
+
+    /// Gaps longer than `threshold` in `[from, to)` ascending by start, where a value was due
+    /// every `interval`
+    async fn list_gaps(
+        &self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        interval: Duration,
+        threshold: Duration,
+    ) -> Result<Vec<Gap>>;
+
+    async fn add_annotation(&self, annotation: &NewAnnotation) -> Result<i64>;
+
+    async fn get_annotation(&self, id: i64) -> Result<Annotation>;
+
+    /// Replaces the content of the annotation
+    async fn update_annotation(&self, id: i64, annotation: &NewAnnotation) -> Result<()>;
+
+    async fn delete_annotation(&self, id: i64) -> Result<()>;
+
+    /// Annotations overlapping `[from, to)` ascending by start, annotations without sensor are
+    /// always listed
+    async fn list_annotations(
+        &self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> Result<Vec<Annotation>>;
+
+    /// Values are corrected by a calibration from its `valid_from` on, until the next calibration of
+    /// the sensor and kind is valid
+    async fn add_calibration(&self, calibration: &NewCalibration) -> Result<i64>;
+
+    async fn delete_calibration(&self, id: i64) -> Result<()>;
+
+    /// Calibrations ascending by `valid_from`
+    async fn list_calibrations(&self, sensors: &[i64]) -> Result<Vec<Calibration>>;
+
+    /// Rules ascending by kind
+    async fn list_plausibility_rules(&self) -> Result<Vec<PlausibilityRule>>;
+
+    /// Replaces the rule of the kind, values that are already stored keep their quality
+    async fn set_plausibility_rule(&self, rule: &PlausibilityRule) -> Result<()>;
+
+    /// Values in `[from, to)` ascending together with the annotations overlapping the range
+    async fn list_sensordata_between_annotated(
+        &self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quality: QualityFilter,
+    ) -> Result<AnnotatedSensorData> {
+        Ok(AnnotatedSensorData {
+            values: self
+                .list_sensordata_between(sensors, from, to, quality)
+                .await?,
+            annotations: self.list_annotations(sensors, from, to).await?,
+        })
+    }
+}
+
+#[async_trait]
+impl SensorStore for SqlitePool {
+    async fn register_sensor(&self, name: &str) -> Result<i64> {
+        crate::register_sensor(self, name).await
+    }
+
+    async fn list_sensors(&self) -> Result<Vec<Sensor>> {
+        crate::list_sensors(self).await
+    }
+
+    async fn add_sensor_data(&self, value: &NewSensorData) -> Result<i64> {
+        crate::add_sensor_data_idempotent(self, value, OnDuplicate::Ignore).await
+    }
+
+    async fn add_sensor_data_batch(&self, values: &[NewSensorData]) -> Result<Vec<i64>> {
+        crate::add_sensor_data_batch(self, values).await
+    }
+
+    async fn list_sensordata(
+        &self,
+        sensors: &[i64],
+        quality: QualityFilter,
+    ) -> Result<Vec<SensorData>> {
+        crate::list_sensordata(self, sensors, quality).await
+    }
+
+    async fn list_sensordata_page(
+        &self,
+        sensors: &[i64],
+        after: Option<&Cursor>,
+        limit: u32,
+        quality: QualityFilter,
+    ) -> Result<Page<SensorData>> {
+        crate::list_sensordata_page(self, sensors, after, limit, quality).await
+    }
+
+    async fn list_sensordata_between(
+        &self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quality: QualityFilter,
+    ) -> Result<Vec<SensorData>> {
+        crate::list_sensordata_between(self, sensors, from, to, quality).await
+    }
+
+    fn stream_sensordata<'a>(
+        &'a self,
+        sensors: &[i64],
+        quality: QualityFilter,
+    ) -> BoxStream<'a, Result<SensorData>> {
+        crate::stream_sensordata(self, sensors, quality)
+    }
+
+    fn stream_sensordata_between<'a>(
+        &'a self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quality: QualityFilter,
+    ) -> BoxStream<'a, Result<SensorData>> {
+        crate::stream_sensordata_between(self, sensors, from, to, quality)
+    }
+
+    async fn query_sensordata(&self, query: &SensorQuery) -> Result<Vec<SensorData>> {
+        query.fetch_all(self).await
+    }
+
+    async fn list_last_values_descending_since(
+        &self,
+        sensors: &[i64],
+        since: &DateTime<Utc>,
+        rows: u32,
+        quality: QualityFilter,
+    ) -> Result<Vec<SensorData>> {
+        crate::list_last_values_descending_since(self, sensors, since, rows, quality).await
+    }
+
+    async fn aggregate_sensordata(
+        &self,
+        sensors: &[i64],
+        kind: MeasurementKind,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        width: BucketWidth,
+        aggregates: &[Aggregate],
+    ) -> Result<Vec<AggregatedBucket>> {
+        crate::aggregate_sensordata(self, sensors, kind, from, to, width, aggregates).await
+    }
+
+    async fn list_gaps(
+        &self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        interval: Duration,
+        threshold: Duration,
+    ) -> Result<Vec<Gap>> {
+        crate::list_gaps(self, sensors, from, to, interval, threshold).await
+    }
+
+    async fn add_annotation(&self, annotation: &NewAnnotation) -> Result<i64> {
+        crate::add_annotation(self, annotation).await
+    }
+
+    async fn get_annotation(&self, id: i64) -> Result<Annotation> {
+        crate::get_annotation(self, id).await
+    }
+
+    async fn update_annotation(&self, id: i64, annotation: &NewAnnotation) -> Result<()> {
+        crate::update_annotation(self, id, annotation).await
+    }
+
+    async fn delete_annotation(&self, id: i64) -> Result<()> {
+        crate::delete_annotation(self, id).await
+    }
+
+    async fn list_annotations(
+        &self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> Result<Vec<Annotation>> {
+        crate::list_annotations(self, sensors, from, to).await
+    }
+
+    async fn add_calibration(&self, calibration: &NewCalibration) -> Result<i64> {
+        crate::add_calibration(self, calibration).await
+    }
+
+    async fn delete_calibration(&self, id: i64) -> Result<()> {
+        crate::delete_calibration(self, id).await
+    }
+
+    async fn list_calibrations(&self, sensors: &[i64]) -> Result<Vec<Calibration>> {
+        crate::list_calibrations(self, sensors).await
+    }
+
+    async fn list_plausibility_rules(&self) -> Result<Vec<PlausibilityRule>> {
+        crate::list_plausibility_rules(self).await
+    }
+
+    async fn set_plausibility_rule(&self, rule: &PlausibilityRule) -> Result<()> {
+        crate::set_plausibility_rule(self, rule).await
+    }
+}
+
+/// Keeps sensors and values in memory, e.g. for tests. Clones share the same data.
+#[derive(Debug, Clone)]
+pub struct MemoryStore {
+    inner: Arc<Mutex<MemoryStoreInner>>,
+    feed: SensorDataFeed,
+}
+
+#[derive(Debug)]
+struct MemoryStoreInner {
+    sensors: Vec<Sensor>,
+    // kept sorted by (timestamp, id), ids are assigned in insert order
+    values: Vec<SensorData>,
+    next_value_id: i64,
+    // ids of the values by their identities
+    message_ids: HashMap<(i64, String), i64>,
+    device_timestamps: HashMap<(i64, MeasurementKind, DateTime<Utc>), i64>,
+    // ascending by id
+    annotations: Vec<Annotation>,
+    next_annotation_id: i64,
+    // ascending by valid_from
+    calibrations: Vec<Calibration>,
+    next_calibration_id: i64,
+    // ascending by kind
+    rules: Vec<PlausibilityRule>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        // like the database, the store starts with the default sensor
+        let default_sensor = Sensor {
+            id: DEFAULT_SENSOR_ID,
+            name: "default".to_string(),
+        };
+        // and with the rules a new database starts with
+        let mut rules = MeasurementKind::ALL
+            .map(PlausibilityRule::default_for)
+            .to_vec();
+        rules.sort_by_key(|rule| rule.kind.as_str());
+        MemoryStore {
+            inner: Arc::new(Mutex::new(MemoryStoreInner {
+                sensors: vec![default_sensor],
+                values: Vec::new(),
+                next_value_id: 1,
+                message_ids: HashMap::new(),
+                device_timestamps: HashMap::new(),
+                annotations: Vec::new(),
+                next_annotation_id: 1,
+                calibrations: Vec::new(),
+                next_calibration_id: 1,
+                rules,
+            })),
+            feed: SensorDataFeed::new(),
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    /// Feed of the values inserted into this store
+    pub fn feed(&self) -> SensorDataFeed {
+        self.feed.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryStoreInner> {
+        self.inner.lock().expect("memory store lock is poisoned")
+    }
+
+    fn select(
+        &self,
+        sensors: &[i64],
+        quality: QualityFilter,
+        filter: impl Fn(&SensorData) -> bool,
+    ) -> Result<Vec<SensorData>> {
+        let inner = self.lock();
+        Ok(inner
+            .values
+            .iter()
+            .filter(|value| sensors.is_empty() || sensors.contains(&value.sensor_id))
+            .filter(|value| quality.accepts(value.quality))
+            .map(|value| inner.calibrated(value.clone()))
+            .filter(|value| filter(value))
+            .collect())
+    }
+
+    // the selected values are copied, the memory store is not meant for large data
+    fn select_stream(
+        &self,
+        sensors: &[i64],
+        quality: QualityFilter,
+        filter: impl Fn(&SensorData) -> bool,
+    ) -> BoxStream<'static, Result<SensorData>> {
+        match self.select(sensors, quality, filter) {
+            Ok(values) => futures::stream::iter(values.into_iter().map(Ok)).boxed(),
+            Err(error) => futures::stream::once(async { Err(error) }).boxed(),
+        }
+    }
+}
+
+impl MemoryStoreInner {
+    fn check(&self, value: &NewSensorData) -> Result<()> {
+        validate_sensor_value(value.kind, &value.unit, value.value)?;
+        self.check_sensor(value.sensor_id)
+    }
+
+    fn check_sensor(&self, sensor_id: i64) -> Result<()> {
+        if !self.sensors.iter().any(|sensor| sensor.id == sensor_id) {
+            return Err(Error::ConstraintViolation(format!(
+                "unknown sensor {}",
+                sensor_id
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_annotation(&self, annotation: &NewAnnotation) -> Result<()> {
+        annotation.validate()?;
+        match annotation.sensor_id {
+            Some(sensor_id) => self.check_sensor(sensor_id),
+            None => Ok(()),
+        }
+    }
+
+    fn annotation_position(&self, id: i64) -> Result<usize> {
+        self.annotations
+            .binary_search_by_key(&id, |annotation| annotation.id)
+            .map_err(|_| Error::NotFound(format!("annotation {}", id)))
+    }
+
+    /// The value corrected by the calibration valid at its timestamp
+    fn calibrated(&self, mut value: SensorData) -> SensorData {
+        if let Some(calibration) = calibration_at(
+            &self.calibrations,
+            value.sensor_id,
+            value.kind,
+            &value.unit,
+            &value.timestamp,
+        ) {
+            value.value = calibration.apply(value.value);
+        }
+        value
+    }
+
+    /// Id of the stored value with the same identity
+    fn duplicate_of(&self, value: &NewSensorData) -> Option<i64> {
+        let by_message_id = value
+            .message_id
+            .as_ref()
+            .and_then(|message_id| self.message_ids.get(&(value.sensor_id, message_id.clone())));
+        let by_device_timestamp = value.device_timestamp.and_then(|device_timestamp| {
+            self.device_timestamps
+                .get(&(value.sensor_id, value.kind, device_timestamp))
+        });
+        by_message_id.or(by_device_timestamp).copied()
+    }
+
+    fn insert(&mut self, value: &NewSensorData) -> SensorData {
+        let id = self.next_value_id;
+        self.next_value_id += 1;
+        if let Some(message_id) = &value.message_id {
+            self.message_ids
+                .insert((value.sensor_id, message_id.clone()), id);
+        }
+        if let Some(device_timestamp) = value.device_timestamp {
+            self.device_timestamps
+                .insert((value.sensor_id, value.kind, device_timestamp), id);
+        }
+        let position = self
+            .values
+            .partition_point(|stored| stored.timestamp <= value.timestamp);
+        let previous = self.values[..position]
+            .iter()
+            .rev()
+            .find(|stored| {
+                stored.sensor_id == value.sensor_id
+                    && stored.kind == value.kind
+                    && stored.timestamp < value.timestamp
+                    && stored.quality != Quality::Rejected
+            })
+            .map(|stored| {
+                let previous_value = stored.kind.to_default_unit(&stored.unit, stored.value);
+                (stored.timestamp, previous_value)
+            });
+        let assessment = self
+            .rules
+            .iter()
+            .find(|rule| rule.kind == value.kind)
+            .map_or_else(Assessment::good, |rule| {
+                rule.assess(&value.timestamp, &value.unit, value.value, previous)
+            });
+        let value = SensorData {
+            id,
+            sensor_id: value.sensor_id,
+            timestamp: value.timestamp,
+            kind: value.kind,
+            unit: value.unit.clone(),
+            value: value.value,
+            quality: assessment.quality,
+            quality_reason: assessment.reason,
+        };
+        self.values.insert(position, value.clone());
+        value
+    }
+}
+
+#[async_trait]
+impl SensorStore for MemoryStore {
+    async fn register_sensor(&self, name: &str) -> Result<i64> {
+        let mut inner = self.lock();
+        if let Some(sensor) = inner.sensors.iter().find(|sensor| sensor.name == name) {
+            return Ok(sensor.id);
+        }
+        let id = inner
+            .sensors
+            .iter()
+            .map(|sensor| sensor.id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        inner.sensors.push(Sensor {
+            id,
+            name: name.to_string(),
+        });
+        Ok(id)
+    }
+
+    async fn list_sensors(&self) -> Result<Vec<Sensor>> {
+        Ok(self.lock().sensors.clone())
+    }
+
+    async fn add_sensor_data(&self, value: &NewSensorData) -> Result<i64> {
+        let mut inner = self.lock();
+        inner.check(value)?;
+        if let Some(id) = inner.duplicate_of(value) {
+            return Ok(id);
+        }
+        let value = inner.insert(value);
+        let id = value.id;
+        self.feed.publish(inner.calibrated(value));
+        Ok(id)
+    }
+
+    async fn add_sensor_data_batch(&self, values: &[NewSensorData]) -> Result<Vec<i64>> {
+        let mut inner = self.lock();
+        for value in values {
+            inner.check(value)?;
+        }
+        let mut ids = Vec::with_capacity(values.len());
+        let mut inserted = Vec::new();
+        for value in values {
+            match inner.duplicate_of(value) {
+                Some(id) => ids.push(id),
+                None => {
+                    let value = inner.insert(value);
+                    ids.push(value.id);
+                    inserted.push(inner.calibrated(value));
+                }
+            }
+        }
+        for value in inserted {
+            self.feed.publish(value);
+        }
+        Ok(ids)
+    }
+
+    async fn list_sensordata(
+        &self,
+        sensors: &[i64],
+        quality: QualityFilter,
+    ) -> Result<Vec<SensorData>> {
+        self.select(sensors, quality, |_| true)
+    }
+
+    async fn list_sensordata_page(
+        &self,
+        sensors: &[i64],
+        after: Option<&Cursor>,
+        limit: u32,
+        quality: QualityFilter,
+    ) -> Result<Page<SensorData>> {
+        check_limit(limit)?;
+        let mut values = self.select(sensors, quality, |value| {
+            after.is_none_or(|cursor| cursor.is_before(value))
+        })?;
+        values.truncate(limit as usize + 1);
+        Ok(Page::from_rows(values, limit))
+    }
+
+    async fn list_sensordata_between(
+        &self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quality: QualityFilter,
+    ) -> Result<Vec<SensorData>> {
+        self.select(sensors, quality, |value| {
+            value.timestamp >= *from && value.timestamp < *to
+        })
+    }
+
+    fn stream_sensordata<'a>(
+        &'a self,
+        sensors: &[i64],
+        quality: QualityFilter,
+    ) -> BoxStream<'a, Result<SensorData>> {
+        self.select_stream(sensors, quality, |_| true)
+    }
+
+    fn stream_sensordata_between<'a>(
+        &'a self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quality: QualityFilter,
+    ) -> BoxStream<'a, Result<SensorData>> {
+        self.select_stream(sensors, quality, |value| {
+            value.timestamp >= *from && value.timestamp < *to
+        })
+    }
+
+    async fn query_sensordata(&self, query: &SensorQuery) -> Result<Vec<SensorData>> {
+        query.validate()?;
+        // the query filters by sensor and quality itself
+        let mut values = self.select(&[], QualityFilter::All, |value| query.matches(value))?;
+        if query.order == SortOrder::Descending {
+            values.reverse();
+        }
+        Ok(values
+            .into_iter()
+            .skip(query.offset as usize)
+            .take(query.limit.map_or(usize::MAX, |limit| limit as usize))
+            .collect())
+    }
+
+    async fn list_last_values_descending_since(
+        &self,
+        sensors: &[i64],
+        since: &DateTime<Utc>,
+        rows: u32,
+        quality: QualityFilter,
+    ) -> Result<Vec<SensorData>> {
+        let query = SensorQuery::new()
+            .sensors(sensors)
+            .after(*since)
+            .order(SortOrder::Descending)
+            .limit(rows)
+            .quality(quality);
+        self.query_sensordata(&query).await
+    }
+
+    async fn aggregate_sensordata(
+        &self,
+        sensors: &[i64],
+        kind: MeasurementKind,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        width: BucketWidth,
+        aggregates: &[Aggregate],
+    ) -> Result<Vec<AggregatedBucket>> {
+        let width = i64::from(width.seconds());
+        let values = self.select(sensors, QualityFilter::NotRejected, |value| {
+            value.kind == kind && value.timestamp >= *from && value.timestamp < *to
+        })?;
+
+        let mut buckets: BTreeMap<i64, Vec<f64>> = BTreeMap::new();
+        for value in values {
+            let bucket = value.timestamp.timestamp().div_euclid(width) * width;
+            // like the SQL query, units are converted to the default unit of the kind
+            buckets
+                .entry(bucket)
+                .or_default()
+                .push(kind.to_default_unit(&value.unit, value.value));
+        }
+        let requested = |aggregate| aggregates.contains(&aggregate);
+        buckets
+            .into_iter()
+            .map(|(bucket, values)| {
+                let bucket_start = DateTime::from_timestamp(bucket, 0)
+                    .ok_or_else(|| Error::invalid_input(format!("invalid bucket {}", bucket)))?;
+                let sum: f64 = values.iter().sum();
+                Ok(AggregatedBucket {
+                    bucket_start,
+                    min: requested(Aggregate::Min)
+                        .then(|| values.iter().copied().fold(f64::INFINITY, f64::min)),
+                    max: requested(Aggregate::Max)
+                        .then(|| values.iter().copied().fold(f64::NEG_INFINITY, f64::max)),
+                    avg: requested(Aggregate::Avg).then(|| sum / values.len() as f64),
+                    count: requested(Aggregate::Count).then_some(values.len() as i64),
+                })
+            })
+            .collect()
+    }
+
+    async fn list_gaps(
+        &self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        interval: Duration,
+        threshold: Duration,
+    ) -> Result<Vec<Gap>> {
+        let mut detector = GapDetector::new(sensors, from, to, interval, threshold)?;
+        let mut values = self.select(sensors, QualityFilter::All, |value| {
+            value.timestamp >= *from && value.timestamp < *to
+        })?;
+        values.sort_by_key(|value| (value.sensor_id, value.timestamp));
+        for value in values {
+            detector.push(value.sensor_id, value.timestamp);
+        }
+        Ok(detector.finish())
+    }
+
+    async fn add_annotation(&self, annotation: &NewAnnotation) -> Result<i64> {
+        let mut inner = self.lock();
+        inner.check_annotation(annotation)?;
+        let id = inner.next_annotation_id;
+        inner.next_annotation_id += 1;
+        inner
+            .annotations
+            .push(annotation.clone().into_annotation(id));
+        Ok(id)
+    }
+
+    async fn get_annotation(&self, id: i64) -> Result<Annotation> {
+        let inner = self.lock();
+        let position = inner.annotation_position(id)?;
+        Ok(inner.annotations[position].clone())
+    }
+
+    async fn update_annotation(&self, id: i64, annotation: &NewAnnotation) -> Result<()> {
+        let mut inner = self.lock();
+        let position = inner.annotation_position(id)?;
+        inner.check_annotation(annotation)?;
+        inner.annotations[position] = annotation.clone().into_annotation(id);
+        Ok(())
+    }
+
+    async fn delete_annotation(&self, id: i64) -> Result<()> {
+        let mut inner = self.lock();
+        let position = inner.annotation_position(id)?;
+        inner.annotations.remove(position);
+        Ok(())
+    }
+
+    async fn list_annotations(
+        &self,
+        sensors: &[i64],
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> Result<Vec<Annotation>> {
+        let mut annotations: Vec<_> = self
+            .lock()
+            .annotations
+            .iter()
+            .filter(|annotation| {
+                annotation.overlaps(from, to)
+                    && annotation
+                        .sensor_id
+                        .is_none_or(|sensor_id| sensors.is_empty() || sensors.contains(&sensor_id))
+            })
+            .cloned()
+            .collect();
+        annotations.sort_by_key(|annotation| (annotation.starts_at, annotation.id));
+        Ok(annotations)
+    }
+
+    async fn add_calibration(&self, calibration: &NewCalibration) -> Result<i64> {
+        let mut inner = self.lock();
+        calibration.validate()?;
+        inner.check_sensor(calibration.sensor_id)?;
+        if inner.calibrations.iter().any(|stored| {
+            stored.sensor_id == calibration.sensor_id
+                && stored.kind == calibration.kind
+                && stored.unit == calibration.unit
+                && stored.valid_from == calibration.valid_from
+        }) {
+            return Err(Error::ConstraintViolation(format!(
+                "sensor {} already has a {} calibration in {} valid from {}",
+                calibration.sensor_id, calibration.kind, calibration.unit, calibration.valid_from
+            )));
+        }
+        let id = inner.next_calibration_id;
+        inner.next_calibration_id += 1;
+        let position = inner
+            .calibrations
+            .partition_point(|stored| stored.valid_from <= calibration.valid_from);
+        inner
+            .calibrations
+            .insert(position, calibration.clone().into_calibration(id));
+        Ok(id)
+    }
+
+    async fn delete_calibration(&self, id: i64) -> Result<()> {
+        let mut inner = self.lock();
+        let position = inner
+            .calibrations
+            .iter()
+            .position(|calibration| calibration.id == id)
+            .ok_or_else(|| Error::NotFound(format!("calibration {}", id)))?;
+        inner.calibrations.remove(position);
+        Ok(())
+    }
+
+    async fn list_calibrations(&self, sensors: &[i64]) -> Result<Vec<Calibration>> {
+        Ok(self
+            .lock()
+            .calibrations
+            .iter()
+            .filter(|calibration| sensors.is_empty() || sensors.contains(&calibration.sensor_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn list_plausibility_rules(&self) -> Result<Vec<PlausibilityRule>> {
+        Ok(self.lock().rules.clone())
+    }
+
+    async fn set_plausibility_rule(&self, rule: &PlausibilityRule) -> Result<()> {
+        rule.validate()?;
+        let mut inner = self.lock();
+        inner.rules.retain(|stored| stored.kind != rule.kind);
+        let position = inner
+            .rules
+            .partition_point(|stored| stored.kind.as_str() < rule.kind.as_str());
+        inner.rules.insert(position, rule.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use futures::TryStreamExt;
+    use sqlx::SqlitePool;
+
+    use super::{MemoryStore, SensorStore};
+    use crate::{
+        to_utc_datetime, Aggregate, Error, MeasurementKind, NewAnnotation, NewCalibration,
+        NewSensorData, PlausibilityRule, Quality, QualityFilter, SensorQuery, SortOrder,
+    };
+
+    fn new_value(sensor_id: i64, timestamp: &str, value: f64) -> NewSensorData {
+        NewSensorData::temperature(sensor_id, to_utc_datetime(timestamp).unwrap(), value)
+    }
+
+    // both backends have to answer the same queries with the same results
+    async fn check_store(store: &impl SensorStore) {
+        let first = store.register_sensor("picow-1").await.unwrap();
+        let second = store.register_sensor("picow-2").await.unwrap();
+        assert_eq!(store.register_sensor("picow-1").await.unwrap(), first);
+        assert_eq!(store.list_sensors().await.unwrap().len(), 3);
+
+        store
+            .add_sensor_data(&new_value(first, "2024-01-01 09:30:00", 11.))
+            .await
+            .unwrap();
+        let values = [
+            new_value(first, "2024-01-01 09:00:00", 10.),
+            new_value(second, "2024-01-01 10:00:00", 20.),
+            new_value(first, "2024-01-01 11:00:00", 12.),
+        ];
+        assert_eq!(store.add_sensor_data_batch(&values).await.unwrap().len(), 3);
+        let invalid = [
+            new_value(first, "2024-01-01 12:00:00", 13.),
+            new_value(42, "2024-01-01 12:00:00", 13.),
+        ];
+        assert!(store.add_sensor_data_batch(&invalid).await.is_err());
+
+        let values: Vec<_> = store
+            .list_sensordata(&[], QualityFilter::All)
+            .await
+            .unwrap()
+            .iter()
+            .map(|value| value.value)
+            .collect();
+        assert_eq!(values, [10., 11., 20., 12.]);
+        assert_eq!(
+            store
+                .list_sensordata(&[second], QualityFilter::All)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let page = store
+            .list_sensordata_page(&[], None, 3, QualityFilter::All)
+            .await
+            .unwrap();
+        let paged: Vec<_> = page.items.iter().map(|value| value.value).collect();
+        assert_eq!(paged, [10., 11., 20.]);
+        let next_cursor = page.next_cursor.unwrap();
+        let page = store
+            .list_sensordata_page(&[], Some(&next_cursor), 3, QualityFilter::All)
+            .await
+            .unwrap();
+        let paged: Vec<_> = page.items.iter().map(|value| value.value).collect();
+        assert_eq!(paged, [12.]);
+        assert_eq!(page.next_cursor, None);
+
+        let between = store
+            .list_sensordata_between(
+                &[first],
+                &to_utc_datetime("2024-01-01 09:30:00").unwrap(),
+                &to_utc_datetime("2024-01-01 11:00:00").unwrap(),
+                QualityFilter::All,
+            )
+            .await
+            .unwrap();
+        assert_eq!(between.len(), 1);
+        assert_eq!(between[0].value, 11.);
+        let streamed: Vec<_> = store
+            .stream_sensordata_between(
+                &[first],
+                &to_utc_datetime("2024-01-01 09:30:00").unwrap(),
+                &to_utc_datetime("2024-01-01 11:00:00").unwrap(),
+                QualityFilter::All,
+            )
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(streamed.len(), 1);
+        assert_eq!(streamed[0].value, 11.);
+        let streamed: Vec<_> = store
+            .stream_sensordata(&[], QualityFilter::All)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(streamed.len(), 4);
+
+        let latest = store
+            .list_last_values_descending_since(
+                &[],
+                &to_utc_datetime("2024-01-01 09:00:00").unwrap(),
+                2,
+                QualityFilter::All,
+            )
+            .await
+            .unwrap();
+        let latest: Vec<_> = latest.iter().map(|value| value.value).collect();
+        assert_eq!(latest, [12., 20.]);
+        let query = SensorQuery::new()
+            .sensors(&[first])
+            .from(to_utc_datetime("2024-01-01 09:30:00").unwrap())
+            .min_value(11.)
+            .quality(QualityFilter::All)
+            .order(SortOrder::Descending);
+        let queried = store.query_sensordata(&query).await.unwrap();
+        let queried: Vec<_> = queried.iter().map(|value| value.value).collect();
+        assert_eq!(queried, [12., 11.]);
+        let queried = store
+            .query_sensordata(&query.offset(1).limit(1))
+            .await
+            .unwrap();
+        assert_eq!(queried.len(), 1);
+        assert_eq!(queried[0].value, 11.);
+        assert!(store
+            .query_sensordata(&SensorQuery::new().limit(0))
+            .await
+            .is_err());
+
+        let buckets = store
+            .aggregate_sensordata(
+                &[first],
+                MeasurementKind::Temperature,
+                &to_utc_datetime("2024-01-01 00:00:00").unwrap(),
+                &to_utc_datetime("2024-01-02 00:00:00").unwrap(),
+                "1h".parse().unwrap(),
+                &[Aggregate::Avg, Aggregate::Count],
+            )
+            .await
+            .unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(
+            buckets[0].bucket_start,
+            to_utc_datetime("2024-01-01 09:00:00").unwrap()
+        );
+        assert_eq!(buckets[0].avg, Some(10.5));
+        assert_eq!(buckets[0].count, Some(2));
+        assert_eq!(buckets[0].min, None);
+
+        let annotation = NewAnnotation {
+            sensor_id: Some(second),
+            starts_at: to_utc_datetime("2024-01-01 09:45:00").unwrap(),
+            ends_at: to_utc_datetime("2024-01-01 10:15:00").unwrap(),
+            text: "sensor relocated".to_string(),
+            tags: vec!["relocation".to_string()],
+        };
+        let id = store.add_annotation(&annotation).await.unwrap();
+        let unknown_sensor = NewAnnotation {
+            sensor_id: Some(42),
+            ..annotation.clone()
+        };
+        assert!(store.add_annotation(&unknown_sensor).await.is_err());
+        let annotated = store
+            .list_sensordata_between_annotated(
+                &[second],
+                &to_utc_datetime("2024-01-01 10:00:00").unwrap(),
+                &to_utc_datetime("2024-01-01 11:00:00").unwrap(),
+                QualityFilter::All,
+            )
+            .await
+            .unwrap();
+        assert_eq!(annotated.values.len(), 1);
+        assert_eq!(annotated.annotations.len(), 1);
+        assert_eq!(annotated.annotations[0].text, "sensor relocated");
+        let other_sensor = store
+            .list_annotations(
+                &[first],
+                &to_utc_datetime("2024-01-01 00:00:00").unwrap(),
+                &to_utc_datetime("2024-01-02 00:00:00").unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(other_sensor.is_empty());
+
+        let changed = NewAnnotation {
+            text: "sensor moved back".to_string(),
+            ..annotation
+        };
+        store.update_annotation(id, &changed).await.unwrap();
+        assert_eq!(
+            store.get_annotation(id).await.unwrap().text,
+            "sensor moved back"
+        );
+        store.delete_annotation(id).await.unwrap();
+        assert!(matches!(
+            store.get_annotation(id).await,
+            Err(Error::NotFound(_))
+        ));
+        assert!(store.delete_annotation(id).await.is_err());
+
+        // second reads 2 °C too high since 10:00
+        let calibration = NewCalibration {
+            sensor_id: second,
+            kind: MeasurementKind::Temperature,
+            unit: "°C".to_string(),
+            valid_from: to_utc_datetime("2024-01-01 10:00:00").unwrap(),
+            offset: -2.,
+            gain: 1.,
+        };
+        let calibration_id = store.add_calibration(&calibration).await.unwrap();
+        assert!(store.add_calibration(&calibration).await.is_err());
+        let calibrated: Vec<_> = store
+            .list_sensordata(&[], QualityFilter::All)
+            .await
+            .unwrap()
+            .iter()
+            .map(|value| value.value)
+            .collect();
+        assert_eq!(calibrated, [10., 11., 18., 12.]);
+        assert_eq!(store.list_calibrations(&[first]).await.unwrap(), []);
+        store.delete_calibration(calibration_id).await.unwrap();
+        assert!(matches!(
+            store.delete_calibration(calibration_id).await,
+            Err(Error::NotFound(_))
+        ));
+
+        // first has no value between 09:30 and 11:00, second none before 10:00
+        let gaps = store
+            .list_gaps(
+                &[first, second],
+                &to_utc_datetime("2024-01-01 09:00:00").unwrap(),
+                &to_utc_datetime("2024-01-01 11:00:00").unwrap(),
+                Duration::minutes(1),
+                Duration::minutes(30),
+            )
+            .await
+            .unwrap();
+        let gaps: Vec<_> = gaps
+            .iter()
+            .map(|gap| (gap.sensor_id, gap.duration().num_minutes()))
+            .collect();
+        assert_eq!(gaps, [(second, 59), (first, 89), (second, 59)]);
+
+        // retried values are stored once
+        let mut retried = new_value(second, "2024-01-01 12:00:00", 21.);
+        retried.message_id = Some("42".to_string());
+        let id = store.add_sensor_data(&retried).await.unwrap();
+        assert_eq!(store.add_sensor_data(&retried).await.unwrap(), id);
+        let mut replayed = new_value(second, "2024-01-01 12:00:01", 21.);
+        replayed.device_timestamp = Some(to_utc_datetime("2024-01-01 11:59:59").unwrap());
+        let ids = store
+            .add_sensor_data_batch(&[replayed.clone(), retried, replayed])
+            .await
+            .unwrap();
+        assert_eq!(ids[1], id);
+        assert_eq!(ids[0], ids[2]);
+        let stored = store
+            .list_sensordata(&[second], QualityFilter::All)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 3);
+
+        // values in °F are aggregated in °C
+        let mut fahrenheit = new_value(second, "2024-01-01 13:05:00", 77.);
+        fahrenheit.unit = "°F".to_string();
+        store
+            .add_sensor_data_batch(&[new_value(second, "2024-01-01 13:00:00", 20.), fahrenheit])
+            .await
+            .unwrap();
+        let buckets = store
+            .aggregate_sensordata(
+                &[second],
+                MeasurementKind::Temperature,
+                &to_utc_datetime("2024-01-01 13:00:00").unwrap(),
+                &to_utc_datetime("2024-01-01 14:00:00").unwrap(),
+                "1h".parse().unwrap(),
+                &Aggregate::ALL,
+            )
+            .await
+            .unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].min, Some(20.));
+        assert_eq!(buckets[0].max, Some(25.));
+        assert_eq!(buckets[0].avg, Some(22.5));
+
+        // both start with the rules of a new database, values are checked with the changed ones
+        let rules = store.list_plausibility_rules().await.unwrap();
+        let kinds: Vec<_> = rules.iter().map(|rule| rule.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                MeasurementKind::Humidity,
+                MeasurementKind::Pressure,
+                MeasurementKind::Temperature,
+                MeasurementKind::Voltage
+            ]
+        );
+        assert!(rules
+            .iter()
+            .all(|rule| *rule == PlausibilityRule::default_for(rule.kind)));
+        let rule = PlausibilityRule {
+            max: Some(30.),
+            ..PlausibilityRule::default_for(MeasurementKind::Temperature)
+        };
+        store.set_plausibility_rule(&rule).await.unwrap();
+        let id = store
+            .add_sensor_data(&new_value(second, "2024-01-01 14:00:00", 35.))
+            .await
+            .unwrap();
+        let stored = store
+            .list_sensordata(&[second], QualityFilter::All)
+            .await
+            .unwrap();
+        let stored = stored.iter().find(|value| value.id == id).unwrap();
+        assert_eq!(stored.quality, Quality::Rejected);
+        let invalid = PlausibilityRule {
+            min: Some(40.),
+            ..rule
+        };
+        assert!(matches!(
+            store.set_plausibility_rule(&invalid).await,
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_store(pool: SqlitePool) -> sqlx::Result<()> {
+        check_store(&pool).await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        check_store(&MemoryStore::new()).await;
+    }
+}
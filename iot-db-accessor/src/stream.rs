@@ -0,0 +1,135 @@
+//! Streaming variants of the list functions.
+//!
+//! The rows are decoded while they are read from the database, so memory stays flat no matter how
+//! many values are selected. The queries are built at runtime because the streams own their
+//! parameters, the compile time checked macros only borrow them.
+
+use chrono::{DateTime, Utc};
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use sqlx::SqlitePool;
+
+use crate::quality::quality_filter;
+use crate::{sensor_filter, Error, QualityFilter, Result, SensorData};
+
+/// Streams the values of the given sensors ascending, an empty slice streams the values of all sensors
+pub fn stream_sensordata<'a>(
+    pool: &'a SqlitePool,
+    sensors: &[i64],
+    quality: QualityFilter,
+) -> BoxStream<'a, Result<SensorData>> {
+    let (sensors, quality) = match filters(sensors, quality) {
+        Ok(filters) => filters,
+        Err(error) => return futures::stream::once(async { Err(error) }).boxed(),
+    };
+    sqlx::query_as::<_, SensorData>(
+        r#"
+    SELECT id, sensor_id, timestamp, kind, unit, value, quality, quality_reason
+    FROM calibrated_sensor_values
+    WHERE ($1 = '[]' OR sensor_id IN (SELECT value FROM json_each($1)))
+      AND quality IN (SELECT value FROM json_each($2))
+    ORDER BY timestamp, id
+    "#,
+    )
+    .bind(sensors)
+    .bind(quality)
+    .fetch(pool)
+    .map_err(Error::from)
+    .boxed()
+}
+
+/// Streams the values of the given sensors in `[from, to)` ascending, an empty slice streams the
+/// values of all sensors
+pub fn stream_sensordata_between<'a>(
+    pool: &'a SqlitePool,
+    sensors: &[i64],
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    quality: QualityFilter,
+) -> BoxStream<'a, Result<SensorData>> {
+    let (sensors, quality) = match filters(sensors, quality) {
+        Ok(filters) => filters,
+        Err(error) => return futures::stream::once(async { Err(error) }).boxed(),
+    };
+    sqlx::query_as::<_, SensorData>(
+        r#"
+    SELECT id, sensor_id, timestamp, kind, unit, value, quality, quality_reason
+    FROM calibrated_sensor_values
+    WHERE timestamp >= $1 AND timestamp < $2
+      AND ($3 = '[]' OR sensor_id IN (SELECT value FROM json_each($3)))
+      AND quality IN (SELECT value FROM json_each($4))
+    ORDER BY timestamp, id
+    "#,
+    )
+    .bind(*from)
+    .bind(*to)
+    .bind(sensors)
+    .bind(quality)
+    .fetch(pool)
+    .map_err(Error::from)
+    .boxed()
+}
+
+fn filters(sensors: &[i64], quality: QualityFilter) -> Result<(String, String)> {
+    Ok((sensor_filter(sensors)?, quality_filter(quality)?))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::TryStreamExt;
+    use sqlx::SqlitePool;
+
+    use super::{stream_sensordata, stream_sensordata_between};
+    use crate::{
+        add_sensor_data_batch, list_sensordata, register_sensor, to_utc_datetime, NewSensorData,
+        QualityFilter,
+    };
+
+    #[sqlx::test]
+    async fn test_stream(pool: SqlitePool) -> sqlx::Result<()> {
+        let first = register_sensor(&pool, "picow-1").await.unwrap();
+        let second = register_sensor(&pool, "picow-2").await.unwrap();
+        let start = to_utc_datetime("2024-01-01 00:00:00").unwrap();
+        let values: Vec<_> = (0..1000)
+            .map(|i| {
+                let sensor_id = if i % 2 == 0 { first } else { second };
+                NewSensorData::temperature(
+                    sensor_id,
+                    start + chrono::Duration::minutes(i),
+                    i as f64,
+                )
+            })
+            .collect();
+        add_sensor_data_batch(&pool, &values).await.unwrap();
+
+        let streamed: Vec<_> = stream_sensordata(&pool, &[], QualityFilter::All)
+            .try_collect()
+            .await
+            .unwrap();
+        let listed = list_sensordata(&pool, &[], QualityFilter::All)
+            .await
+            .unwrap();
+        assert_eq!(streamed.len(), 1000);
+        assert!(streamed
+            .iter()
+            .zip(&listed)
+            .all(|(streamed, listed)| streamed.id == listed.id));
+
+        let streamed: Vec<_> = stream_sensordata(&pool, &[second], QualityFilter::All)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(streamed.len(), 500);
+        assert!(streamed.iter().all(|value| value.sensor_id == second));
+
+        let to = start + chrono::Duration::minutes(10);
+        let streamed: Vec<_> =
+            stream_sensordata_between(&pool, &[first], &start, &to, QualityFilter::All)
+                .try_collect()
+                .await
+                .unwrap();
+        let streamed: Vec<_> = streamed.iter().map(|value| value.value).collect();
+        assert_eq!(streamed, [0., 2., 4., 6., 8.]);
+
+        Ok(())
+    }
+}
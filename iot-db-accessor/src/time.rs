@@ -0,0 +1,129 @@
+//! Timestamps are stored and passed around as `DateTime<Utc>`.
+//!
+//! A display timezone is only used to render timestamps for humans and to interpret input
+//! without an offset, e.g. `2024-03-31 02:30:00` typed by a user in Europe.
+
+use std::env;
+
+use chrono::{DateTime, LocalResult, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::{Error, Result};
+
+/// Formats accepted for timestamps without an offset
+const NAIVE_FORMATS: [&str; 2] = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+
+/// Parses an IANA timezone name like `Europe/Berlin` or `UTC`
+pub fn parse_timezone(name: &str) -> Result<Tz> {
+    name.parse().map_err(|_| {
+        Error::invalid_input(format!(
+            "unknown timezone '{}', expected e.g. 'UTC' or 'Europe/Berlin'",
+            name
+        ))
+    })
+}
+
+/// Reads the display timezone from `IOT_DISPLAY_TIMEZONE`, UTC if it is not set
+pub fn display_timezone_from_env() -> Result<Tz> {
+    match env::var("IOT_DISPLAY_TIMEZONE") {
+        Ok(name) => parse_timezone(&name),
+        Err(_) => Ok(Tz::UTC),
+    }
+}
+
+/// Parses an RFC 3339 timestamp like `2024-01-01T09:00:00+01:00`, or a timestamp without offset
+/// like `2024-01-01 09:00:00` which is interpreted in `timezone`.
+/// Local times that are skipped or repeated by a daylight saving transition are rejected.
+pub fn parse_timestamp(input: &str, timezone: &Tz) -> Result<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(input) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    let naive = NAIVE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(input, format).ok())
+        .ok_or_else(|| {
+            Error::invalid_input(format!(
+                "invalid timestamp '{}', expected e.g. '2024-01-01 09:00:00' or '2024-01-01T09:00:00+01:00'",
+                input
+            ))
+        })?;
+    match timezone.from_local_datetime(&naive) {
+        LocalResult::Single(timestamp) => Ok(timestamp.with_timezone(&Utc)),
+        LocalResult::Ambiguous(_, _) => Err(Error::invalid_input(format!(
+            "timestamp '{}' is ambiguous in {}, add an offset like '+01:00'",
+            input, timezone
+        ))),
+        LocalResult::None => Err(Error::invalid_input(format!(
+            "timestamp '{}' does not exist in {}",
+            input, timezone
+        ))),
+    }
+}
+
+/// Parses a timestamp like [`parse_timestamp`], a timestamp without offset is in UTC
+pub fn to_utc_datetime(input: &str) -> Result<DateTime<Utc>> {
+    parse_timestamp(input, &Tz::UTC)
+}
+
+/// Renders the timestamp as RFC 3339 with the offset of `timezone`
+pub fn format_timestamp(timestamp: &DateTime<Utc>, timezone: &Tz) -> String {
+    timestamp
+        .with_timezone(timezone)
+        .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono_tz::Tz;
+
+    use super::{format_timestamp, parse_timestamp, parse_timezone, to_utc_datetime};
+
+    #[test]
+    fn test_parse_timestamp() {
+        let berlin = parse_timezone("Europe/Berlin").unwrap();
+        let utc = to_utc_datetime("2024-01-01 08:00:00").unwrap();
+        assert_eq!(
+            parse_timestamp("2024-01-01 09:00:00", &berlin).unwrap(),
+            utc
+        );
+        assert_eq!(
+            parse_timestamp("2024-01-01T09:00:00", &berlin).unwrap(),
+            utc
+        );
+        // an explicit offset wins over the timezone
+        assert_eq!(
+            parse_timestamp("2024-01-01T08:00:00Z", &berlin).unwrap(),
+            utc
+        );
+        assert_eq!(
+            parse_timestamp("2024-01-01T10:00:00+02:00", &Tz::UTC).unwrap(),
+            utc
+        );
+        // summer time
+        assert_eq!(
+            parse_timestamp("2024-07-01 10:00:00", &berlin).unwrap(),
+            to_utc_datetime("2024-07-01 08:00:00").unwrap()
+        );
+
+        // skipped and repeated by the daylight saving transitions
+        assert!(parse_timestamp("2024-03-31 02:30:00", &berlin).is_err());
+        assert!(parse_timestamp("2024-10-27 02:30:00", &berlin).is_err());
+        assert!(parse_timestamp("2024-10-27T02:30:00+01:00", &berlin).is_ok());
+        assert!(parse_timestamp("yesterday", &berlin).is_err());
+        assert!(parse_timezone("Mars/Olympus_Mons").is_err());
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        let berlin = parse_timezone("Europe/Berlin").unwrap();
+        let timestamp = to_utc_datetime("2024-01-01 08:00:00").unwrap();
+        assert_eq!(
+            format_timestamp(&timestamp, &berlin),
+            "2024-01-01T09:00:00+01:00"
+        );
+        assert_eq!(
+            format_timestamp(&timestamp, &Tz::UTC),
+            "2024-01-01T08:00:00Z"
+        );
+    }
+}
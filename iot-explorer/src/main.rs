@@ -1,14 +1,21 @@
 use dotenvy::dotenv;
-use std::env;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Duration, Utc};
 use clap::{Parser, Subcommand};
+use futures::{StreamExt, TryStreamExt};
 use iot_db_accessor::{
-    add_sensor_data, get_date_with_default, list_last_values_descending_since, list_sensordata,
-    to_naivedatetime,
+    add_annotation, add_calibration, add_sensor_data, backup_database, display_timezone_from_env,
+    format_timestamp, get_date_with_default, get_end_date_with_default, list_annotations,
+    list_calibrations, list_gaps, list_sensordata_page, list_sensors, parse_duration,
+    parse_timestamp, parse_timezone, restore_database, stream_sensordata,
+    stream_sensordata_between, to_utc_datetime, Annotation, Calibration, DatabaseConfig,
+    MeasurementKind, NewAnnotation, NewCalibration, QualityFilter, SensorData, SensorDataFeed,
+    SensorQuery, SortOrder, Tz, DEFAULT_SENSOR_ID,
 };
-use sqlx::{Pool, Sqlite, SqlitePool};
-use tokio::time::sleep;
+use sqlx::{Pool, Sqlite};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -16,88 +23,516 @@ use tokio::time::sleep;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// timezone to show timestamps in and to read timestamps without offset in, e.g.
+    /// "Europe/Berlin", defaults to IOT_DISPLAY_TIMEZONE or UTC
+    #[clap(long, global = true)]
+    timezone: Option<String>,
+    /// which values to list depending on their plausibility check: all, not-rejected or good
+    #[clap(long, global = true, default_value_t = QualityFilter::NotRejected)]
+    quality: QualityFilter,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Add a sensor value to the database with timestamp from now
-    Add { value: f64 },
+    Add {
+        value: f64,
+        /// id of the sensor the value belongs to
+        #[clap(long, short, default_value_t = DEFAULT_SENSOR_ID)]
+        sensor: i64,
+        /// what was measured: temperature, humidity, pressure or voltage
+        #[clap(long, short, default_value_t = MeasurementKind::Temperature)]
+        kind: MeasurementKind,
+        /// unit of the value, defaults to the default unit of the kind (e.g. °C)
+        #[clap(long, short)]
+        unit: Option<String>,
+    },
     /// List all Sensor values ascending
-    All,
+    All {
+        /// only list values of this sensor id, can be repeated
+        #[clap(long = "sensor", short)]
+        sensors: Vec<i64>,
+        /// values loaded from the database at once
+        #[clap(long, default_value = "1000")]
+        page_size: u32,
+    },
+    /// Write Sensor values ascending as CSV to stdout, without loading them all at once
+    Export {
+        /// only export values of this sensor id, can be repeated
+        #[clap(long = "sensor", short)]
+        sensors: Vec<i64>,
+        /// only export values since the date, example: "2024-01-01 00:00:00"
+        #[clap(long)]
+        from: Option<String>,
+        /// only export values before the date, defaults to now if `--from` is given
+        #[clap(long)]
+        to: Option<String>,
+    },
     /// List latest Sensor values descending
     Last {
+        /// only list values of this sensor id, can be repeated
+        #[clap(long = "sensor", short)]
+        sensors: Vec<i64>,
         /// follow up and list new values, does not exit the program
         #[clap(long, short, action)]
         follow: bool,
         /// output the last NUM rows, instead of teht last 10
         #[clap(long, short = 'n', default_value = "10")]
         rows: u32,
-        /// show sensor values since the date in format, example: "2024-01-01 00:00:00"
-        #[arg(value_parser = parse_duration)]
-        since: Option<NaiveDateTime>,
+        /// show sensor values since the date in format, example: "2024-01-01 00:00:00" or
+        /// "2024-01-01T00:00:00+01:00"
+        since: Option<String>,
+    },
+    /// List the Sensor values matching all given filters
+    Query {
+        /// only list values of this sensor id, can be repeated
+        #[clap(long = "sensor", short)]
+        sensors: Vec<i64>,
+        /// only list values since the date, example: "2024-01-01 00:00:00"
+        #[clap(long)]
+        from: Option<String>,
+        /// only list values before the date
+        #[clap(long)]
+        to: Option<String>,
+        /// only list values of this kind: temperature, humidity, pressure or voltage
+        #[clap(long, short)]
+        kind: Option<MeasurementKind>,
+        /// only list values of at least this value, after the calibration
+        #[clap(long, allow_negative_numbers = true)]
+        min: Option<f64>,
+        /// only list values of at most this value, after the calibration
+        #[clap(long, allow_negative_numbers = true)]
+        max: Option<f64>,
+        /// ascending or descending by timestamp
+        #[clap(long, default_value_t = SortOrder::Ascending)]
+        order: SortOrder,
+        /// list at most NUM values
+        #[clap(long, short = 'n')]
+        limit: Option<u32>,
+        /// skip the first NUM values
+        #[clap(long, default_value = "0")]
+        offset: u32,
+    },
+    /// Correct the values of a sensor to value * gain + offset from a point in time on, also the
+    /// values that are already stored
+    Calibrate {
+        /// id of the calibrated sensor
+        #[clap(long, short)]
+        sensor: i64,
+        /// which values of the sensor are corrected: temperature, humidity, pressure or voltage
+        #[clap(long, short, default_value_t = MeasurementKind::Temperature)]
+        kind: MeasurementKind,
+        /// only values in this unit are corrected, defaults to the default unit of the kind
+        #[clap(long, short)]
+        unit: Option<String>,
+        /// added to the values, in their unit
+        #[clap(long, default_value_t = 0., allow_negative_numbers = true)]
+        offset: f64,
+        /// the values are multiplied with it before the offset is added
+        #[clap(long, default_value_t = 1.)]
+        gain: f64,
+        /// the calibration applies to values measured since the date, defaults to all values
+        #[clap(long)]
+        from: Option<String>,
+    },
+    /// List the calibrations ascending by the date they are valid from
+    Calibrations {
+        /// only list calibrations of this sensor id, can be repeated
+        #[clap(long = "sensor", short)]
+        sensors: Vec<i64>,
+    },
+    /// List the periods in which sensors sent no values, e.g. because they were offline
+    Gaps {
+        /// how often the sensors send a value, e.g. "1m"
+        #[clap(long, value_parser = parse_duration)]
+        interval: Duration,
+        /// only list gaps longer than this, e.g. "15m", defaults to the interval
+        #[clap(long, value_parser = parse_duration)]
+        threshold: Option<Duration>,
+        /// only check this sensor id, can be repeated, defaults to the sensors with values
+        #[clap(long = "sensor", short)]
+        sensors: Vec<i64>,
+        /// start of the checked period, defaults to 24 hours ago
+        #[clap(long)]
+        from: Option<String>,
+        /// end of the checked period, defaults to now
+        #[clap(long)]
+        to: Option<String>,
+    },
+    /// List all known sensors
+    Sensors,
+    /// Mark a period, e.g. "window opened", to explain the values measured meanwhile
+    Annotate {
+        text: String,
+        /// start of the period, defaults to now
+        #[clap(long)]
+        from: Option<String>,
+        /// end of the period, defaults to the start
+        #[clap(long)]
+        to: Option<String>,
+        /// id of the sensor the annotation belongs to, defaults to all sensors
+        #[clap(long, short)]
+        sensor: Option<i64>,
+        /// tag of the annotation, can be repeated
+        #[clap(long = "tag", short)]
+        tags: Vec<String>,
     },
+    /// List the annotations overlapping a period ascending
+    Annotations {
+        /// only list annotations of this sensor id and those of all sensors, can be repeated
+        #[clap(long = "sensor", short)]
+        sensors: Vec<i64>,
+        /// only list annotations since the date, example: "2024-01-01 00:00:00"
+        #[clap(long)]
+        from: Option<String>,
+        /// only list annotations before the date
+        #[clap(long)]
+        to: Option<String>,
+    },
+    /// Write a snapshot of the database to a new file, while the bridge keeps writing
+    Backup { file: PathBuf },
+    /// Replace the content of the database with a backup, older backups are migrated
+    Restore { file: PathBuf },
     /// create some test data
     Testdata {},
 }
 
-fn parse_duration(arg: &str) -> Result<NaiveDateTime, std::num::ParseIntError> {
-    Ok(to_naivedatetime(arg))
+/// Timestamps without offset are in the display timezone
+fn parse_timestamp_arg(
+    arg: &Option<String>,
+    timezone: &Tz,
+) -> iot_db_accessor::Result<Option<DateTime<Utc>>> {
+    arg.as_deref()
+        .map(|arg| parse_timestamp(arg, timezone))
+        .transpose()
+}
+
+fn print_calibration(calibration: &Calibration, timezone: &Tz) {
+    println!(
+        "since {}  sensor {}  {} in {} * {} + {}  (id {})",
+        format_timestamp(&calibration.valid_from, timezone),
+        calibration.sensor_id,
+        calibration.kind,
+        calibration.unit,
+        calibration.gain,
+        calibration.offset,
+        calibration.id
+    );
+}
+
+/// e.g. "1h 5m 30s", leading zero parts are left out
+fn format_duration(duration: &Duration) -> String {
+    let seconds = duration.num_seconds();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", seconds),
+        (0, _) => format!("{}m {}s", minutes, seconds),
+        _ => format!("{}h {}m {}s", hours, minutes, seconds),
+    }
+}
+
+fn print_value(rec: &SensorData, timezone: &Tz) {
+    let flag = match &rec.quality_reason {
+        Some(reason) => format!("  [{}: {}]", rec.quality, reason),
+        None => String::new(),
+    };
+    println!(
+        "{}  sensor {}  {} {} {}  (id {}){}",
+        format_timestamp(&rec.timestamp, timezone),
+        rec.sensor_id,
+        rec.kind,
+        rec.value,
+        rec.unit,
+        rec.id,
+        flag
+    );
+}
+
+fn print_annotation(annotation: &Annotation, timezone: &Tz) {
+    let sensor = match annotation.sensor_id {
+        Some(sensor_id) => format!("sensor {}", sensor_id),
+        None => "all sensors".to_string(),
+    };
+    println!(
+        "{} - {}  {}  {}  {:?}  (id {})",
+        format_timestamp(&annotation.starts_at, timezone),
+        format_timestamp(&annotation.ends_at, timezone),
+        sensor,
+        annotation.text,
+        annotation.tags,
+        annotation.id
+    );
 }
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {:#}", error);
+            // errors of the database access get a sysexits.h code, anything else is a failure
+            match error.downcast_ref::<iot_db_accessor::Error>() {
+                Some(error) => ExitCode::from(error.exit_code()),
+                None => ExitCode::FAILURE,
+            }
+        }
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
     dotenv().ok();
 
-    let pool = SqlitePool::connect(&env::var("DATABASE_URL")?).await?;
+    let pool = DatabaseConfig::from_env()?.connect_and_migrate().await?;
     let cli = Cli::parse();
+    let timezone = match &cli.timezone {
+        Some(timezone) => parse_timezone(timezone)?,
+        None => display_timezone_from_env()?,
+    };
 
     // You can check for the existence of subcommands, and if found use their
     // matches just as you would the top level cmd
     match &cli.command {
-        Commands::Add { value } => {
-            let _id = add_sensor_data(&pool, chrono::Utc::now().naive_utc(), *value).await;
+        Commands::Add {
+            value,
+            sensor,
+            kind,
+            unit,
+        } => {
+            let unit = unit.as_deref().unwrap_or(kind.default_unit());
+            let timestamp = chrono::Utc::now();
+            add_sensor_data(&pool, *sensor, timestamp, *kind, unit, *value).await?;
+        }
+        Commands::All { sensors, page_size } => {
+            let mut cursor = None;
+            loop {
+                let page =
+                    list_sensordata_page(&pool, sensors, cursor.as_ref(), *page_size, cli.quality)
+                        .await?;
+                for rec in page.items {
+                    print_value(&rec, &timezone);
+                }
+                match page.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
         }
-        Commands::All {} => {
-            let recs = list_sensordata(&pool).await;
-            for rec in recs.unwrap() {
-                println!("{:?}", rec);
+        Commands::Export { sensors, from, to } => {
+            let from = parse_timestamp_arg(from, &timezone)?;
+            let to = parse_timestamp_arg(to, &timezone)?;
+            let mut values = match (from, to) {
+                (None, None) => stream_sensordata(&pool, sensors, cli.quality),
+                (from, to) => {
+                    let from = get_date_with_default(&from);
+                    let to = to.unwrap_or_else(chrono::Utc::now);
+                    stream_sensordata_between(&pool, sensors, &from, &to, cli.quality)
+                }
+            };
+            let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+            writeln!(out, "id,sensor_id,timestamp,kind,unit,value,quality")?;
+            while let Some(rec) = values.try_next().await? {
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{}",
+                    rec.id,
+                    rec.sensor_id,
+                    format_timestamp(&rec.timestamp, &timezone),
+                    rec.kind,
+                    rec.unit,
+                    rec.value,
+                    rec.quality
+                )?;
             }
+            out.flush()?;
         }
         Commands::Last {
+            sensors,
             follow,
             since,
             rows,
         } => {
-            let mut since_latest = get_date_with_default(since);
+            let since = get_date_with_default(&parse_timestamp_arg(since, &timezone)?);
+            // subscribe before listing, so no value inserted in between is missed
+            let mut new_values = match follow {
+                true => Some(SensorDataFeed::spawn(pool.clone()).await?.stream()),
+                false => None,
+            };
             println!("Sensor Values");
 
-            loop {
-                let recs = list_last_values_descending_since(&pool, &since_latest, *rows).await;
-                let sensor_values = recs.unwrap();
-                for rec in sensor_values.iter().rev() {
-                    println!("{:?}", rec);
-                }
+            let sensor_values = SensorQuery::new()
+                .sensors(sensors)
+                .after(since)
+                .order(SortOrder::Descending)
+                .limit(*rows)
+                .quality(cli.quality)
+                .fetch_all(&pool)
+                .await?;
+            for rec in sensor_values.iter().rev() {
+                print_value(rec, &timezone);
+            }
 
-                if *follow {
-                    if let Some(latest) = sensor_values.first() {
-                        since_latest = latest.timestamp
+            if let Some(new_values) = &mut new_values {
+                // values inserted while listing are also published, ids grow with each insert
+                let listed_until = sensor_values.iter().map(|rec| rec.id).max().unwrap_or(0);
+                while let Some(rec) = new_values.next().await {
+                    if rec.id > listed_until
+                        && (sensors.is_empty() || sensors.contains(&rec.sensor_id))
+                        && cli.quality.accepts(rec.quality)
+                    {
+                        print_value(&rec, &timezone);
                     }
-                    sleep(std::time::Duration::from_secs(1)).await;
-                } else {
-                    break;
                 }
             }
         }
+        Commands::Query {
+            sensors,
+            from,
+            to,
+            kind,
+            min,
+            max,
+            order,
+            limit,
+            offset,
+        } => {
+            let mut query = SensorQuery::new()
+                .sensors(sensors)
+                .quality(cli.quality)
+                .order(*order)
+                .offset(*offset);
+            if let Some(from) = parse_timestamp_arg(from, &timezone)? {
+                query = query.from(from);
+            }
+            if let Some(to) = parse_timestamp_arg(to, &timezone)? {
+                query = query.to(to);
+            }
+            if let Some(kind) = kind {
+                query = query.kind(*kind);
+            }
+            if let Some(min) = min {
+                query = query.min_value(*min);
+            }
+            if let Some(max) = max {
+                query = query.max_value(*max);
+            }
+            if let Some(limit) = limit {
+                query = query.limit(*limit);
+            }
+            for rec in query.fetch_all(&pool).await? {
+                print_value(&rec, &timezone);
+            }
+        }
+        Commands::Annotate {
+            text,
+            from,
+            to,
+            sensor,
+            tags,
+        } => {
+            let starts_at = parse_timestamp_arg(from, &timezone)?.unwrap_or_else(chrono::Utc::now);
+            let ends_at = parse_timestamp_arg(to, &timezone)?.unwrap_or(starts_at);
+            let annotation = NewAnnotation {
+                sensor_id: *sensor,
+                starts_at,
+                ends_at,
+                text: text.clone(),
+                tags: tags.clone(),
+            };
+            let id = add_annotation(&pool, &annotation).await?;
+            print_annotation(&annotation.into_annotation(id), &timezone);
+        }
+        Commands::Annotations { sensors, from, to } => {
+            let from = get_date_with_default(&parse_timestamp_arg(from, &timezone)?);
+            let to = get_end_date_with_default(&parse_timestamp_arg(to, &timezone)?);
+            for annotation in list_annotations(&pool, sensors, &from, &to).await? {
+                print_annotation(&annotation, &timezone);
+            }
+        }
+        Commands::Calibrate {
+            sensor,
+            kind,
+            unit,
+            offset,
+            gain,
+            from,
+        } => {
+            let calibration = NewCalibration {
+                sensor_id: *sensor,
+                kind: *kind,
+                unit: unit
+                    .clone()
+                    .unwrap_or_else(|| kind.default_unit().to_string()),
+                valid_from: get_date_with_default(&parse_timestamp_arg(from, &timezone)?),
+                offset: *offset,
+                gain: *gain,
+            };
+            let id = add_calibration(&pool, &calibration).await?;
+            print_calibration(&calibration.into_calibration(id), &timezone);
+        }
+        Commands::Calibrations { sensors } => {
+            for calibration in list_calibrations(&pool, sensors).await? {
+                print_calibration(&calibration, &timezone);
+            }
+        }
+        Commands::Gaps {
+            interval,
+            threshold,
+            sensors,
+            from,
+            to,
+        } => {
+            let to = parse_timestamp_arg(to, &timezone)?.unwrap_or_else(chrono::Utc::now);
+            let from = parse_timestamp_arg(from, &timezone)?.unwrap_or(to - Duration::days(1));
+            let threshold = threshold.unwrap_or(*interval);
+            for gap in list_gaps(&pool, sensors, &from, &to, *interval, threshold).await? {
+                println!(
+                    "{} - {}  sensor {}  no values for {}",
+                    format_timestamp(&gap.starts_at, &timezone),
+                    format_timestamp(&gap.ends_at, &timezone),
+                    gap.sensor_id,
+                    format_duration(&gap.duration())
+                );
+            }
+        }
+        Commands::Sensors => {
+            for sensor in list_sensors(&pool).await? {
+                println!("{:?}", sensor);
+            }
+        }
+        Commands::Backup { file } => {
+            backup_database(&pool, file).await?;
+            println!("Backed up to {}", file.display());
+        }
+        Commands::Restore { file } => {
+            let version = restore_database(&pool, file).await?;
+            println!("Restored {} (schema version {})", file.display(), version);
+        }
         Commands::Testdata {} => {
-            create_test_data(&pool).await;
+            create_test_data(&pool).await?;
         }
     }
     Ok(())
 }
 
-async fn create_test_data(pool: &Pool<Sqlite>) {
-    let _ = add_sensor_data(pool, to_naivedatetime("2024-01-01 09:00:00"), 10.00).await;
-    let _ = add_sensor_data(pool, to_naivedatetime("2024-01-01 09:30:00"), 11.00).await;
-    let _ = add_sensor_data(pool, to_naivedatetime("2024-01-01 09:59:00"), 12.00).await;
-    let _ = add_sensor_data(pool, to_naivedatetime("2024-01-01 10:00:00"), 13.00).await;
-    let _ = add_sensor_data(pool, chrono::Utc::now().naive_utc(), 10.00).await;
+async fn create_test_data(pool: &Pool<Sqlite>) -> iot_db_accessor::Result<()> {
+    let test_data = [
+        (to_utc_datetime("2024-01-01 09:00:00")?, 10.00),
+        (to_utc_datetime("2024-01-01 09:30:00")?, 11.00),
+        (to_utc_datetime("2024-01-01 09:59:00")?, 12.00),
+        (to_utc_datetime("2024-01-01 10:00:00")?, 13.00),
+        (chrono::Utc::now(), 10.00),
+    ];
+    let kind = MeasurementKind::Temperature;
+    for (timestamp, value) in test_data {
+        let _ = add_sensor_data(
+            pool,
+            DEFAULT_SENSOR_ID,
+            timestamp,
+            kind,
+            kind.default_unit(),
+            value,
+        )
+        .await;
+    }
+    Ok(())
 }
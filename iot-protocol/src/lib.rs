@@ -0,0 +1,298 @@
+//! Wire protocol between the sensors and the `iot-data-bridge`.
+//!
+//! The legacy protocol is a bare temperature as big endian `f32`, without identity or checks. A v2
+//! frame carries the device, a sequence number, the kind of the value and optionally the time the
+//! device measured it, protected by a CRC-32. All numbers are big endian:
+//!
+//! | bytes | field                                                         |
+//! |-------|---------------------------------------------------------------|
+//! | 2     | magic `A5 5A`                                                 |
+//! | 1     | version, `2`                                                  |
+//! | 1     | flags, bit 0: a device timestamp follows the sequence number  |
+//! | 1     | kind: 1 temperature, 2 humidity, 3 pressure, 4 voltage        |
+//! | 8     | device id, e.g. the unique id of the flash of a PicoW         |
+//! | 4     | sequence number, incremented per frame                        |
+//! | 8     | device timestamp in milliseconds since the unix epoch, if set |
+//! | 4     | value as `f32` in the default unit of the kind                |
+//! | 4     | CRC-32 (IEEE) of all previous bytes                           |
+//!
+//! The magic never starts a plausible legacy reading, as an `f32` starting with `A5` is about
+//! `-1e-16`. The crate has no dependencies and is `no_std`, so the firmware can use it as well.
+
+#![no_std]
+
+use core::fmt;
+
+pub const MAGIC: [u8; 2] = [0xA5, 0x5A];
+pub const VERSION: u8 = 2;
+/// Size of a reading of the legacy protocol
+pub const LEGACY_SIZE: usize = 4;
+/// Bytes needed to know the size of a frame
+pub const PREFIX_SIZE: usize = 4;
+pub const MIN_FRAME_SIZE: usize = 25;
+pub const MAX_FRAME_SIZE: usize = MIN_FRAME_SIZE + 8;
+
+const FLAG_DEVICE_TIMESTAMP: u8 = 0x01;
+
+/// What a value describes, the values are in the default unit of the kind: °C, %, hPa or V
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Temperature = 1,
+    Humidity = 2,
+    Pressure = 3,
+    Voltage = 4,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 4] = [
+        Kind::Temperature,
+        Kind::Humidity,
+        Kind::Pressure,
+        Kind::Voltage,
+    ];
+
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_code(code: u8) -> Option<Kind> {
+        Kind::ALL.into_iter().find(|kind| kind.code() == code)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub device_id: u64,
+    pub sequence: u32,
+    pub kind: Kind,
+    /// when the device measured the value, in milliseconds since the unix epoch
+    pub device_timestamp: Option<i64>,
+    pub value: f32,
+}
+
+/// Why bytes could not be decoded as a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// more bytes are needed, the frame is not complete yet
+    Incomplete,
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    UnknownFlags(u8),
+    UnknownKind(u8),
+    ChecksumMismatch,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Incomplete => f.write_str("incomplete frame"),
+            DecodeError::InvalidMagic => f.write_str("invalid magic"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            DecodeError::UnknownFlags(flags) => write!(f, "unknown flags {:#04x}", flags),
+            DecodeError::UnknownKind(kind) => write!(f, "unknown kind {}", kind),
+            DecodeError::ChecksumMismatch => f.write_str("checksum mismatch"),
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+/// The bytes of an encoded frame
+#[derive(Debug, Clone, Copy)]
+pub struct EncodedFrame {
+    bytes: [u8; MAX_FRAME_SIZE],
+    len: usize,
+}
+
+impl EncodedFrame {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Frame {
+    /// Size of the encoded frame
+    pub fn encoded_len(&self) -> usize {
+        match self.device_timestamp {
+            Some(_) => MAX_FRAME_SIZE,
+            None => MIN_FRAME_SIZE,
+        }
+    }
+
+    pub fn encode(&self) -> EncodedFrame {
+        let mut bytes = [0; MAX_FRAME_SIZE];
+        let mut writer = Writer {
+            bytes: &mut bytes,
+            len: 0,
+        };
+        writer.put(&MAGIC);
+        writer.put(&[VERSION]);
+        let flags = match self.device_timestamp {
+            Some(_) => FLAG_DEVICE_TIMESTAMP,
+            None => 0,
+        };
+        writer.put(&[flags, self.kind.code()]);
+        writer.put(&self.device_id.to_be_bytes());
+        writer.put(&self.sequence.to_be_bytes());
+        if let Some(device_timestamp) = self.device_timestamp {
+            writer.put(&device_timestamp.to_be_bytes());
+        }
+        writer.put(&self.value.to_be_bytes());
+        let crc = crc32(&writer.bytes[..writer.len]);
+        writer.put(&crc.to_be_bytes());
+        let len = writer.len;
+        EncodedFrame { bytes, len }
+    }
+
+    /// Decodes the frame at the start of `bytes` and returns it with its size, the bytes after it
+    /// are not looked at
+    pub fn decode(bytes: &[u8]) -> Result<(Frame, usize), DecodeError> {
+        let len = frame_len(bytes)?;
+        let bytes = bytes.get(..len).ok_or(DecodeError::Incomplete)?;
+        let (content, crc) = bytes.split_at(len - 4);
+        if crc32(content) != u32::from_be_bytes(array(crc)) {
+            return Err(DecodeError::ChecksumMismatch);
+        }
+        let kind = Kind::from_code(content[4]).ok_or(DecodeError::UnknownKind(content[4]))?;
+        let device_id = u64::from_be_bytes(array(&content[5..13]));
+        let sequence = u32::from_be_bytes(array(&content[13..17]));
+        let (device_timestamp, value) = match len {
+            MAX_FRAME_SIZE => (
+                Some(i64::from_be_bytes(array(&content[17..25]))),
+                &content[25..29],
+            ),
+            _ => (None, &content[17..21]),
+        };
+        let frame = Frame {
+            device_id,
+            sequence,
+            kind,
+            device_timestamp,
+            value: f32::from_be_bytes(array(value)),
+        };
+        Ok((frame, len))
+    }
+}
+
+/// Size of the frame starting with `bytes`, known from its first `PREFIX_SIZE` bytes
+pub fn frame_len(bytes: &[u8]) -> Result<usize, DecodeError> {
+    let prefix = bytes.get(..PREFIX_SIZE);
+    // a partial magic may still become a frame
+    if !MAGIC.starts_with(&bytes[..bytes.len().min(MAGIC.len())]) {
+        return Err(DecodeError::InvalidMagic);
+    }
+    let prefix = prefix.ok_or(DecodeError::Incomplete)?;
+    if prefix[2] != VERSION {
+        return Err(DecodeError::UnsupportedVersion(prefix[2]));
+    }
+    match prefix[3] {
+        0 => Ok(MIN_FRAME_SIZE),
+        FLAG_DEVICE_TIMESTAMP => Ok(MAX_FRAME_SIZE),
+        flags => Err(DecodeError::UnknownFlags(flags)),
+    }
+}
+
+/// Whether `bytes` may be the start of a frame rather than a legacy reading
+pub fn starts_frame(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&MAGIC[0])
+}
+
+/// Decodes a reading of the legacy protocol, a temperature in °C
+pub fn decode_legacy(bytes: &[u8]) -> Option<f32> {
+    let bytes = bytes.get(..LEGACY_SIZE)?;
+    Some(f32::from_be_bytes(array(bytes)))
+}
+
+/// CRC-32 as used by ethernet and zip, computed bitwise so no table is needed on the sensors
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    bytes.try_into().expect("slice has the size of the field")
+}
+
+struct Writer<'a> {
+    bytes: &'a mut [u8; MAX_FRAME_SIZE],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(device_timestamp: Option<i64>) -> Frame {
+        Frame {
+            device_id: 0xE661_4103_E75F_2A2B,
+            sequence: 17,
+            kind: Kind::Humidity,
+            device_timestamp,
+            value: 45.5,
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for frame in [frame(None), frame(Some(1_704_099_600_000))] {
+            let encoded = frame.encode();
+            assert_eq!(encoded.as_bytes().len(), frame.encoded_len());
+            assert!(starts_frame(encoded.as_bytes()));
+            assert_eq!(
+                Frame::decode(encoded.as_bytes()),
+                Ok((frame, frame.encoded_len()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_incomplete_and_invalid() {
+        let encoded = frame(Some(0)).encode();
+        let bytes = encoded.as_bytes();
+        for len in 0..bytes.len() {
+            assert_eq!(Frame::decode(&bytes[..len]), Err(DecodeError::Incomplete));
+        }
+
+        let mut corrupted = [0; MAX_FRAME_SIZE];
+        corrupted.copy_from_slice(bytes);
+        corrupted[20] ^= 0x01;
+        assert_eq!(
+            Frame::decode(&corrupted),
+            Err(DecodeError::ChecksumMismatch)
+        );
+        corrupted.copy_from_slice(bytes);
+        corrupted[2] = 3;
+        assert_eq!(
+            Frame::decode(&corrupted),
+            Err(DecodeError::UnsupportedVersion(3))
+        );
+        assert_eq!(
+            Frame::decode(&21.5f32.to_be_bytes()),
+            Err(DecodeError::InvalidMagic)
+        );
+        assert!(!starts_frame(&21.5f32.to_be_bytes()));
+        assert_eq!(decode_legacy(&21.5f32.to_be_bytes()), Some(21.5));
+    }
+}
@@ -1,16 +1,25 @@
-use axum::extract::{Query, State};
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::header;
 use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post};
+use axum::Extension;
 use axum::{Json, Router};
 use dotenvy::dotenv;
+use futures::{SinkExt, Stream, StreamExt};
 use iot_db_accessor::{
-    add_sensor_data, get_date_with_default, list_last_values_descending_since, SensorData,
+    display_timezone_from_env, get_date_with_default, get_end_date_with_default, parse_duration,
+    parse_timestamp, Aggregate, AggregatedBucket, AnnotatedSensorData, Annotation, Calibration,
+    Cursor, DatabaseConfig, Gap, MeasurementKind, NewAnnotation, NewCalibration, NewSensorData,
+    Page, QualityFilter, Sensor, SensorData, SensorDataFeed, SensorQuery, SensorStore, SortOrder,
+    Tz, DEFAULT_SENSOR_ID,
 };
 use serde::Deserialize;
-use sqlx::types::chrono::{self};
-use sqlx::SqlitePool;
+use sqlx::types::chrono::{self, DateTime, Utc};
 use std::env;
+use std::str::FromStr;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
 
@@ -21,8 +30,9 @@ async fn main() -> Result<()> {
     dotenv().ok();
     tracing_init();
 
-    let pool = SqlitePool::connect(&env::var("DATABASE_URL")?).await?;
-    let app = create_router(pool);
+    let pool = DatabaseConfig::from_env()?.connect_and_migrate().await?;
+    let feed = SensorDataFeed::spawn(pool.clone()).await?;
+    let app = create_router(pool, feed, display_timezone_from_env()?);
     // start the server, listening on confiured port WebServer IpAdress
     let serverurl = &env::var("IOT_WEBSERVER_URL")?;
     let listener = tokio::net::TcpListener::bind(serverurl).await.unwrap();
@@ -41,18 +51,47 @@ fn tracing_init() {
         .init();
 }
 
-fn create_router(pool: SqlitePool) -> axum::Router {
+/// `feed` pushes new values to the page, `timezone` is used to show timestamps on the page and
+/// to read timestamps without offset
+fn create_router<S>(store: S, feed: SensorDataFeed, timezone: Tz) -> axum::Router
+where
+    S: SensorStore + Clone + 'static,
+{
     // build our application with a route
     Router::new()
         .route("/", get(index))
         .nest(
             "/api",
             Router::new()
-                .route("/sensor_values", get(list_sensordata))
-                .route("/sensor_values_since", get(list_sensordata_since))
-                .route("/add_sensor_value", post(add_sensor_value)),
+                .route("/sensors", get(list_sensors::<S>))
+                .route("/sensor_values", get(list_sensordata::<S>))
+                .route("/sensor_values_since", get(list_sensordata_since::<S>))
+                .route("/sensor_values_between", get(list_sensordata_between::<S>))
+                .route("/sensor_values_query", get(query_sensordata::<S>))
+                .route("/sensor_values_export", get(export_sensordata::<S>))
+                .route("/sensor_values_events", get(sensordata_events))
+                .route("/sensor_values_aggregated", get(aggregate_sensordata::<S>))
+                .route("/sensor_gaps", get(list_gaps::<S>))
+                .route("/add_sensor_value", post(add_sensor_value::<S>))
+                .route(
+                    "/annotations",
+                    get(list_annotations::<S>).post(add_annotation::<S>),
+                )
+                .route(
+                    "/annotations/:id",
+                    get(get_annotation::<S>)
+                        .put(update_annotation::<S>)
+                        .delete(delete_annotation::<S>),
+                )
+                .route(
+                    "/calibrations",
+                    get(list_calibrations::<S>).post(add_calibration::<S>),
+                )
+                .route("/calibrations/:id", delete(delete_calibration::<S>)),
         )
-        .with_state(pool)
+        .with_state(store)
+        .layer(Extension(feed))
+        .layer(Extension(timezone))
         // prevent cross site scripting
         .layer(CorsLayer::new().allow_methods(Any).allow_origin(Any))
         // enable tracing
@@ -61,14 +100,33 @@ fn create_router(pool: SqlitePool) -> axum::Router {
 
 pub struct AppError(anyhow::Error);
 
+impl AppError {
+    /// HTTP status matching the kind of error, database errors are classified by the accessor
+    fn status(&self) -> StatusCode {
+        if let Some(error) = self.0.downcast_ref::<iot_db_accessor::Error>() {
+            return match error {
+                iot_db_accessor::Error::NotFound(_) => StatusCode::NOT_FOUND,
+                iot_db_accessor::Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+                iot_db_accessor::Error::ConstraintViolation(_) => StatusCode::CONFLICT,
+                iot_db_accessor::Error::Busy(_) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+        }
+        if self.0.is::<std::num::ParseFloatError>() || self.0.is::<std::num::ParseIntError>() {
+            return StatusCode::BAD_REQUEST;
+        }
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        let status = self.status();
+        if status.is_server_error() {
+            tracing::error!("{:#}", self.0);
+        }
+        (status, format!("Something went wrong: {}", self.0)).into_response()
     }
 }
 
@@ -84,40 +142,980 @@ where
     }
 }
 
-async fn index() -> axum::response::Html<String> {
+async fn index(Extension(timezone): Extension<Tz>) -> axum::response::Html<String> {
     // use getData (for productive Data)
-    let html = std::include_str!("../assets/index.html").replace("= getTestData", "= getData");
+    let html = std::include_str!("../assets/index.html")
+        .replace("= getTestData", "= getData")
+        .replace(
+            "DISPLAY_TIMEZONE = 'UTC'",
+            &format!("DISPLAY_TIMEZONE = '{}'", timezone.name()),
+        );
     axum::response::Html(html)
 }
 
-async fn add_sensor_value(State(pool): State<SqlitePool>, value: String) -> Result<(), AppError> {
+#[derive(Debug, Deserialize)]
+struct ParamsAddSensorValue {
+    sensor: Option<i64>,
+    kind: Option<MeasurementKind>,
+    /// defaults to the default unit of the kind
+    unit: Option<String>,
+    /// a value posted again with the same id is stored once, e.g. a sequence number
+    message_id: Option<String>,
+    /// when the device measured the value, a value posted again with the same device timestamp
+    /// is stored once
+    device_timestamp: Option<String>,
+}
+
+async fn add_sensor_value<S: SensorStore>(
+    queryparam: Query<ParamsAddSensorValue>,
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
+    value: String,
+) -> Result<(), AppError> {
     let value = value.trim().to_string().parse::<f64>()?;
-    add_sensor_data(&pool, chrono::Utc::now().naive_utc(), value).await?;
+    let kind = queryparam.kind.unwrap_or(MeasurementKind::Temperature);
+    let unit = queryparam.unit.as_deref().unwrap_or(kind.default_unit());
+    let sensor_data = NewSensorData {
+        sensor_id: queryparam.sensor.unwrap_or(DEFAULT_SENSOR_ID),
+        timestamp: chrono::Utc::now(),
+        kind,
+        unit: unit.to_string(),
+        value,
+        message_id: queryparam.message_id.clone(),
+        device_timestamp: parse_optional_timestamp(&queryparam.device_timestamp, &timezone)?,
+    };
+    store.add_sensor_data(&sensor_data).await?;
     Ok(())
 }
 
-pub async fn list_sensordata(
-    State(pool): State<SqlitePool>,
-) -> Result<axum::Json<Vec<SensorData>>, AppError> {
-    iot_db_accessor::list_sensordata(&pool)
+async fn list_sensors<S: SensorStore>(
+    State(store): State<S>,
+) -> Result<axum::Json<Vec<Sensor>>, AppError> {
+    store
+        .list_sensors()
         .await
         .map(Json::from)
         .map_err(AppError::from)
 }
 
+/// values per page of `/api/sensor_values` if no limit is requested ...
+const DEFAULT_PAGE_LIMIT: u32 = 1000;
+/// ... and the most that can be requested
+const MAX_PAGE_LIMIT: u32 = 10_000;
+
+#[derive(Debug, Deserialize)]
+struct ParamsSensordata {
+    /// comma separated list of sensor ids, e.g. `1,2`
+    sensors: Option<String>,
+    /// values per page, defaults to `DEFAULT_PAGE_LIMIT`
+    limit: Option<u32>,
+    /// `next_cursor` of the previous page, the first page is returned without
+    cursor: Option<String>,
+    /// `all`, `not-rejected` or `good`, defaults to `not-rejected`
+    quality: Option<QualityFilter>,
+}
+
+async fn list_sensordata<S: SensorStore>(
+    queryparam: Query<ParamsSensordata>,
+    State(store): State<S>,
+) -> Result<axum::Json<Page<SensorData>>, AppError> {
+    let sensors = parse_list(&queryparam.sensors)?;
+    let limit = queryparam
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .min(MAX_PAGE_LIMIT);
+    let cursor = queryparam
+        .cursor
+        .as_deref()
+        .map(Cursor::from_str)
+        .transpose()?;
+    store
+        .list_sensordata_page(
+            &sensors,
+            cursor.as_ref(),
+            limit,
+            queryparam.quality.unwrap_or_default(),
+        )
+        .await
+        .map(Json::from)
+        .map_err(AppError::from)
+}
+
+/// values buffered between the database and the response of `/api/sensor_values_export`
+const EXPORT_BUFFER: usize = 64;
+
+#[derive(Debug, Deserialize)]
+struct ParamsSensordataExport {
+    /// without `from` and `to` all values are exported
+    from: Option<String>,
+    /// defaults to now if only `from` is given
+    to: Option<String>,
+    /// comma separated list of sensor ids, e.g. `1,2`
+    sensors: Option<String>,
+    /// `all`, `not-rejected` or `good`, defaults to `not-rejected`
+    quality: Option<QualityFilter>,
+}
+
+/// Streams the values as newline delimited JSON, they are never loaded at once
+async fn export_sensordata<S: SensorStore + Clone + 'static>(
+    queryparam: Query<ParamsSensordataExport>,
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
+) -> Result<Response, AppError> {
+    let sensors: Vec<i64> = parse_list(&queryparam.sensors)?;
+    let quality = queryparam.quality.unwrap_or_default();
+    let from = parse_optional_timestamp(&queryparam.from, &timezone)?;
+    let to = parse_optional_timestamp(&queryparam.to, &timezone)?;
+    let range = match (from, to) {
+        (None, None) => None,
+        (from, to) => Some((
+            get_date_with_default(&from),
+            to.unwrap_or_else(chrono::Utc::now),
+        )),
+    };
+
+    // the stream borrows the store, so it is read by a task owning the store
+    let (mut sender, receiver) = futures::channel::mpsc::channel(EXPORT_BUFFER);
+    tokio::spawn(async move {
+        let mut values = match &range {
+            None => store.stream_sensordata(&sensors, quality),
+            Some((from, to)) => store.stream_sensordata_between(&sensors, from, to, quality),
+        };
+        while let Some(value) = values.next().await {
+            // the client went away
+            if sender.send(value).await.is_err() {
+                break;
+            }
+        }
+    });
+    let lines = receiver.map(|value| -> iot_db_accessor::Result<String> {
+        let mut line = serde_json::to_string(&value?)?;
+        line.push('\n');
+        Ok(line)
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct ParamsSensordataEvents {
+    /// comma separated list of sensor ids, e.g. `1,2`
+    sensors: Option<String>,
+    /// `all`, `not-rejected` or `good`, defaults to `not-rejected`
+    quality: Option<QualityFilter>,
+}
+
+/// Pushes every new value as server-sent event, the data is the value as JSON
+async fn sensordata_events(
+    queryparam: Query<ParamsSensordataEvents>,
+    Extension(feed): Extension<SensorDataFeed>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, AppError> {
+    let sensors: Vec<i64> = parse_list(&queryparam.sensors)?;
+    let quality = queryparam.quality.unwrap_or_default();
+    let events = feed
+        .stream()
+        .filter(move |value| {
+            let selected = (sensors.is_empty() || sensors.contains(&value.sensor_id))
+                && quality.accepts(value.quality);
+            futures::future::ready(selected)
+        })
+        .map(|value| Event::default().json_data(value));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
 #[derive(Debug, Deserialize)]
 struct ParamsSensordataSince {
-    since: Option<chrono::NaiveDateTime>,
+    since: Option<String>,
     rows: Option<u32>,
+    /// comma separated list of sensor ids, e.g. `1,2`
+    sensors: Option<String>,
+    /// `all`, `not-rejected` or `good`, defaults to `not-rejected`
+    quality: Option<QualityFilter>,
 }
 
-async fn list_sensordata_since(
+async fn list_sensordata_since<S: SensorStore>(
     queryparam: Query<ParamsSensordataSince>,
-    State(pool): State<SqlitePool>,
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
 ) -> Result<axum::Json<Vec<SensorData>>, AppError> {
     let rows = queryparam.rows.unwrap_or(10);
-    list_last_values_descending_since(&pool, &get_date_with_default(&queryparam.since), rows)
+    let sensors = parse_list(&queryparam.sensors)?;
+    let since = get_date_with_default(&parse_optional_timestamp(&queryparam.since, &timezone)?);
+    let query = SensorQuery::new()
+        .sensors(&sensors)
+        .after(since)
+        .order(SortOrder::Descending)
+        .limit(rows)
+        .quality(queryparam.quality.unwrap_or_default());
+    store
+        .query_sensordata(&query)
+        .await
+        .map(Json::from)
+        .map_err(AppError::from)
+}
+
+#[derive(Debug, Deserialize)]
+struct ParamsSensordataQuery {
+    /// without `from` and `to` the values are not limited in time
+    from: Option<String>,
+    to: Option<String>,
+    /// comma separated list of sensor ids, e.g. `1,2`
+    sensors: Option<String>,
+    kind: Option<MeasurementKind>,
+    /// only values of at least `min` and at most `max`, after the calibration
+    min: Option<f64>,
+    max: Option<f64>,
+    /// `all`, `not-rejected` or `good`, defaults to `not-rejected`
+    quality: Option<QualityFilter>,
+    /// `ascending` or `descending` by timestamp, defaults to `ascending`
+    order: Option<SortOrder>,
+    /// values per response, defaults to `DEFAULT_PAGE_LIMIT`
+    limit: Option<u32>,
+    /// values skipped before the first returned value
+    offset: Option<u32>,
+}
+
+/// Values selected by any combination of the filters
+async fn query_sensordata<S: SensorStore>(
+    queryparam: Query<ParamsSensordataQuery>,
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
+) -> Result<axum::Json<Vec<SensorData>>, AppError> {
+    let mut query = SensorQuery::new()
+        .sensors(&parse_list(&queryparam.sensors)?)
+        .quality(queryparam.quality.unwrap_or_default())
+        .order(queryparam.order.unwrap_or_default())
+        .limit(
+            queryparam
+                .limit
+                .unwrap_or(DEFAULT_PAGE_LIMIT)
+                .min(MAX_PAGE_LIMIT),
+        )
+        .offset(queryparam.offset.unwrap_or(0));
+    if let Some(from) = parse_optional_timestamp(&queryparam.from, &timezone)? {
+        query = query.from(from);
+    }
+    if let Some(to) = parse_optional_timestamp(&queryparam.to, &timezone)? {
+        query = query.to(to);
+    }
+    if let Some(kind) = queryparam.kind {
+        query = query.kind(kind);
+    }
+    if let Some(min) = queryparam.min {
+        query = query.min_value(min);
+    }
+    if let Some(max) = queryparam.max {
+        query = query.max_value(max);
+    }
+    store
+        .query_sensordata(&query)
+        .await
+        .map(Json::from)
+        .map_err(AppError::from)
+}
+
+#[derive(Debug, Deserialize)]
+struct ParamsSensordataAggregated {
+    from: String,
+    to: String,
+    /// bucket width, e.g. `15m`, defaults to `1h`
+    bucket: Option<String>,
+    /// comma separated list of `min`, `max`, `avg` and `count`, defaults to all
+    aggregates: Option<String>,
+    kind: Option<MeasurementKind>,
+    /// comma separated list of sensor ids, e.g. `1,2`
+    sensors: Option<String>,
+}
+
+async fn aggregate_sensordata<S: SensorStore>(
+    queryparam: Query<ParamsSensordataAggregated>,
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
+) -> Result<axum::Json<Vec<AggregatedBucket>>, AppError> {
+    let from = parse_timestamp(&queryparam.from, &timezone)?;
+    let to = parse_timestamp(&queryparam.to, &timezone)?;
+    let width = queryparam.bucket.as_deref().unwrap_or("1h").parse()?;
+    let mut aggregates = parse_list(&queryparam.aggregates)?;
+    if aggregates.is_empty() {
+        aggregates = Aggregate::ALL.to_vec();
+    }
+    let kind = queryparam.kind.unwrap_or(MeasurementKind::Temperature);
+    let sensors = parse_list(&queryparam.sensors)?;
+    store
+        .aggregate_sensordata(&sensors, kind, &from, &to, width, &aggregates)
+        .await
+        .map(Json::from)
+        .map_err(AppError::from)
+}
+
+#[derive(Debug, Deserialize)]
+struct ParamsGaps {
+    from: String,
+    to: String,
+    /// how often the sensors send a value, e.g. `1m`
+    interval: String,
+    /// only gaps longer than this are listed, e.g. `15m`, defaults to the interval
+    threshold: Option<String>,
+    /// comma separated list of sensor ids, e.g. `1,2`, defaults to the sensors with values in the range
+    sensors: Option<String>,
+}
+
+/// Periods in `[from, to)` in which sensors sent no values, so the dashboard can shade them
+async fn list_gaps<S: SensorStore>(
+    queryparam: Query<ParamsGaps>,
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
+) -> Result<axum::Json<Vec<Gap>>, AppError> {
+    let from = parse_timestamp(&queryparam.from, &timezone)?;
+    let to = parse_timestamp(&queryparam.to, &timezone)?;
+    let interval = parse_duration(&queryparam.interval)?;
+    let threshold = match &queryparam.threshold {
+        Some(threshold) => parse_duration(threshold)?,
+        None => interval,
+    };
+    let sensors = parse_list(&queryparam.sensors)?;
+    store
+        .list_gaps(&sensors, &from, &to, interval, threshold)
+        .await
+        .map(Json::from)
+        .map_err(AppError::from)
+}
+
+#[derive(Debug, Deserialize)]
+struct ParamsSensordataBetween {
+    from: String,
+    to: String,
+    /// comma separated list of sensor ids, e.g. `1,2`
+    sensors: Option<String>,
+    /// `all`, `not-rejected` or `good`, defaults to `not-rejected`
+    quality: Option<QualityFilter>,
+}
+
+/// Values in `[from, to)` together with the annotations overlapping the range
+async fn list_sensordata_between<S: SensorStore>(
+    queryparam: Query<ParamsSensordataBetween>,
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
+) -> Result<axum::Json<AnnotatedSensorData>, AppError> {
+    let from = parse_timestamp(&queryparam.from, &timezone)?;
+    let to = parse_timestamp(&queryparam.to, &timezone)?;
+    let sensors = parse_list(&queryparam.sensors)?;
+    let quality = queryparam.quality.unwrap_or_default();
+    store
+        .list_sensordata_between_annotated(&sensors, &from, &to, quality)
+        .await
+        .map(Json::from)
+        .map_err(AppError::from)
+}
+
+#[derive(Debug, Deserialize)]
+struct ParamsAnnotations {
+    /// without `from` and `to` all annotations are listed
+    from: Option<String>,
+    to: Option<String>,
+    /// comma separated list of sensor ids, e.g. `1,2`, annotations without sensor are always listed
+    sensors: Option<String>,
+}
+
+async fn list_annotations<S: SensorStore>(
+    queryparam: Query<ParamsAnnotations>,
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
+) -> Result<axum::Json<Vec<Annotation>>, AppError> {
+    let from = get_date_with_default(&parse_optional_timestamp(&queryparam.from, &timezone)?);
+    let to = get_end_date_with_default(&parse_optional_timestamp(&queryparam.to, &timezone)?);
+    let sensors = parse_list(&queryparam.sensors)?;
+    store
+        .list_annotations(&sensors, &from, &to)
+        .await
+        .map(Json::from)
+        .map_err(AppError::from)
+}
+
+/// Annotation as it is posted, timestamps without offset are in the display timezone
+#[derive(Debug, Deserialize)]
+struct AnnotationBody {
+    sensor_id: Option<i64>,
+    starts_at: String,
+    /// defaults to `starts_at`
+    ends_at: Option<String>,
+    text: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl AnnotationBody {
+    fn parse(self, timezone: &Tz) -> iot_db_accessor::Result<NewAnnotation> {
+        let starts_at = parse_timestamp(&self.starts_at, timezone)?;
+        let ends_at = parse_optional_timestamp(&self.ends_at, timezone)?.unwrap_or(starts_at);
+        Ok(NewAnnotation {
+            sensor_id: self.sensor_id,
+            starts_at,
+            ends_at,
+            text: self.text,
+            tags: self.tags,
+        })
+    }
+}
+
+async fn add_annotation<S: SensorStore>(
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
+    Json(body): Json<AnnotationBody>,
+) -> Result<(StatusCode, axum::Json<Annotation>), AppError> {
+    let annotation = body.parse(&timezone)?;
+    let id = store.add_annotation(&annotation).await?;
+    Ok((StatusCode::CREATED, Json(annotation.into_annotation(id))))
+}
+
+async fn get_annotation<S: SensorStore>(
+    Path(id): Path<i64>,
+    State(store): State<S>,
+) -> Result<axum::Json<Annotation>, AppError> {
+    store
+        .get_annotation(id)
         .await
         .map(Json::from)
         .map_err(AppError::from)
 }
+
+async fn update_annotation<S: SensorStore>(
+    Path(id): Path<i64>,
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
+    Json(body): Json<AnnotationBody>,
+) -> Result<axum::Json<Annotation>, AppError> {
+    let annotation = body.parse(&timezone)?;
+    store.update_annotation(id, &annotation).await?;
+    Ok(Json(annotation.into_annotation(id)))
+}
+
+async fn delete_annotation<S: SensorStore>(
+    Path(id): Path<i64>,
+    State(store): State<S>,
+) -> Result<StatusCode, AppError> {
+    store.delete_annotation(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct ParamsCalibrations {
+    /// comma separated list of sensor ids, e.g. `1,2`
+    sensors: Option<String>,
+}
+
+async fn list_calibrations<S: SensorStore>(
+    queryparam: Query<ParamsCalibrations>,
+    State(store): State<S>,
+) -> Result<axum::Json<Vec<Calibration>>, AppError> {
+    let sensors = parse_list(&queryparam.sensors)?;
+    store
+        .list_calibrations(&sensors)
+        .await
+        .map(Json::from)
+        .map_err(AppError::from)
+}
+
+/// Calibration as it is posted, a timestamp without offset is in the display timezone
+#[derive(Debug, Deserialize)]
+struct CalibrationBody {
+    sensor_id: i64,
+    kind: MeasurementKind,
+    /// unit of the calibrated values and the offset, defaults to the default unit of the kind
+    unit: Option<String>,
+    /// defaults to the start of the recordings, so all values are calibrated
+    valid_from: Option<String>,
+    #[serde(default)]
+    offset: f64,
+    #[serde(default = "default_gain")]
+    gain: f64,
+}
+
+fn default_gain() -> f64 {
+    1.
+}
+
+async fn add_calibration<S: SensorStore>(
+    State(store): State<S>,
+    Extension(timezone): Extension<Tz>,
+    Json(body): Json<CalibrationBody>,
+) -> Result<(StatusCode, axum::Json<Calibration>), AppError> {
+    let calibration = NewCalibration {
+        sensor_id: body.sensor_id,
+        kind: body.kind,
+        unit: body
+            .unit
+            .unwrap_or_else(|| body.kind.default_unit().to_string()),
+        valid_from: get_date_with_default(&parse_optional_timestamp(&body.valid_from, &timezone)?),
+        offset: body.offset,
+        gain: body.gain,
+    };
+    let id = store.add_calibration(&calibration).await?;
+    Ok((StatusCode::CREATED, Json(calibration.into_calibration(id))))
+}
+
+async fn delete_calibration<S: SensorStore>(
+    Path(id): Path<i64>,
+    State(store): State<S>,
+) -> Result<StatusCode, AppError> {
+    store.delete_calibration(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Parses an RFC 3339 timestamp, timestamps without offset are in the display timezone
+fn parse_optional_timestamp(
+    param: &Option<String>,
+    timezone: &Tz,
+) -> iot_db_accessor::Result<Option<DateTime<Utc>>> {
+    param
+        .as_deref()
+        .map(|param| parse_timestamp(param, timezone))
+        .transpose()
+}
+
+/// Parses a comma separated query parameter, a missing parameter is an empty list
+fn parse_list<T>(list: &Option<String>) -> Result<Vec<T>>
+where
+    T: FromStr,
+    anyhow::Error: From<T::Err>,
+{
+    let Some(list) = list else {
+        return Ok(Vec::new());
+    };
+    list.split(',')
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| Ok(item.trim().parse::<T>()?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use futures::StreamExt;
+    use iot_db_accessor::{
+        to_utc_datetime, MemoryStore, NewSensorData, QualityFilter, SensorStore, Tz,
+        DEFAULT_SENSOR_ID,
+    };
+    use tower::ServiceExt;
+
+    use super::create_router;
+
+    async fn send(store: &MemoryStore, request: Request<Body>) -> (StatusCode, String) {
+        let response = create_router(store.clone(), store.feed(), Tz::UTC)
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_add_and_list_sensor_values() {
+        let store = MemoryStore::new();
+        let sensor = store.register_sensor("picow-1").await.unwrap();
+
+        let request = Request::post(format!("/api/add_sensor_value?sensor={}", sensor))
+            .body(Body::from("21.5\n"))
+            .unwrap();
+        assert_eq!(send(&store, request).await.0, StatusCode::OK);
+        let request = Request::post("/api/add_sensor_value?kind=humidity")
+            .body(Body::from("40"))
+            .unwrap();
+        assert_eq!(send(&store, request).await.0, StatusCode::OK);
+
+        let (status, body) = send(&store, get("/api/sensor_values")).await;
+        assert_eq!(status, StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let values = page["items"].as_array().unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0]["value"], 21.5);
+        assert_eq!(values[1]["kind"], "humidity");
+        assert_eq!(values[1]["unit"], "%");
+        assert!(page["next_cursor"].is_null());
+
+        let uri = format!("/api/sensor_values_since?rows=5&sensors={}", sensor);
+        let (status, body) = send(&store, get(&uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        let values: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0]["sensor_id"], sensor);
+    }
+
+    #[tokio::test]
+    async fn test_query_sensor_values() {
+        let store = MemoryStore::new();
+        for value in ["19", "21", "23", "25"] {
+            let request = Request::post("/api/add_sensor_value")
+                .body(Body::from(value))
+                .unwrap();
+            assert_eq!(send(&store, request).await.0, StatusCode::OK);
+        }
+        let request = Request::post("/api/add_sensor_value?kind=humidity")
+            .body(Body::from("22"))
+            .unwrap();
+        assert_eq!(send(&store, request).await.0, StatusCode::OK);
+
+        let uri = "/api/sensor_values_query?kind=temperature&min=20&max=24&order=descending";
+        let (status, body) = send(&store, get(uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        let values: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        let values: Vec<_> = values.iter().map(|value| value["value"].clone()).collect();
+        assert_eq!(values, [23., 21.]);
+
+        let (_, body) = send(&store, get("/api/sensor_values_query?limit=2&offset=3")).await;
+        let values: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0]["value"], 25.);
+        assert_eq!(values[1]["kind"], "humidity");
+
+        for uri in [
+            "/api/sensor_values_query?order=sideways",
+            "/api/sensor_values_query?limit=0",
+            "/api/sensor_values_query?from=yesterday",
+        ] {
+            assert_eq!(send(&store, get(uri)).await.0, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_sensor_value_twice() {
+        let store = MemoryStore::new();
+        for uri in [
+            "/api/add_sensor_value?message_id=17",
+            "/api/add_sensor_value?message_id=17",
+            "/api/add_sensor_value?device_timestamp=2024-01-01T09:00:00Z",
+            "/api/add_sensor_value?device_timestamp=2024-01-01T09:00:00Z",
+        ] {
+            let request = Request::post(uri).body(Body::from("21.5")).unwrap();
+            assert_eq!(send(&store, request).await.0, StatusCode::OK);
+        }
+        let (_, body) = send(&store, get("/api/sensor_values")).await;
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(page["items"].as_array().unwrap().len(), 2);
+
+        let request = Request::post("/api/add_sensor_value?device_timestamp=yesterday")
+            .body(Body::from("21.5"))
+            .unwrap();
+        assert_eq!(send(&store, request).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_gaps() {
+        let store = MemoryStore::new();
+        let sensor = store.register_sensor("picow-1").await.unwrap();
+        let values: Vec<_> = [0, 1, 2, 10, 11]
+            .into_iter()
+            .map(|minute| {
+                let timestamp = to_utc_datetime(&format!("2024-01-01 09:{:02}:00", minute));
+                NewSensorData::temperature(sensor, timestamp.unwrap(), 20.)
+            })
+            .collect();
+        store.add_sensor_data_batch(&values).await.unwrap();
+
+        let uri = "/api/sensor_gaps?from=2024-01-01T09:00:00Z&to=2024-01-01T09:12:00Z&interval=1m";
+        let (status, body) = send(&store, get(uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        let gaps: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0]["sensor_id"], sensor);
+        assert_eq!(gaps[0]["starts_at"], "2024-01-01T09:03:00Z");
+        assert_eq!(gaps[0]["ends_at"], "2024-01-01T09:10:00Z");
+
+        let (_, body) = send(&store, get(&format!("{}&threshold=10m", uri))).await;
+        assert_eq!(body, "[]");
+        let (status, _) = send(&store, get(&format!("{}&threshold=soon", uri))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_quality_filter() {
+        let store = MemoryStore::new();
+        for value in ["20", "-273"] {
+            let request = Request::post("/api/add_sensor_value")
+                .body(Body::from(value))
+                .unwrap();
+            assert_eq!(send(&store, request).await.0, StatusCode::OK);
+        }
+
+        // rejected values are left out unless they are asked for
+        let (_, body) = send(&store, get("/api/sensor_values")).await;
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        let (_, body) = send(&store, get("/api/sensor_values?quality=all")).await;
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let values = page["items"].as_array().unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0]["quality"], "good");
+        assert!(values[0].get("quality_reason").is_none());
+        assert_eq!(values[1]["quality"], "rejected");
+        assert_eq!(
+            values[1]["quality_reason"],
+            "-273 °C is below the minimum of -50 °C"
+        );
+
+        let (status, _) = send(&store, get("/api/sensor_values?quality=bad")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_sensor_values_pages() {
+        let store = MemoryStore::new();
+        for value in ["1", "2", "3"] {
+            let request = Request::post("/api/add_sensor_value")
+                .body(Body::from(value))
+                .unwrap();
+            assert_eq!(send(&store, request).await.0, StatusCode::OK);
+        }
+
+        let mut uri = "/api/sensor_values?limit=2".to_string();
+        let mut values = Vec::new();
+        loop {
+            let (status, body) = send(&store, get(&uri)).await;
+            assert_eq!(status, StatusCode::OK);
+            let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+            values.extend(page["items"].as_array().unwrap().clone());
+            match page["next_cursor"].as_str() {
+                Some(cursor) => uri = format!("/api/sensor_values?limit=2&cursor={}", cursor),
+                None => break,
+            }
+        }
+        let values: Vec<_> = values.iter().map(|value| value["value"].clone()).collect();
+        assert_eq!(values, [1., 2., 3.]);
+
+        let (status, _) = send(&store, get("/api/sensor_values?cursor=yesterday")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_export_sensor_values() {
+        let store = MemoryStore::new();
+        let sensor = store.register_sensor("picow-1").await.unwrap();
+        for (sensor, value) in [(sensor, "1"), (1, "2"), (sensor, "3")] {
+            let request = Request::post(format!("/api/add_sensor_value?sensor={}", sensor))
+                .body(Body::from(value))
+                .unwrap();
+            assert_eq!(send(&store, request).await.0, StatusCode::OK);
+        }
+
+        let uri = format!("/api/sensor_values_export?sensors={}", sensor);
+        let (status, body) = send(&store, get(&uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        let values: Vec<f64> = body
+            .lines()
+            .map(|line| {
+                let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                value["value"].as_f64().unwrap()
+            })
+            .collect();
+        assert_eq!(values, [1., 3.]);
+
+        let uri = "/api/sensor_values_export?from=2024-01-01T00:00:00&to=2024-01-02T00:00:00";
+        let (status, body) = send(&store, get(uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_timezones() {
+        let store = MemoryStore::new();
+        let timestamp = to_utc_datetime("2024-01-01 08:00:00").unwrap();
+        let value = NewSensorData::temperature(DEFAULT_SENSOR_ID, timestamp, 21.5);
+        store.add_sensor_data(&value).await.unwrap();
+        let since = |timezone, since| {
+            let router = create_router(store.clone(), store.feed(), timezone);
+            let uri = format!("/api/sensor_values_since?since={}", since);
+            async move {
+                let response = router.oneshot(get(&uri)).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap()
+            }
+        };
+
+        // 08:30 in Berlin is 07:30 UTC
+        let values = since(Tz::Europe__Berlin, "2024-01-01T08:30:00").await;
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0]["timestamp"], "2024-01-01T08:00:00Z");
+        assert!(since(Tz::UTC, "2024-01-01T08:30:00").await.is_empty());
+        // an explicit offset is used as is, `+` has to be encoded
+        assert!(since(Tz::Europe__Berlin, "2024-01-01T08:30:00%2B00:00")
+            .await
+            .is_empty());
+        assert_eq!(since(Tz::UTC, "2024-01-01T08:30:00%2B01:00").await.len(), 1);
+
+        let response = create_router(store.clone(), store.feed(), Tz::Europe__Berlin)
+            .oneshot(get("/"))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains("DISPLAY_TIMEZONE = 'Europe/Berlin'"));
+    }
+
+    #[tokio::test]
+    async fn test_sensor_value_events() {
+        let store = MemoryStore::new();
+        let sensor = store.register_sensor("picow-1").await.unwrap();
+        let uri = format!("/api/sensor_values_events?sensors={}", sensor);
+        let response = create_router(store.clone(), store.feed(), Tz::UTC)
+            .oneshot(get(&uri))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut events = response.into_body().into_data_stream();
+
+        // only the value of the selected sensor is pushed
+        for (sensor, value) in [(DEFAULT_SENSOR_ID, "1"), (sensor, "2")] {
+            let request = Request::post(format!("/api/add_sensor_value?sensor={}", sensor))
+                .body(Body::from(value))
+                .unwrap();
+            assert_eq!(send(&store, request).await.0, StatusCode::OK);
+        }
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let event = String::from_utf8(event.to_vec()).unwrap();
+        let data = event.strip_prefix("data: ").unwrap().trim_end();
+        let value: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(value["value"], 2.);
+        assert_eq!(value["sensor_id"], sensor);
+    }
+
+    fn json(method: &str, uri: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_calibrations() {
+        let store = MemoryStore::new();
+        let request = Request::post("/api/add_sensor_value")
+            .body(Body::from("24.5"))
+            .unwrap();
+        assert_eq!(send(&store, request).await.0, StatusCode::OK);
+
+        let body = r#"{"sensor_id": 1, "kind": "temperature", "offset": -4.5}"#;
+        let (status, body) = send(&store, json("POST", "/api/calibrations", body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let calibration: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(calibration["gain"], 1.);
+        assert_eq!(calibration["unit"], "°C");
+        assert_eq!(calibration["valid_from"], "1970-01-01T00:00:00Z");
+        let (_, body) = send(&store, get("/api/sensor_values")).await;
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(page["items"][0]["value"], 20.);
+        let (_, body) = send(&store, get("/api/calibrations?sensors=1")).await;
+        let calibrations: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(calibrations.len(), 1);
+
+        let body = r#"{"sensor_id": 1, "kind": "temperature", "gain": -1}"#;
+        let (status, _) = send(&store, json("POST", "/api/calibrations", body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let uri = format!("/api/calibrations/{}", calibration["id"]);
+        let request = Request::delete(&uri).body(Body::empty()).unwrap();
+        assert_eq!(send(&store, request).await.0, StatusCode::NO_CONTENT);
+        let request = Request::delete(&uri).body(Body::empty()).unwrap();
+        assert_eq!(send(&store, request).await.0, StatusCode::NOT_FOUND);
+        let (_, body) = send(&store, get("/api/sensor_values")).await;
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(page["items"][0]["value"], 24.5);
+    }
+
+    #[tokio::test]
+    async fn test_annotations() {
+        let store = MemoryStore::new();
+        let timestamp = to_utc_datetime("2024-01-01 09:10:00").unwrap();
+        let value = NewSensorData::temperature(DEFAULT_SENSOR_ID, timestamp, 14.);
+        store.add_sensor_data(&value).await.unwrap();
+
+        let body = r#"{"starts_at": "2024-01-01 09:00:00", "ends_at": "2024-01-01 09:15:00",
+            "text": "window opened", "tags": ["window"]}"#;
+        let (status, body) = send(&store, json("POST", "/api/annotations", body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let annotation: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(annotation["starts_at"], "2024-01-01T09:00:00Z");
+        assert!(annotation["sensor_id"].is_null());
+        let uri = format!("/api/annotations/{}", annotation["id"]);
+
+        let uri_between =
+            "/api/sensor_values_between?from=2024-01-01%2009:00:00&to=2024-01-01%2010:00:00";
+        let (status, body) = send(&store, get(uri_between)).await;
+        assert_eq!(status, StatusCode::OK);
+        let between: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(between["values"][0]["value"], 14.);
+        assert_eq!(between["annotations"][0]["text"], "window opened");
+        assert_eq!(between["annotations"][0]["tags"][0], "window");
+
+        let body = r#"{"starts_at": "2024-01-01 09:00:00", "text": "heater maintenance"}"#;
+        let (status, _) = send(&store, json("PUT", &uri, body)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, body) = send(&store, get(&uri)).await;
+        let annotation: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(annotation["text"], "heater maintenance");
+        assert_eq!(annotation["ends_at"], "2024-01-01T09:00:00Z");
+        let (_, body) = send(&store, get("/api/annotations?from=2024-01-01%2010:00:00")).await;
+        assert_eq!(body, "[]");
+        let (_, body) = send(&store, get("/api/annotations")).await;
+        let annotations: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(annotations.len(), 1);
+
+        let delete = Request::delete(&uri).body(Body::empty()).unwrap();
+        assert_eq!(send(&store, delete).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(&store, get(&uri)).await.0, StatusCode::NOT_FOUND);
+
+        let reversed = r#"{"starts_at": "2024-01-01 10:00:00", "ends_at": "2024-01-01 09:00:00",
+            "text": "window opened"}"#;
+        let (status, _) = send(&store, json("POST", "/api/annotations", reversed)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let unknown_sensor = r#"{"sensor_id": 42, "starts_at": "2024-01-01 09:00:00",
+            "text": "sensor relocated"}"#;
+        let (status, _) = send(&store, json("POST", "/api/annotations", unknown_sensor)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_sensor_value() {
+        let store = MemoryStore::new();
+        let request = Request::post("/api/add_sensor_value")
+            .body(Body::from("warm"))
+            .unwrap();
+        assert_eq!(send(&store, request).await.0, StatusCode::BAD_REQUEST);
+        let request = Request::post("/api/add_sensor_value?kind=humidity&unit=hPa")
+            .body(Body::from("40"))
+            .unwrap();
+        assert_eq!(send(&store, request).await.0, StatusCode::BAD_REQUEST);
+        let request = Request::post("/api/add_sensor_value?sensor=42")
+            .body(Body::from("21.5"))
+            .unwrap();
+        assert_eq!(send(&store, request).await.0, StatusCode::CONFLICT);
+        assert!(store
+            .list_sensordata(&[], QualityFilter::All)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -2,13 +2,17 @@
 //! to the TCP server address configured in the environment variable
 //! IOT_DATA_BRIDGE_URL.
 //!
+//! The values are sent in the legacy format unless IOT_SIMULATOR_PROTOCOL is "v2",
+//! the v2 frames identify the simulator by IOT_SIMULATOR_DEVICE_ID (default 1).
 
 #![warn(rust_2018_idioms)]
 
 use dotenvy::dotenv;
 
 use std::env;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+
+use iot_protocol::{Frame, Kind};
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio::time::sleep;
@@ -17,14 +21,60 @@ use tokio::time::sleep;
 pub async fn main() -> anyhow::Result<()> {
     dotenv().ok();
     let serverurl = get_server_url_from_env();
+    let protocol = Protocol::from_env()?;
 
     let up_and_down = UpAndDown::new(10, 15);
-    for value in up_and_down {
-        create_value(&serverurl, value).await
+    for (sequence, value) in (0..).zip(up_and_down) {
+        create_value(&serverurl, protocol, sequence, value).await
     }
     Ok(())
 }
 
+/// Format of the sent values
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    /// a bare temperature as big endian f32
+    Legacy,
+    /// frames with device id, sequence number, timestamp and checksum
+    V2 { device_id: u64 },
+}
+
+impl Protocol {
+    fn from_env() -> anyhow::Result<Self> {
+        match env::var("IOT_SIMULATOR_PROTOCOL").as_deref() {
+            Err(_) | Ok("legacy") => Ok(Protocol::Legacy),
+            Ok("v2") => {
+                let device_id = match env::var("IOT_SIMULATOR_DEVICE_ID") {
+                    Ok(device_id) => device_id.parse()?,
+                    Err(_) => 1,
+                };
+                Ok(Protocol::V2 { device_id })
+            }
+            Ok(protocol) => anyhow::bail!("unknown protocol '{}', expected legacy or v2", protocol),
+        }
+    }
+
+    fn encode(&self, sequence: u32, value: f32) -> Vec<u8> {
+        match self {
+            Protocol::Legacy => value.to_be_bytes().to_vec(),
+            Protocol::V2 { device_id } => {
+                let device_timestamp = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .ok()
+                    .and_then(|since_epoch| since_epoch.as_millis().try_into().ok());
+                let frame = Frame {
+                    device_id: *device_id,
+                    sequence,
+                    kind: Kind::Temperature,
+                    device_timestamp,
+                    value,
+                };
+                frame.encode().as_bytes().to_vec()
+            }
+        }
+    }
+}
+
 // Function to initialize server URL from environment variable.
 fn get_server_url_from_env() -> String {
     let mut serverurl =
@@ -36,7 +86,7 @@ fn get_server_url_from_env() -> String {
 }
 
 // Refactored create_value function to take server URL as a parameter.
-async fn create_value(serverurl: &str, value: i32) {
+async fn create_value(serverurl: &str, protocol: Protocol, sequence: u32, value: i32) {
     sleep(Duration::from_secs(1)).await;
     let value = value as f32;
 
@@ -46,7 +96,7 @@ async fn create_value(serverurl: &str, value: i32) {
             println!("Temp: {} degrees", value);
 
             // Convert and send to server
-            let msg = &value.to_be_bytes();
+            let msg = &protocol.encode(sequence, value);
             match stream.write_all(msg).await {
                 Ok(_) => println!("wrote to stream; len:{}", msg.len()),
                 Err(e) => eprintln!("failed to write to stream; error: {}", e),
@@ -110,4 +160,16 @@ mod tests {
         assert_eq!(iterator.next(), Some(10));
         assert_eq!(iterator.next(), Some(11));
     }
+
+    #[test]
+    fn test_encode_v2() {
+        let protocol = Protocol::V2 { device_id: 42 };
+        let (frame, len) = Frame::decode(&protocol.encode(3, 12.)).unwrap();
+        assert_eq!(len, frame.encoded_len());
+        assert_eq!(frame.device_id, 42);
+        assert_eq!(frame.sequence, 3);
+        assert_eq!(frame.value, 12.);
+        assert!(frame.device_timestamp.is_some());
+        assert_eq!(Protocol::Legacy.encode(3, 12.), 12f32.to_be_bytes());
+    }
 }